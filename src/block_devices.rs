@@ -0,0 +1,184 @@
+use std;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use aws_instance_metadata;
+use aws_instance_metadata::myerr::MetadataRetrievalError;
+use rusoto::ec2::DescribeInstancesError;
+use config_source::{ConfigSourceError, FetchMetadata, MetadataServiceClient};
+use ebs;
+
+/// Drive letters that `block-device: auto` chooses from; AWS recommends /dev/sd[f-p] (or /dev/xvd[f-p]) for EBS
+/// volumes on Linux.
+const FIRST_AUTO_LETTER: u8 = b'f';
+const LAST_AUTO_LETTER: u8 = b'p';
+
+#[derive(Debug)]
+pub enum BlockDeviceError {
+    InstanceMetadataFailed(MetadataRetrievalError),
+    BlockDeviceMappingFailed(ConfigSourceError),
+    DescribeInstancesFailed(DescribeInstancesError),
+    ReadSysBlockFailed(std::io::Error),
+    /// There aren't enough free block devices for all of the volume's members.
+    NoFreeBlockDevices(usize),
+}
+
+impl fmt::Display for BlockDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockDeviceError::InstanceMetadataFailed(ref err) => {
+                write!(f, "unable to retrieve instance metadata: {:?}", err)
+            }
+            BlockDeviceError::BlockDeviceMappingFailed(ref err) => {
+                write!(f, "unable to read block device mapping: {}", err)
+            }
+            BlockDeviceError::DescribeInstancesFailed(ref err) => {
+                write!(f, "unable to describe instance block devices: {:?}", err)
+            }
+            BlockDeviceError::ReadSysBlockFailed(ref err) => write!(f, "unable to read /sys/block: {}", err),
+            BlockDeviceError::NoFreeBlockDevices(count) => {
+                write!(f, "no {} consecutive free block devices between /dev/xvdf and /dev/xvdp", count)
+            }
+        }
+    }
+}
+
+/// Every block device that `block-device: auto` may choose, /dev/xvdf to /dev/xvdp.
+pub fn auto_block_devices() -> Vec<String> {
+    free_block_device_names(&[])
+}
+
+/// Chooses block devices for a volume with `block-device: auto` from those that aren't used on this instance by its
+/// block device mapping, its attached volumes, or devices the kernel knows about, and aren't `claimed` by other
+/// configured volumes.  For a single volume every free block device is returned, to be tried in turn; a set of
+/// `members` volumes gets the first run of consecutive free block devices.
+pub fn choose_block_devices(members: Option<usize>, claimed: &[String]) -> Result<Vec<String>, BlockDeviceError> {
+    let metadata = aws_instance_metadata::get().map_err(BlockDeviceError::InstanceMetadataFailed)?;
+    let mut used = mapped_block_devices(&MetadataServiceClient {}).map_err(BlockDeviceError::BlockDeviceMappingFailed)?;
+    used.extend(ebs::instance_block_devices(&metadata).map_err(BlockDeviceError::DescribeInstancesFailed)?);
+    used.extend(local_block_devices(Path::new("/sys/block")).map_err(BlockDeviceError::ReadSysBlockFailed)?);
+    used.extend(claimed.iter().cloned());
+    debug!("block devices in use: {:?}", used);
+
+    let free = free_block_device_names(&used);
+    match members {
+        None if free.is_empty() => Err(BlockDeviceError::NoFreeBlockDevices(1)),
+        None => Ok(free),
+        Some(count) => consecutive_block_devices(&free, count).ok_or(BlockDeviceError::NoFreeBlockDevices(count)),
+    }
+}
+
+/// Device names in the instance's block device mapping, eg. `sda1` for the root device and `sdb` for an instance
+/// store volume.
+fn mapped_block_devices<M: FetchMetadata>(metadata_client: &M) -> Result<Vec<String>, ConfigSourceError> {
+    let mappings = metadata_client.fetch("meta-data/block-device-mapping/")?.unwrap_or_default();
+    let mut devices = Vec::new();
+    for mapping in mappings.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        if let Some(device) = metadata_client.fetch(&format!("meta-data/block-device-mapping/{}", mapping))? {
+            devices.push(String::from(device.trim()));
+        }
+    }
+    Ok(devices)
+}
+
+fn local_block_devices(sys_block: &Path) -> std::io::Result<Vec<String>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(sys_block)? {
+        devices.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(devices)
+}
+
+/// The drive letter of an `sd` or `xvd` device name, with or without `/dev/` or a partition number; eg. `f` for
+/// `/dev/sdf`, `xvdf` or `sdf1`.  The kernel presents devices attached as /dev/sdX as /dev/xvdX on Xen instances, so
+/// a letter used under either name is in use.
+fn drive_letter(device: &str) -> Option<u8> {
+    let name = device.trim_left_matches("/dev/");
+    let letters = if name.starts_with("xvd") {
+        &name["xvd".len()..]
+    } else if name.starts_with("sd") {
+        &name["sd".len()..]
+    } else {
+        return None;
+    };
+    let letters = letters.trim_right_matches(|c: char| c.is_digit(10));
+    if letters.len() == 1 {
+        Some(letters.as_bytes()[0])
+    } else {
+        None
+    }
+}
+
+/// Whether `a` and `b` name the same block device, including as /dev/sdX and /dev/xvdX.
+pub fn same_block_device(a: &str, b: &str) -> bool {
+    a == b || drive_letter(a).map_or(false, |letter| drive_letter(b) == Some(letter))
+}
+
+fn free_block_device_names(used: &[String]) -> Vec<String> {
+    let used_letters: Vec<u8> = used.iter().filter_map(|device| drive_letter(device)).collect();
+    (FIRST_AUTO_LETTER..LAST_AUTO_LETTER + 1)
+        .filter(|letter| !used_letters.contains(letter))
+        .map(|letter| format!("/dev/xvd{}", letter as char))
+        .collect()
+}
+
+fn consecutive_block_devices(free: &[String], count: usize) -> Option<Vec<String>> {
+    free.windows(count)
+        .find(|run| {
+                  run.windows(2).all(|pair| {
+                                         drive_letter(&pair[1]) == drive_letter(&pair[0]).map(|letter| letter + 1)
+                                     })
+              })
+        .map(|run| run.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MockFetchMetadata {
+        documents: HashMap<&'static str, &'static str>,
+    }
+
+    impl FetchMetadata for MockFetchMetadata {
+        fn fetch(&self, path: &str) -> Result<Option<String>, ConfigSourceError> {
+            Ok(self.documents.get(path).map(|document| String::from(*document)))
+        }
+    }
+
+    #[test]
+    fn reads_block_device_mapping() {
+        let mut documents = HashMap::new();
+        documents.insert("meta-data/block-device-mapping/", "ami\nephemeral0\nroot");
+        documents.insert("meta-data/block-device-mapping/ami", "/dev/xvda");
+        documents.insert("meta-data/block-device-mapping/ephemeral0", "sdb");
+        documents.insert("meta-data/block-device-mapping/root", "/dev/xvda");
+        let devices = mapped_block_devices(&MockFetchMetadata { documents: documents }).unwrap();
+        assert_eq!(vec!["/dev/xvda", "sdb", "/dev/xvda"], devices);
+    }
+
+    #[test]
+    fn finds_free_block_devices() {
+        let used = vec![String::from("/dev/sda1"), String::from("xvdf"), String::from("/dev/sdg"),
+                        String::from("xvdj1"), String::from("nvme0n1"), String::from("loop0")];
+        let free = free_block_device_names(&used);
+        assert_eq!(vec!["/dev/xvdh", "/dev/xvdi", "/dev/xvdk", "/dev/xvdl", "/dev/xvdm", "/dev/xvdn", "/dev/xvdo",
+                        "/dev/xvdp"],
+                   free);
+        assert_eq!(Some(vec![String::from("/dev/xvdh"), String::from("/dev/xvdi")]),
+                   consecutive_block_devices(&free, 2));
+        assert_eq!(Some(vec![String::from("/dev/xvdk"), String::from("/dev/xvdl"), String::from("/dev/xvdm")]),
+                   consecutive_block_devices(&free, 3));
+        assert_eq!(None, consecutive_block_devices(&free, 7));
+        assert_eq!(11, auto_block_devices().len());
+    }
+
+    #[test]
+    fn compares_block_devices() {
+        assert!(same_block_device("/dev/xvdf", "/dev/xvdf"));
+        assert!(same_block_device("/dev/sdf", "/dev/xvdf"));
+        assert!(!same_block_device("/dev/xvdf", "/dev/xvdg"));
+        assert!(!same_block_device("/dev/nvme1n1", "/dev/nvme2n1"));
+    }
+}
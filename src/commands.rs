@@ -0,0 +1,1147 @@
+use std;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Instant;
+use aws_instance_metadata;
+use chrono::UTC;
+use rusoto;
+use serde_json;
+use block_devices;
+use config::{self, Config, Volume};
+use ebs;
+use fsck;
+use luks;
+use lvm;
+use mkfs;
+use mount;
+use raid;
+use schema;
+
+/// Attaches, formats if necessary, and mounts every configured volume.  A failing volume doesn't prevent the
+/// remaining volumes from being set up; the exit code is that of the first failure.
+pub fn attach(config: &Config, output: &OutputOptions) -> i32 {
+    let mut exit_code = 0;
+    let mut reports = Vec::with_capacity(config.volumes.len());
+    for (index, volume) in config.volumes.iter().enumerate() {
+        info!("setting up volume for block device {}", volume.block_device);
+        let mut report = VolumeReport::new(volume);
+        match setup_volume(volume, &claimed_block_devices(config, index), &mut report) {
+            Ok(_) => {
+                info!("volume for block device {} is mounted at {}",
+                      volume.block_device,
+                      volume.mount.target)
+            }
+            Err(e) => {
+                error!("volume for block device {} failed: {:?}", volume.block_device, e);
+                report.exit_code = e.exit_code();
+                report.error = Some(format!("{:?}", e));
+                if exit_code == 0 {
+                    exit_code = e.exit_code();
+                }
+            }
+        }
+        reports.push(report);
+    }
+
+    let report = AttachReport {
+        exit_code: exit_code,
+        volumes: reports,
+    };
+    if let Err(e) = write_report(&report, output) {
+        error!("failed to write attach result: {:?}", e);
+        if exit_code == 0 {
+            exit_code = 110;
+        }
+    }
+    exit_code
+}
+
+/// How the results of `attach` are reported, in addition to logging.
+pub struct OutputOptions {
+    /// Print the result document as JSON to stdout.
+    pub json: bool,
+    /// Write the result document as JSON to this file.
+    pub result_file: Option<String>,
+}
+
+/// Result document for `attach`, written as JSON for downstream scripts.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct AttachReport {
+    exit_code: i32,
+    volumes: Vec<VolumeReport>,
+}
+
+/// What happened to one configured volume.  Fields are filled in as each stage completes, so a failed volume reports
+/// how far it got.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct VolumeReport {
+    block_device: String,
+    exit_code: i32,
+    error: Option<String>,
+    /// Attached EBS volume ids, keyed by block device.
+    ebs_volumes: BTreeMap<String, String>,
+    /// Whether the EBS volumes were newly created rather than existing volumes being reattached.
+    ebs_volumes_created: bool,
+    /// Device that the filesystem is on; the LVM, RAID or encryption device if configured.
+    device: String,
+    filesystem_created: bool,
+    filesystem_type: Option<String>,
+    filesystem_uuid: Option<String>,
+    mount_target: String,
+    mounted: bool,
+    read_only: bool,
+    /// Seconds taken by each completed stage.
+    timings: BTreeMap<&'static str, f64>,
+}
+
+impl VolumeReport {
+    fn new(volume: &Volume) -> VolumeReport {
+        VolumeReport {
+            block_device: volume.block_device.to_owned(),
+            exit_code: 0,
+            error: None,
+            ebs_volumes: BTreeMap::new(),
+            ebs_volumes_created: false,
+            // with block-device auto, a single volume's device isn't known until it's attached
+            device: match volume.filesystem_device() {
+                ref device if device == config::AUTO_BLOCK_DEVICE => String::new(),
+                device => device,
+            },
+            filesystem_created: false,
+            filesystem_type: None,
+            filesystem_uuid: None,
+            mount_target: volume.mount.target.to_owned(),
+            mounted: false,
+            read_only: false,
+            timings: BTreeMap::new(),
+        }
+    }
+
+    fn record_timing(&mut self, stage: &'static str, start: Instant) {
+        let elapsed = start.elapsed();
+        self.timings.insert(stage,
+                            elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    }
+}
+
+fn write_report(report: &AttachReport, output: &OutputOptions) -> Result<(), std::io::Error> {
+    let json = serde_json::to_string_pretty(report).unwrap();
+    if output.json {
+        println!("{}", json);
+    }
+    if let Some(ref result_file) = output.result_file {
+        let mut file = File::create(result_file)?;
+        file.write_all(json.as_bytes())?;
+        file.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Unmounts every configured volume and detaches it from this instance, so that it can be attached elsewhere.
+/// Volumes are torn down in the reverse of the order they were set up.
+pub fn detach(config: &Config) -> i32 {
+    let mut exit_code = 0;
+    for volume in config.volumes.iter().rev() {
+        info!("tearing down volume for block device {}", volume.block_device);
+        match teardown_volume(volume) {
+            Ok(_) => info!("volume for block device {} is detached", volume.block_device),
+            Err(e) => {
+                error!("volume for block device {} failed: {:?}", volume.block_device, e);
+                if exit_code == 0 {
+                    exit_code = e.exit_code();
+                }
+            }
+        }
+    }
+    exit_code
+}
+
+/// Reports what is currently attached and mounted for each configured volume.
+pub fn status(config: &Config) -> i32 {
+    let mut exit_code = 0;
+    for volume in &config.volumes {
+        println!("volume {}:", volume.block_device);
+        // with block-device auto, the block devices are those the volume's EBS volumes were attached at
+        let mut block_devices = volume.member_block_devices();
+        if let config::BlockProvider::AwsEbs(ref ebs) = volume.block_provider {
+            match ebs::list_attached_volumes(&volume.attachment_block_devices(), ebs) {
+                Ok(ref attached) if attached.is_empty() => println!("  ebs: no volumes attached"),
+                Ok(attached) => {
+                    for (device, volume_id) in ebs::attached_devices(&attached) {
+                        println!("  ebs: {} attached at {}", volume_id, device);
+                        if volume.auto_block_device() {
+                            block_devices.push(device);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("failed to describe attached volumes: {:?}", e);
+                    println!("  ebs: unknown");
+                    exit_code = 101;
+                }
+            }
+        }
+        for block_device in block_devices {
+            println!("  block device {}: {}", block_device, present_or_missing(&block_device));
+        }
+        match volume.layout {
+            config::Layout::Single => {}
+            _ => {
+                let layout_device = volume.layout_device();
+                println!("  layout device {}: {}", layout_device, present_or_missing(&layout_device));
+            }
+        }
+        if volume.encryption.is_some() {
+            let mapped_device = volume.filesystem_device();
+            println!("  encrypted device {}: {}",
+                     mapped_device,
+                     if Path::new(&mapped_device).exists() { "open" } else { "closed" });
+        }
+        print_mount_status("mount", &volume.mount.target);
+        for bind in &volume.mount.binds {
+            print_mount_status("bind", &bind.target);
+        }
+    }
+    exit_code
+}
+
+fn present_or_missing(device: &str) -> &'static str {
+    if Path::new(device).exists() {
+        "present"
+    } else {
+        "missing"
+    }
+}
+
+fn print_mount_status(kind: &str, target: &str) {
+    match mount::find_mount(target) {
+        Ok(Some(entry)) => {
+            println!("  {} {}: mounted from {} ({})",
+                     kind,
+                     target,
+                     entry.device,
+                     if entry.read_only() { "read-only" } else { "read-write" })
+        }
+        Ok(None) => println!("  {} {}: not mounted", kind, target),
+        Err(e) => println!("  {} {}: unknown ({:?})", kind, target, e),
+    }
+}
+
+/// Lists every EBS volume matching each configured volume's tags, regardless of state, along with which instance
+/// (if any) has it attached.
+pub fn list(config: &Config) -> i32 {
+    let mut exit_code = 0;
+    for volume in &config.volumes {
+        println!("volume {}:", volume.block_device);
+        if let config::BlockProvider::AwsEbs(ref ebs) = volume.block_provider {
+            match ebs::list_volumes(ebs) {
+                Ok(ref volumes) if volumes.is_empty() => println!("  no matching volumes"),
+                Ok(volumes) => {
+                    for vol in &volumes {
+                        print_ebs_volume(vol);
+                    }
+                }
+                Err(e) => {
+                    error!("failed to describe volumes: {:?}", e);
+                    exit_code = 101;
+                }
+            }
+        }
+    }
+    exit_code
+}
+
+fn print_ebs_volume(vol: &rusoto::ec2::Volume) {
+    let attachment = vol.attachments
+        .as_ref()
+        .and_then(|attachments| attachments.get(0))
+        .map(|attachment| {
+            format!("attached to {} at {}",
+                    attachment.instance_id.as_ref().map_or("?", |s| s.as_str()),
+                    attachment.device.as_ref().map_or("?", |s| s.as_str()))
+        })
+        .unwrap_or_else(|| String::from("unattached"));
+    let set = match (ebs::tag_value(vol, ebs::SET_ID_TAG), ebs::tag_value(vol, ebs::SET_MEMBER_TAG)) {
+        (Some(set_id), Some(member)) => format!(", set {} member {}", set_id, member),
+        _ => String::new(),
+    };
+    let last_attached = match (ebs::tag_value(vol, ebs::LAST_ATTACHED_INSTANCE_TAG),
+                               ebs::tag_value(vol, ebs::LAST_ATTACHED_AT_TAG)) {
+        (Some(instance_id), Some(attached_at)) => format!(", last attached to {} at {}", instance_id, attached_at),
+        _ => String::new(),
+    };
+    println!("  {} {} {} {}GB in {}, {}{}{}",
+             vol.volume_id.as_ref().map_or("?", |s| s.as_str()),
+             vol.state.as_ref().map_or("?", |s| s.as_str()),
+             vol.volume_type.as_ref().map_or("?", |s| s.as_str()),
+             vol.size.unwrap_or_default(),
+             vol.availability_zone.as_ref().map_or("?", |s| s.as_str()),
+             attachment,
+             set,
+             last_attached);
+}
+
+/// Deletes the EBS volumes matching each configured volume's tags that have been left unattached for longer than the
+/// retention period, eg. after failed boots.
+pub fn gc(config: &Config, options: &ebs::GcOptions) -> i32 {
+    let mut exit_code = 0;
+    for volume in &config.volumes {
+        println!("volume {}:", volume.block_device);
+        if let config::BlockProvider::AwsEbs(ref ebs) = volume.block_provider {
+            match ebs::collect_garbage(ebs, options) {
+                Ok(ref collected) if collected.is_empty() => println!("  no unattached volumes"),
+                Ok(collected) => {
+                    for vol in &collected {
+                        print_gc_volume(vol, options);
+                    }
+                }
+                Err(e) => {
+                    error!("failed to collect unattached volumes: {:?}", e);
+                    exit_code = 101;
+                }
+            }
+        }
+    }
+    exit_code
+}
+
+fn print_gc_volume(vol: &ebs::GcVolume, options: &ebs::GcOptions) {
+    let action = match vol.action {
+        ebs::GcAction::Marked => String::from("newly unattached, retained"),
+        ebs::GcAction::Retained => String::from("retained"),
+        ebs::GcAction::Kept => String::from("kept to leave the minimum number available"),
+        ebs::GcAction::Deleted { snapshot_id: Some(ref snapshot_id) } => {
+            format!("deleted after creating snapshot {}", snapshot_id)
+        }
+        ebs::GcAction::Deleted { snapshot_id: None } if options.dry_run && options.snapshot => {
+            String::from("would be snapshotted and deleted")
+        }
+        ebs::GcAction::Deleted { snapshot_id: None } if options.dry_run => String::from("would be deleted"),
+        ebs::GcAction::Deleted { snapshot_id: None } => String::from("deleted"),
+    };
+    println!("  {} unattached for {} hours: {}",
+             vol.volume_id,
+             (UTC::now() - vol.available_since).num_hours(),
+             action);
+}
+
+/// Creates EBS volumes for each configured volume with a `pool` until each of its availability zones has the pool's
+/// size available, so that instances starting there attach one immediately rather than waiting for a new volume.
+pub fn pool(config: &Config, dry_run: bool) -> i32 {
+    let mut exit_code = 0;
+    for volume in &config.volumes {
+        println!("volume {}:", volume.block_device);
+        let (ebs, pool) = match volume.block_provider {
+            config::BlockProvider::AwsEbs(ref ebs) => {
+                match ebs.pool {
+                    Some(ref pool) => (ebs, pool),
+                    None => {
+                        println!("  no pool configured");
+                        continue;
+                    }
+                }
+            }
+            _ => continue,
+        };
+        match ebs::fill_pool(ebs, pool, &volume.config_fingerprint(), volume.members(), dry_run) {
+            Ok(zones) => {
+                let unit = if volume.members() > 1 { "volume set(s)" } else { "volume(s)" };
+                for zone in zones {
+                    let created = if zone.created == 0 {
+                        String::from("pool is full")
+                    } else if dry_run {
+                        format!("would create {}", zone.created)
+                    } else {
+                        format!("created {}", zone.volume_ids.join(", "))
+                    };
+                    println!("  {}: {} {} available; {}", zone.availability_zone, zone.available, unit, created);
+                }
+            }
+            Err(ebs::PoolError::SnapshotFingerprintMismatch(ref snapshot_id, ref fingerprint)) => {
+                error!("snapshot {} was taken of a volume created for {}, but this configuration is {}",
+                       snapshot_id,
+                       fingerprint,
+                       volume.config_fingerprint());
+                exit_code = 101;
+            }
+            Err(e) => {
+                error!("failed to fill volume pool: {:?}", e);
+                exit_code = 101;
+            }
+        }
+    }
+    exit_code
+}
+
+/// Snapshots the EBS volumes attached for each configured volume, freezing its mounted filesystem while the snapshots
+/// are created so that they're consistent, and then deletes older snapshots that `retention` doesn't keep.  A failing
+/// volume doesn't prevent the remaining volumes from being snapshotted; the exit code is that of the first failure.
+pub fn snapshot(config: &Config, retention: &ebs::SnapshotRetention) -> i32 {
+    let mut exit_code = 0;
+    for volume in &config.volumes {
+        println!("volume {}:", volume.block_device);
+        if let Err(e) = snapshot_volume(volume, retention) {
+            error!("snapshot of volume for block device {} failed: {:?}", volume.block_device, e);
+            if exit_code == 0 {
+                exit_code = e.exit_code();
+            }
+        }
+    }
+    exit_code
+}
+
+#[derive(Debug)]
+enum SnapshotVolumeError {
+    UnexpectedBlockProvider,
+    NotAttached,
+    DescribeVolumesFailed(rusoto::ec2::DescribeVolumesError),
+    Ec2ClientFailed(ebs::Ec2ClientError),
+    FindMountFailed(mount::MountError),
+    FreezeFailed(mount::MountError),
+    ThawFailed(mount::MountError),
+    SnapshotFailed(ebs::SnapshotError),
+}
+
+impl SnapshotVolumeError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            SnapshotVolumeError::UnexpectedBlockProvider |
+            SnapshotVolumeError::NotAttached |
+            SnapshotVolumeError::DescribeVolumesFailed(_) |
+            SnapshotVolumeError::Ec2ClientFailed(_) |
+            SnapshotVolumeError::SnapshotFailed(_) => 101,
+            SnapshotVolumeError::FindMountFailed(_) |
+            SnapshotVolumeError::FreezeFailed(_) |
+            SnapshotVolumeError::ThawFailed(_) => 104,
+        }
+    }
+}
+
+fn snapshot_volume(volume: &Volume, retention: &ebs::SnapshotRetention) -> Result<(), SnapshotVolumeError> {
+    let ebs = match volume.block_provider {
+        config::BlockProvider::AwsEbs(ref ebs) => ebs,
+        _ => return Err(SnapshotVolumeError::UnexpectedBlockProvider),
+    };
+    let attached = ebs::list_attached_volumes(&volume.attachment_block_devices(), ebs)
+        .map_err(SnapshotVolumeError::DescribeVolumesFailed)?;
+    if attached.is_empty() {
+        return Err(SnapshotVolumeError::NotAttached);
+    }
+    // the client is built before the filesystem is frozen, so that nothing but CreateSnapshot can fail while it is
+    let ec2_client = ebs::instance_ec2_client().map_err(SnapshotVolumeError::Ec2ClientFailed)?;
+
+    // a read-only filesystem can't change while it's snapshotted, and can't be frozen
+    let target = volume.mount.target.as_str();
+    let freeze = match mount::find_mount(target).map_err(SnapshotVolumeError::FindMountFailed)? {
+        Some(ref entry) => !entry.read_only(),
+        None => {
+            warn!("{} is not mounted; snapshotting without freezing the filesystem", target);
+            false
+        }
+    };
+    let snapshot_ids = if freeze {
+        let frozen = mount::FrozenFilesystem::freeze(target).map_err(SnapshotVolumeError::FreezeFailed)?;
+        info!("froze filesystem at {}", target);
+        // a snapshot's contents are fixed when it's created, so the filesystem is thawed without waiting for it to
+        // complete; throttled requests aren't retried, since every write on the filesystem waits for the thaw
+        let snapshot_ids = ebs::create_snapshots(&attached, 0, &ec2_client);
+        if let Err(e) = frozen.thaw() {
+            if let Err(ref snapshot_err) = snapshot_ids {
+                error!("failed to snapshot volumes: {:?}", snapshot_err);
+            }
+            return Err(SnapshotVolumeError::ThawFailed(e));
+        }
+        info!("thawed filesystem at {}", target);
+        snapshot_ids
+    } else {
+        ebs::create_snapshots(&attached, ebs.timeouts.throttle_retries, &ec2_client)
+    };
+    let snapshot_ids = snapshot_ids.map_err(SnapshotVolumeError::SnapshotFailed)?;
+    ebs::tag_snapshots(&attached, &snapshot_ids, ebs, UTC::now(), &ec2_client)
+        .map_err(SnapshotVolumeError::SnapshotFailed)?;
+    for (vol, snapshot_id) in attached.iter().zip(&snapshot_ids) {
+        println!("  created snapshot {} of {}", snapshot_id, vol.volume_id.to_owned().unwrap_or_default());
+    }
+
+    if !retention.is_empty() {
+        let deleted = ebs::prune_snapshots(&attached, retention, ebs, &ec2_client)
+            .map_err(SnapshotVolumeError::SnapshotFailed)?;
+        for snapshot_id in deleted {
+            println!("  deleted snapshot {}", snapshot_id);
+        }
+    }
+    Ok(())
+}
+
+/// Runs preflight checks of everything that `attach` depends on, and prints a pass/fail table.  Returns 1 if any
+/// check failed; unverified checks are shown, but don't fail.
+pub fn doctor(config: &Config) -> i32 {
+    let mut results: Vec<(String, DoctorStatus, String)> = Vec::new();
+
+    match aws_instance_metadata::get() {
+        Ok(metadata) => {
+            results.push((String::from("instance metadata"),
+                          DoctorStatus::Pass,
+                          format!("{} in {}", metadata.instance_id, metadata.availability_zone)))
+        }
+        Err(e) => results.push((String::from("instance metadata"), DoctorStatus::Fail, format!("{:?}", e))),
+    }
+
+    for volume in &config.volumes {
+        let ebs = match volume.block_provider {
+            config::BlockProvider::AwsEbs(ref ebs) => ebs,
+            _ => continue,
+        };
+        // permissions can depend on a volume's tags, so each volume is checked, and named when there are several
+        let name = |action: &str| if config.volumes.len() > 1 {
+            format!("{} ({})", action, volume.mount.target)
+        } else {
+            String::from(action)
+        };
+        match ebs::check_all_permissions(&volume.attachment_block_devices()[0], ebs) {
+            Ok(checks) => {
+                for (action, check) in checks {
+                    results.push(match check {
+                                     ebs::PermissionCheck::Allowed => (name(action), DoctorStatus::Pass, String::new()),
+                                     ebs::PermissionCheck::Denied => {
+                                         (name(action), DoctorStatus::Fail, String::from("not permitted"))
+                                     }
+                                     ebs::PermissionCheck::Failed(err) => {
+                                         (name(action), DoctorStatus::Fail, format!("could not be checked: {}", err))
+                                     }
+                                     ebs::PermissionCheck::Unverified(err) => {
+                                         (name(action),
+                                          DoctorStatus::Unverified,
+                                          format!("could not be verified: {}", err))
+                                     }
+                                 });
+                }
+            }
+            Err(e) => {
+                results.push((name("ec2 permissions"),
+                              DoctorStatus::Fail,
+                              format!("instance metadata unavailable: {:?}", e)))
+            }
+        }
+    }
+
+    for volume in &config.volumes {
+        if let Some(ref encryption) = volume.encryption {
+            let name = format!("encryption key for {}", encryption.name);
+            match luks::check_key_source(&encryption.key_source) {
+                Ok(_) => results.push((name, DoctorStatus::Pass, String::new())),
+                Err(e) => results.push((name, DoctorStatus::Fail, format!("{:?}", e))),
+            }
+        }
+    }
+
+    for command in required_commands(config) {
+        let executable = std::fs::metadata(command)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if executable {
+            results.push((String::from(command), DoctorStatus::Pass, String::new()));
+        } else {
+            results.push((String::from(command), DoctorStatus::Fail, String::from("not found or not executable")));
+        }
+    }
+
+    let width = results.iter().map(|&(ref name, _, _)| name.len()).max().unwrap_or(0);
+    let mut exit_code = 0;
+    for (name, status, detail) in results {
+        let status_text = match status {
+            DoctorStatus::Pass => "pass",
+            DoctorStatus::Unverified => "warn",
+            DoctorStatus::Fail => "FAIL",
+        };
+        println!("{:width$}  {}  {}", name, status_text, detail, width = width);
+        if status == DoctorStatus::Fail {
+            exit_code = 1;
+        }
+    }
+    exit_code
+}
+
+/// The outcome of one of `doctor`'s checks.  A check is unverified when it couldn't be carried out (eg. EC2 returned
+/// an unexpected error to a `DryRun` call), which doesn't show that `attach` would fail, so it doesn't fail `doctor`.
+#[derive(PartialEq)]
+enum DoctorStatus {
+    Pass,
+    Unverified,
+    Fail,
+}
+
+/// External commands that `attach` and `detach` may run for the configured volumes.
+fn required_commands(config: &Config) -> Vec<&'static str> {
+    let mut commands = vec!["/sbin/mkfs", "/sbin/blkid", "/bin/mount", "/bin/umount"];
+    for volume in &config.volumes {
+        match volume.layout {
+            config::Layout::Single => {}
+            config::Layout::Lvm(_) => {
+                commands.extend(&["/sbin/pvcreate", "/sbin/vgcreate", "/sbin/lvcreate", "/sbin/vgchange"])
+            }
+            config::Layout::Raid(_) => commands.push("/sbin/mdadm"),
+        }
+        if volume.encryption.is_some() {
+            commands.push("/sbin/cryptsetup");
+        }
+        if volume.file_system.fsck.is_some() {
+            commands.extend(&["/usr/bin/timeout", "/sbin/e2fsck", "/sbin/xfs_repair"]);
+        }
+        if volume.mount.owner.is_some() || volume.mount.group.is_some() {
+            commands.push("/bin/chown");
+        }
+        if volume.mount.context.is_some() {
+            commands.push("/usr/bin/chcon");
+        }
+    }
+    let mut seen = Vec::with_capacity(commands.len());
+    commands.retain(|command| if seen.contains(command) {
+                        false
+                    } else {
+                        seen.push(*command);
+                        true
+                    });
+    commands
+}
+
+/// Prints a JSON Schema describing the configuration file.
+pub fn config_schema() -> i32 {
+    println!("{}", serde_json::to_string_pretty(&schema::config_schema()).unwrap());
+    0
+}
+
+/// Reports that the configuration is valid; it has already been loaded and validated by the time this is called,
+/// with `${instance.*}` references checked but not looked up, so no AWS calls are made.
+pub fn validate(config: &Config) -> i32 {
+    println!("configuration is valid; {} volume(s) configured", config.volumes.len());
+    0
+}
+
+/// Reports what `attach` would do for each configured volume without changing anything.
+pub fn plan(config: &Config) -> i32 {
+    plan_volumes(config, false)
+}
+
+/// Like `plan`, but additionally issues EC2 `DryRun` requests to verify that the calls `attach` would make are
+/// permitted.
+pub fn dry_run(config: &Config) -> i32 {
+    plan_volumes(config, true)
+}
+
+fn plan_volumes(config: &Config, check_permissions: bool) -> i32 {
+    let mut exit_code = 0;
+    for (index, volume) in config.volumes.iter().enumerate() {
+        println!("volume {}:", volume.block_device);
+        if let Err(e) = plan_volume(volume, &claimed_block_devices(config, index), check_permissions) {
+            error!("volume for block device {} failed: {:?}", volume.block_device, e);
+            if exit_code == 0 {
+                exit_code = e.exit_code();
+            }
+        }
+    }
+    exit_code
+}
+
+fn plan_volume(volume: &Volume, claimed: &[String], check_permissions: bool) -> Result<(), SetupVolumeError> {
+    let attached = match volume.block_provider {
+        config::BlockProvider::AwsEbs(ref ebs) if volume.auto_block_device() => {
+            ebs::find_attached_volumes(&volume.attachment_block_devices(), ebs)
+                .map_err(SetupVolumeError::DescribeVolumesFailed)?
+                .map(|attached| attached.volumes.into_iter().map(|(block_device, _)| block_device).collect())
+        }
+        _ => None,
+    };
+    let (member_block_devices, attached) = match attached {
+        Some(attached_block_devices) => (attached_block_devices, true),
+        None if volume.auto_block_device() => {
+            let mut chosen = attach_block_devices(volume, claimed)?;
+            chosen.truncate(volume.members());
+            (chosen, false)
+        }
+        None => {
+            let member_block_devices = volume.member_block_devices();
+            let attached = member_block_devices.iter().all(|block_device| Path::new(block_device).exists());
+            (member_block_devices, attached)
+        }
+    };
+    if attached {
+        println!("  block device(s) already attached: {}", member_block_devices.join(", "));
+    } else {
+        match volume.block_provider {
+            config::BlockProvider::AwsEbs(ref ebs) => {
+                let (volume_set, allow_partial) = match volume.layout {
+                    config::Layout::Single => (false, false),
+                    config::Layout::Lvm(_) => (true, false),
+                    config::Layout::Raid(ref raid) => (true, raid.allow_degraded),
+                };
+                let fingerprint = volume.config_fingerprint();
+                let plan = ebs::plan_attach(&member_block_devices, ebs, &fingerprint, volume_set, allow_partial)
+                    .map_err(SetupVolumeError::AttachVolumeFailed)?;
+                print_attach_plan(&plan, ebs, &member_block_devices);
+                if check_permissions {
+                    let checks = ebs::check_attach_permissions(&member_block_devices, ebs, &plan);
+                    let mut denied = Vec::new();
+                    for (action, check) in checks {
+                        match check {
+                            ebs::PermissionCheck::Allowed => println!("  {} is permitted", action),
+                            ebs::PermissionCheck::Denied => {
+                                println!("  {} is NOT permitted", action);
+                                denied.push(action);
+                            }
+                            ebs::PermissionCheck::Failed(err) => {
+                                println!("  {} could NOT be checked: {}", action, err);
+                                denied.push(action);
+                            }
+                            ebs::PermissionCheck::Unverified(err) => {
+                                println!("  {} could not be verified: {}", action, err)
+                            }
+                        }
+                    }
+                    if !denied.is_empty() {
+                        return Err(SetupVolumeError::PermissionDenied(denied));
+                    }
+                }
+            }
+            _ => return Err(SetupVolumeError::UnexpectedBlockProvider),
+        }
+    }
+
+    match volume.layout {
+        config::Layout::Single => {}
+        config::Layout::Lvm(ref lvm) => {
+            println!("  would create or activate volume group {} with logical volume {}",
+                     lvm.volume_group,
+                     volume.layout_device())
+        }
+        config::Layout::Raid(ref raid) => {
+            println!("  would create or assemble raid{} array {}", raid.level, raid.device)
+        }
+    }
+    let layout_device = match volume.layout {
+        config::Layout::Single => member_block_devices[0].to_owned(),
+        _ => volume.layout_device(),
+    };
+    if volume.encryption.is_some() {
+        if let Ok(false) = luks::has_luks_header(layout_device.as_str()) {
+            println!("  would format {} with LUKS", layout_device);
+        }
+        println!("  would open encrypted device {} as {}",
+                 layout_device,
+                 volume.filesystem_device());
+    }
+
+    let device = match volume.encryption {
+        Some(_) => volume.filesystem_device(),
+        None => layout_device,
+    };
+    match mkfs::filesystem_exists(device.as_str()) {
+        Ok(true) => {
+            if volume.file_system.fsck.is_some() {
+                println!("  would check existing filesystem on {}", device);
+            } else {
+                println!("  would use existing filesystem on {}", device);
+            }
+        }
+        _ => {
+            println!("  would create a filesystem on {} if none exists: mkfs {}",
+                     device,
+                     volume.file_system.mkfs.join(" "))
+        }
+    }
+    println!("  would mount {} at {}", device, volume.mount.target);
+    for bind in &volume.mount.binds {
+        println!("  would bind mount {} onto {}", bind.source, bind.target);
+    }
+    Ok(())
+}
+
+fn print_attach_plan(plan: &ebs::AttachPlan, config: &config::EbsBlockProviderConfig, block_devices: &[String]) {
+    match *plan {
+        ebs::AttachPlan::AttachExisting(ref candidates) => {
+            let attachments: Vec<String> = candidates[0]
+                .iter()
+                .map(|&(ref block_device, ref volume_id)| format!("{} to {}", volume_id, block_device))
+                .collect();
+            println!("  would attach {}", attachments.join(", "));
+            if candidates.len() > 1 {
+                println!("  {} other candidate(s) would be tried if the attach fails",
+                         candidates.len() - 1);
+            }
+        }
+        ebs::AttachPlan::CreateNew { ref availability_zone } => {
+            let mut tags: Vec<String> = config.ebs_tags
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            tags.sort();
+            println!("  would create {} new {} volume(s) of {}GB in {}, tagged {}, attached to {}",
+                     block_devices.len(),
+                     config.volume_type,
+                     config.size,
+                     availability_zone,
+                     tags.join(", "),
+                     block_devices.join(", "));
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SetupVolumeError {
+    UnexpectedBlockProvider,
+    ChooseBlockDeviceFailed(block_devices::BlockDeviceError),
+    DescribeVolumesFailed(rusoto::ec2::DescribeVolumesError),
+    AttachVolumeFailed(ebs::AttachVolumeError),
+    PermissionDenied(Vec<&'static str>),
+    LvmFailed(lvm::LvmError),
+    RaidFailed(raid::RaidError),
+    EncryptionFailed(luks::LuksError),
+    DetectFilesystemFailed(mkfs::MakeFilesystemError),
+    MakeFilesystemFailed(mkfs::MakeFilesystemError),
+    FilesystemCheckFailed(fsck::FsckError),
+    CreateMountPointFailed(std::io::Error),
+    MountFailed(mount::MountError),
+    ApplyPermissionsFailed(mount::MountError),
+    BindMountFailed(mount::MountError),
+}
+
+impl SetupVolumeError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            SetupVolumeError::UnexpectedBlockProvider |
+            SetupVolumeError::ChooseBlockDeviceFailed(_) |
+            SetupVolumeError::DescribeVolumesFailed(_) |
+            SetupVolumeError::AttachVolumeFailed(_) |
+            SetupVolumeError::PermissionDenied(_) => 101,
+            SetupVolumeError::DetectFilesystemFailed(_) |
+            SetupVolumeError::MakeFilesystemFailed(_) => 102,
+            SetupVolumeError::CreateMountPointFailed(_) => 103,
+            SetupVolumeError::MountFailed(_) => 104,
+            SetupVolumeError::ApplyPermissionsFailed(_) => 105,
+            SetupVolumeError::BindMountFailed(_) => 106,
+            SetupVolumeError::LvmFailed(_) |
+            SetupVolumeError::RaidFailed(_) => 107,
+            SetupVolumeError::EncryptionFailed(_) => 108,
+            SetupVolumeError::FilesystemCheckFailed(_) => 109,
+        }
+    }
+}
+
+/// Block devices that the configured volumes other than `config.volumes[index]` are attached at, which that volume
+/// mustn't fall back to or choose.
+fn claimed_block_devices(config: &Config, index: usize) -> Vec<String> {
+    config.volumes
+        .iter()
+        .enumerate()
+        .filter(|&(other, _)| other != index)
+        .flat_map(|(_, volume)| volume.member_block_devices())
+        .collect()
+}
+
+/// Block devices to attach the volume at; `Volume::attachment_block_devices` other than those `claimed` by other
+/// volumes, or chosen from those free on this instance if `block-device` is auto.
+fn attach_block_devices(volume: &Volume, claimed: &[String]) -> Result<Vec<String>, SetupVolumeError> {
+    if !volume.auto_block_device() {
+        let mut attachment_block_devices = volume.attachment_block_devices();
+        attachment_block_devices.retain(|block_device| {
+                                            !claimed.iter()
+                                                 .any(|other| block_devices::same_block_device(other, block_device))
+                                        });
+        return Ok(attachment_block_devices);
+    }
+    let members = match volume.layout {
+        config::Layout::Single => None,
+        _ => Some(volume.members()),
+    };
+    let chosen = block_devices::choose_block_devices(members, claimed)
+        .map_err(SetupVolumeError::ChooseBlockDeviceFailed)?;
+    debug!("chose free block device(s): {}", chosen.join(", "));
+    Ok(chosen)
+}
+
+/// Attaches the volume's EBS volumes, unless an earlier `attach` already did, and returns them along with the block
+/// devices that the layout's members are at.
+fn attach_ebs_volumes(volume: &Volume,
+                      ebs: &config::EbsBlockProviderConfig,
+                      claimed: &[String])
+                      -> Result<(ebs::AttachedVolumes, Vec<String>), SetupVolumeError> {
+    // with block-device auto, the devices chosen by an earlier attach are only known from its attachments
+    if let Some(attached) = ebs::find_attached_volumes(&volume.attachment_block_devices(), ebs)
+           .map_err(SetupVolumeError::DescribeVolumesFailed)? {
+        info!("volume is already attached: {:?}", attached.volumes);
+        let attach_devices = if volume.auto_block_device() {
+            attached.volumes.iter().map(|&(ref block_device, _)| block_device.to_owned()).collect()
+        } else {
+            volume.attachment_block_devices()
+        };
+        return Ok((attached, attach_devices));
+    }
+
+    let attach_devices = attach_block_devices(volume, claimed)?;
+    let identity = ebs::VolumeIdentity {
+        fingerprint: volume.config_fingerprint(),
+        config_hash: volume.config_hash(),
+    };
+    let attach_result = match volume.layout {
+        config::Layout::Single => ebs::find_and_attach_volume(&attach_devices, ebs, &identity),
+        config::Layout::Lvm(_) => ebs::find_and_attach_volume_set(&attach_devices, ebs, false, &identity),
+        config::Layout::Raid(ref raid) => {
+            ebs::find_and_attach_volume_set(&attach_devices, ebs, raid.allow_degraded, &identity)
+        }
+    };
+    if let Err(ebs::AttachVolumeError::FingerprintMismatch(ref volume_id, ref fingerprint)) = attach_result {
+        error!("available volume {} was created for {}, but this configuration is {}; attach it with its original \
+                configuration, or set fingerprint-mismatch: skip to leave it unattached",
+               volume_id,
+               fingerprint,
+               identity.fingerprint);
+    }
+    let attached = attach_result.map_err(SetupVolumeError::AttachVolumeFailed)?;
+    info!("attach volume succeeded");
+    Ok((attached, attach_devices))
+}
+
+fn setup_volume(volume: &Volume, claimed: &[String], report: &mut VolumeReport) -> Result<(), SetupVolumeError> {
+    let start = Instant::now();
+    let (layout_device, attach_devices) = match volume.block_provider {
+        config::BlockProvider::AwsEbs(ref ebs) => {
+            let (attached, attach_devices) = attach_ebs_volumes(volume, ebs, claimed)?;
+            // a single volume is attached at a later block device than configured if that one was in use
+            let layout_device = match volume.layout {
+                config::Layout::Single => attached.volumes[0].0.to_owned(),
+                _ => volume.layout_device(),
+            };
+            report.ebs_volumes = attached.volumes.into_iter().collect();
+            report.ebs_volumes_created = attached.created;
+            (layout_device, attach_devices)
+        }
+        _ => return Err(SetupVolumeError::UnexpectedBlockProvider),
+    };
+    report.record_timing("attach", start);
+
+    let start = Instant::now();
+    match volume.layout {
+        config::Layout::Single => {}
+        config::Layout::Lvm(ref lvm) => {
+            lvm::ensure_logical_volume(lvm, &attach_devices)
+                .map_err(SetupVolumeError::LvmFailed)?;
+            info!("logical volume {} is active", volume.layout_device());
+            report.record_timing("layout", start);
+        }
+        config::Layout::Raid(ref raid) => {
+            raid::ensure_array(raid, &attach_devices)
+                .map_err(SetupVolumeError::RaidFailed)?;
+            info!("raid array {} is running", volume.layout_device());
+            report.record_timing("layout", start);
+        }
+    }
+
+    if let Some(ref encryption) = volume.encryption {
+        let start = Instant::now();
+        luks::ensure_open(encryption, layout_device.as_str())
+            .map_err(SetupVolumeError::EncryptionFailed)?;
+        info!("encrypted volume {} is open", volume.filesystem_device());
+        report.record_timing("encryption", start);
+    }
+
+    let device = match volume.encryption {
+        Some(_) => volume.filesystem_device(),
+        None => layout_device,
+    };
+    report.device = device.to_owned();
+    let start = Instant::now();
+    let mut read_only = false;
+    let filesystem_created = if mkfs::filesystem_exists(device.as_str())
+           .map_err(SetupVolumeError::DetectFilesystemFailed)? {
+        info!("filesystem already exists on block device");
+        if let Some(ref fsck) = volume.file_system.fsck {
+            read_only = fsck::check_filesystem(fsck, device.as_str())
+                .map_err(SetupVolumeError::FilesystemCheckFailed)?;
+            info!("filesystem check completed");
+            report.record_timing("fsck", start);
+        }
+        false
+    } else {
+        info!("filesystem was not found; creating");
+        mkfs::make_filesystem(&volume.file_system, device.as_str())
+            .map_err(SetupVolumeError::MakeFilesystemFailed)?;
+        info!("created filesystem successfully");
+        report.record_timing("mkfs", start);
+        true
+    };
+    report.filesystem_created = filesystem_created;
+    match mkfs::filesystem_info(device.as_str()) {
+        Ok(info) => {
+            report.filesystem_type = info.fs_type;
+            report.filesystem_uuid = info.uuid;
+        }
+        Err(e) => warn!("unable to read filesystem type and uuid: {:?}", e),
+    }
+
+    let start = Instant::now();
+    std::fs::create_dir_all(volume.mount.target.to_owned())
+        .map_err(SetupVolumeError::CreateMountPointFailed)?;
+    info!("created/ensured mount point directory successfully");
+
+    mount::mount(&volume.mount, device.as_str(), read_only)
+        .map_err(SetupVolumeError::MountFailed)?;
+    if read_only {
+        warn!("mounted filesystem read-only");
+    } else {
+        info!("mounted filesystem successfully");
+    }
+    report.mounted = true;
+    report.read_only = read_only;
+
+    if read_only {
+        info!("skipping mount point ownership and permissions on read-only filesystem");
+    } else if filesystem_created || volume.mount.apply_permissions == config::ApplyPermissions::Always {
+        mount::apply_permissions(&volume.mount)
+            .map_err(SetupVolumeError::ApplyPermissionsFailed)?;
+        info!("applied mount point ownership and permissions successfully");
+    }
+
+    for bind in &volume.mount.binds {
+        mount::bind_mount(&volume.mount, bind)
+            .map_err(SetupVolumeError::BindMountFailed)?;
+        info!("bind mounted {} onto {} successfully", bind.source, bind.target);
+    }
+    report.record_timing("mount", start);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+enum TeardownVolumeError {
+    UnexpectedBlockProvider,
+    FindMountFailed(mount::MountError),
+    UnmountFailed(mount::MountError),
+    LvmFailed(lvm::LvmError),
+    RaidFailed(raid::RaidError),
+    EncryptionFailed(luks::LuksError),
+    DetachVolumeFailed(ebs::DetachVolumeError),
+}
+
+impl TeardownVolumeError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            TeardownVolumeError::UnexpectedBlockProvider |
+            TeardownVolumeError::DetachVolumeFailed(_) => 101,
+            TeardownVolumeError::FindMountFailed(_) |
+            TeardownVolumeError::UnmountFailed(_) => 104,
+            TeardownVolumeError::LvmFailed(_) |
+            TeardownVolumeError::RaidFailed(_) => 107,
+            TeardownVolumeError::EncryptionFailed(_) => 108,
+        }
+    }
+}
+
+fn teardown_volume(volume: &Volume) -> Result<(), TeardownVolumeError> {
+    for bind in volume.mount.binds.iter().rev() {
+        unmount_if_mounted(&bind.target)?;
+    }
+    unmount_if_mounted(&volume.mount.target)?;
+
+    if let Some(ref encryption) = volume.encryption {
+        if Path::new(&volume.filesystem_device()).exists() {
+            luks::close(encryption).map_err(TeardownVolumeError::EncryptionFailed)?;
+            info!("closed encrypted device {}", volume.filesystem_device());
+        }
+    }
+
+    if Path::new(&volume.layout_device()).exists() {
+        match volume.layout {
+            config::Layout::Single => {}
+            config::Layout::Lvm(ref lvm) => {
+                lvm::deactivate_logical_volume(lvm).map_err(TeardownVolumeError::LvmFailed)?;
+                info!("deactivated volume group {}", lvm.volume_group);
+            }
+            config::Layout::Raid(ref raid) => {
+                raid::stop_array(raid).map_err(TeardownVolumeError::RaidFailed)?;
+                info!("stopped raid array {}", raid.device);
+            }
+        }
+    }
+
+    match volume.block_provider {
+        config::BlockProvider::AwsEbs(ref ebs) => {
+            let detached = ebs::detach_volumes(&volume.attachment_block_devices(), ebs)
+                .map_err(TeardownVolumeError::DetachVolumeFailed)?;
+            info!("detached {} volume(s)", detached);
+            Ok(())
+        }
+        _ => Err(TeardownVolumeError::UnexpectedBlockProvider),
+    }
+}
+
+fn unmount_if_mounted(target: &str) -> Result<(), TeardownVolumeError> {
+    if mount::find_mount(target).map_err(TeardownVolumeError::FindMountFailed)?.is_some() {
+        mount::unmount(target).map_err(TeardownVolumeError::UnmountFailed)?;
+        info!("unmounted {}", target);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_attach_report() {
+        let mut ebs_volumes = BTreeMap::new();
+        ebs_volumes.insert(String::from("/dev/xvdf"), String::from("vol-1234"));
+        let mut timings = BTreeMap::new();
+        timings.insert("attach", 1.5);
+        let report = AttachReport {
+            exit_code: 0,
+            volumes: vec![VolumeReport {
+                              block_device: String::from("/dev/xvdf"),
+                              exit_code: 0,
+                              error: None,
+                              ebs_volumes: ebs_volumes,
+                              ebs_volumes_created: true,
+                              device: String::from("/dev/xvdf"),
+                              filesystem_created: true,
+                              filesystem_type: Some(String::from("ext4")),
+                              filesystem_uuid: None,
+                              mount_target: String::from("/mnt"),
+                              mounted: true,
+                              read_only: false,
+                              timings: timings,
+                          }],
+        };
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        let volume = &json["volumes"][0];
+        assert_eq!("vol-1234", volume["ebs-volumes"]["/dev/xvdf"]);
+        assert_eq!(Some(true), volume["ebs-volumes-created"].as_bool());
+        assert_eq!("ext4", volume["filesystem-type"]);
+        assert!(volume["filesystem-uuid"].is_null());
+        assert_eq!(Some(1.5), volume["timings"]["attach"].as_f64());
+    }
+
+    #[test]
+    fn reports_auto_block_device_once_attached() {
+        let volume: Volume = serde_json::from_str(r#"{"block-device": "auto",
+                                                      "block-provider": {"aws-ebs": {"ebs-tags": {"a": "b"},
+                                                                                     "size": 100}}}"#)
+            .unwrap();
+        assert_eq!("", VolumeReport::new(&volume).device);
+    }
+
+    #[test]
+    fn falls_back_only_to_unclaimed_block_devices() {
+        let volume: Volume = serde_json::from_str(r#"{"block-device": "/dev/xvdn",
+                                                      "block-provider": {"aws-ebs": {"ebs-tags": {"a": "b"},
+                                                                                     "size": 100}}}"#)
+            .unwrap();
+        assert_eq!(vec!["/dev/xvdn", "/dev/xvdo", "/dev/xvdp"],
+                   attach_block_devices(&volume, &[]).unwrap());
+        assert_eq!(vec!["/dev/xvdn", "/dev/xvdp"],
+                   attach_block_devices(&volume, &[String::from("/dev/sdo")]).unwrap());
+    }
+}
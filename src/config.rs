@@ -1,14 +1,48 @@
 use serde_yaml;
+use serde_json;
+use toml;
+use ring::digest;
+use rustc_serialize::hex::ToHex;
 use std;
 use std::collections::HashMap;
 use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
 use std::error::Error;
 use std::fmt;
+use template::{self, Variables};
+use config_source::{self, ConfigSource, ConfigSourceError};
+use yaml_location;
+use block_devices;
 
-#[derive(Debug, Deserialize)]
+/// Top-level configuration.  A single volume can be configured with the top-level `block-device`,
+/// `block-provider`, `file-system`, `mount`, `layout` and `encryption` keys, or multiple volumes with the `volumes`
+/// list; when loaded, the single-volume form is folded into `volumes` so that the rest of the program only deals
+/// with the list.
+#[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
+    pub block_device: Option<String>,
+    #[serde(default)]
+    pub block_provider: Option<BlockProvider>,
+    #[serde(default)]
+    pub file_system: Option<FileSystem>,
+    #[serde(default)]
+    pub mount: Option<Mount>,
+    #[serde(default)]
+    pub layout: Option<Layout>,
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Volume {
     #[serde(default = "default_block_device")]
     pub block_device: String,
     pub block_provider: BlockProvider,
@@ -16,20 +50,208 @@ pub struct Config {
     pub file_system: FileSystem,
     #[serde(default = "default_mount")]
     pub mount: Mount,
+    #[serde(default = "default_layout")]
+    pub layout: Layout,
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+}
+
+impl Volume {
+    /// Whether the block devices are chosen from those free on the instance when the volume is attached, rather than
+    /// configured.
+    pub fn auto_block_device(&self) -> bool {
+        self.block_device == AUTO_BLOCK_DEVICE
+    }
+
+    /// Number of EBS volumes that make up the volume's layout.
+    pub fn members(&self) -> usize {
+        match self.layout {
+            Layout::Single => 1,
+            Layout::Lvm(ref lvm) => lvm.members as usize,
+            Layout::Raid(ref raid) => raid.members as usize,
+        }
+    }
+
+    /// Block devices that the volume's member(s) are attached to.  Multi-member layouts use consecutive device names
+    /// starting at `block-device`, eg. /dev/xvdf, /dev/xvdg, /dev/xvdh.  Empty if `block-device` is auto, as they
+    /// aren't known until the volume is attached.  Members past drive letter z, and block devices that don't end in a
+    /// drive letter, which validation rejects, have none.
+    pub fn member_block_devices(&self) -> Vec<String> {
+        let last = match self.block_device.bytes().last() {
+            Some(last) if !self.auto_block_device() && last >= b'a' && last <= b'z' => last,
+            _ => return Vec::new(),
+        };
+        let prefix = &self.block_device[..self.block_device.len() - 1];
+        (0..self.members())
+            .map(|i| last as usize + i)
+            .take_while(|&letter| letter <= b'z' as usize)
+            .map(|letter| format!("{}{}", prefix, letter as u8 as char))
+            .collect()
+    }
+
+    /// Block devices that the volume's EBS volumes may be attached at.  A single volume moves on to the following
+    /// device names, up to eg. /dev/xvdp, if its block device is already in use on the instance; members of a set are
+    /// always attached at their own block devices.  Any that `block-device: auto` may choose if it's auto.
+    pub fn attachment_block_devices(&self) -> Vec<String> {
+        if self.auto_block_device() {
+            return block_devices::auto_block_devices();
+        }
+        match self.layout {
+            Layout::Single => {
+                let (prefix, last) = self.block_device.split_at(self.block_device.len() - 1);
+                let last = last.as_bytes()[0];
+                let mut block_devices = vec![self.block_device.to_owned()];
+                if last >= b'a' && last < b'p' {
+                    block_devices.extend((last + 1..b'p' + 1).map(|c| format!("{}{}", prefix, c as char)));
+                }
+                block_devices
+            }
+            Layout::Lvm(_) |
+            Layout::Raid(_) => self.member_block_devices(),
+        }
+    }
+
+    /// Block device presented by the volume's layout; the attached block device itself for a single volume, or the
+    /// logical volume or md array assembled from the members.
+    pub fn layout_device(&self) -> String {
+        match self.layout {
+            Layout::Single => self.block_device.to_owned(),
+            Layout::Lvm(ref lvm) => format!("/dev/{}/{}", lvm.volume_group, lvm.logical_volume),
+            Layout::Raid(ref raid) => raid.device.to_owned(),
+        }
+    }
+
+    /// Block device that the filesystem is created on and mounted from; the opened dm-crypt mapping if the volume is
+    /// encrypted, otherwise the layout device.
+    pub fn filesystem_device(&self) -> String {
+        match self.encryption {
+            Some(ref encryption) => format!("/dev/mapper/{}", encryption.name),
+            None => self.layout_device(),
+        }
+    }
+
+    /// Summarizes the settings that determine what's on the volume's EBS volumes: the filesystem type, the layout and
+    /// its members, and whether it's encrypted, eg. `xfs/lvm-3/luks`.  A volume created with one fingerprint can't be
+    /// used with a configuration with another.
+    pub fn config_fingerprint(&self) -> String {
+        let layout = match self.layout {
+            Layout::Single => String::from("single"),
+            Layout::Lvm(ref lvm) => format!("lvm-{}", lvm.members),
+            Layout::Raid(ref raid) => format!("raid{}-{}", raid.level, raid.members),
+        };
+        let encryption = if self.encryption.is_some() { "luks" } else { "none" };
+        format!("{}/{}/{}", self.file_system.filesystem_type(), layout, encryption)
+    }
+
+    /// A short digest of the volume's configuration, after templates and overrides are applied, to record on its
+    /// EBS volumes.  The settings are hashed as JSON with sorted keys, so their order in the configuration (eg. of
+    /// `ebs-tags`) doesn't matter.
+    pub fn config_hash(&self) -> String {
+        let settings = serde_json::to_value(self).map(|value| value.to_string()).unwrap_or_default();
+        digest::digest(&digest::SHA256, settings.as_bytes()).as_ref()[..8].to_hex()
+    }
 }
 
-fn default_block_device() -> String {
+/// `block-device` value that chooses a free block device when the volume is attached.
+pub const AUTO_BLOCK_DEVICE: &'static str = "auto";
+
+pub fn default_block_device() -> String {
     String::from("/dev/xvdc")
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    Single,
+    Lvm(LvmLayout),
+    Raid(RaidLayout),
+}
+
+fn default_layout() -> Layout {
+    Layout::Single
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct LvmLayout {
+    pub members: u32,
+    #[serde(default = "default_lvm_volume_group")]
+    pub volume_group: String,
+    #[serde(default = "default_lvm_logical_volume")]
+    pub logical_volume: String,
+    #[serde(default)]
+    pub stripe_size: Option<String>,
+}
+
+pub fn default_lvm_volume_group() -> String {
+    String::from("cps")
+}
+
+pub fn default_lvm_logical_volume() -> String {
+    String::from("data")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct RaidLayout {
+    pub level: u32,
+    pub members: u32,
+    #[serde(default = "default_raid_device")]
+    pub device: String,
+    #[serde(default)]
+    pub allow_degraded: bool,
+}
+
+pub fn default_raid_device() -> String {
+    String::from("/dev/md0")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Encryption {
+    #[serde(default = "default_encryption_name")]
+    pub name: String,
+    pub key_source: KeySource,
+}
+
+pub fn default_encryption_name() -> String {
+    String::from("cps-crypt")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeySource {
+    /// Path to a file containing the passphrase.
+    File(String),
+    /// Name of an environment variable containing the passphrase.
+    Env(String),
+    /// A KMS-encrypted passphrase, decrypted with the instance's credentials.
+    Kms(KmsKeySource),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct KmsKeySource {
+    /// Base64-encoded ciphertext blob, as output by `aws kms encrypt`.
+    #[serde(default)]
+    pub ciphertext: Option<String>,
+    /// Path to a file containing the base64-encoded ciphertext blob.
+    #[serde(default)]
+    pub ciphertext_file: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum BlockProvider {
     AwsEbs(EbsBlockProviderConfig),
     ReservedForFuture,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct EbsBlockProviderConfig {
@@ -38,65 +260,317 @@ pub struct EbsBlockProviderConfig {
     pub volume_type: String,
     pub size: i32,
     pub ebs_tags: HashMap<String, String>,
+    #[serde(default)]
+    pub selection: Option<VolumeSelection>,
+    #[serde(default)]
+    pub fingerprint_mismatch: FingerprintMismatch,
+    #[serde(default)]
+    pub pool: Option<VolumePool>,
+    #[serde(default)]
+    pub timeouts: EbsTimeouts,
+}
+
+/// Available volumes that the `pool` command keeps ready, so that an instance attaches one instead of waiting for a
+/// new volume to be created.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct VolumePool {
+    /// Number of available volumes, or complete volume sets, to keep in each availability zone.
+    pub size: u32,
+    /// Availability zones to keep volumes in; the instance's if none are given.
+    #[serde(default)]
+    pub availability_zones: Vec<String>,
+    /// Snapshot to create pooled volumes from, eg. of a freshly formatted volume, so that they don't need a
+    /// filesystem created when they're first attached.
+    #[serde(default)]
+    pub snapshot_id: Option<String>,
+}
+
+/// What attach does with an available volume whose configuration fingerprint (see `Volume::config_fingerprint`)
+/// differs from the configuration's.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FingerprintMismatch {
+    /// Fail the attach, so that the volume's data isn't abandoned for a new volume.
+    Fail,
+    /// Try the next available volume, or create a new one.
+    Skip,
+}
+
+impl Default for FingerprintMismatch {
+    fn default() -> FingerprintMismatch {
+        FingerprintMismatch::Fail
+    }
 }
 
-fn default_ebs_volume_type() -> String {
+/// Which volume is attached when several are available; without a policy, volumes are tried in the order that EC2
+/// describes them.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VolumeSelection {
+    /// Most recently created first.
+    Newest,
+    /// Least recently created first.
+    Oldest,
+    /// Largest first.
+    Largest,
+    /// The volume with the most recent snapshot first; volumes that have never been snapshotted are tried last.
+    MostRecentSnapshot,
+    /// Volumes with the given tag value first.
+    PreferTag(PreferredTag),
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PreferredTag {
+    pub key: String,
+    pub value: String,
+}
+
+pub fn default_ebs_volume_type() -> String {
     String::from("gp2")
 }
 
-#[derive(Debug, Deserialize)]
+/// How long to wait for each stage of attaching or detaching a volume, and how often EC2 is polled while waiting.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct EbsTimeouts {
+    #[serde(default)]
+    pub volume_available: Polling,
+    #[serde(default)]
+    pub volume_attached: Polling,
+    #[serde(default)]
+    pub volume_detached: Polling,
+    #[serde(default = "default_throttle_retries")]
+    pub throttle_retries: u32,
+}
+
+impl Default for EbsTimeouts {
+    fn default() -> EbsTimeouts {
+        EbsTimeouts {
+            volume_available: Polling::default(),
+            volume_attached: Polling::default(),
+            volume_detached: Polling::default(),
+            throttle_retries: default_throttle_retries(),
+        }
+    }
+}
+
+pub fn default_throttle_retries() -> u32 {
+    8
+}
+
+/// Polling for a change in state, in seconds.  The interval between polls starts at `initial_interval` and doubles
+/// up to `max_interval`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Polling {
+    #[serde(default = "default_polling_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_polling_initial_interval")]
+    pub initial_interval: u64,
+    #[serde(default = "default_polling_max_interval")]
+    pub max_interval: u64,
+}
+
+impl Default for Polling {
+    fn default() -> Polling {
+        Polling {
+            timeout: default_polling_timeout(),
+            initial_interval: default_polling_initial_interval(),
+            max_interval: default_polling_max_interval(),
+        }
+    }
+}
+
+pub fn default_polling_timeout() -> u64 {
+    300
+}
+
+pub fn default_polling_initial_interval() -> u64 {
+    1
+}
+
+pub fn default_polling_max_interval() -> u64 {
+    15
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct FileSystem {
     #[serde(default = "default_file_system_mkfs")]
     pub mkfs: Vec<String>,
+    #[serde(default)]
+    pub fsck: Option<Fsck>,
+}
+
+impl FileSystem {
+    /// The filesystem type given to mkfs with `-t`, `--type` or `--type=`; mkfs creates ext2 if none is given.
+    pub fn filesystem_type(&self) -> &str {
+        let mut args = self.mkfs.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-t" || arg == "--type" {
+                return args.next().map_or("ext2", |filesystem_type| filesystem_type.as_str());
+            } else if arg.starts_with("--type=") {
+                return &arg["--type=".len()..];
+            }
+        }
+        "ext2"
+    }
 }
 
 fn default_file_system() -> FileSystem {
-    FileSystem { mkfs: default_file_system_mkfs() }
+    FileSystem {
+        mkfs: default_file_system_mkfs(),
+        fsck: None,
+    }
 }
 
-fn default_file_system_mkfs() -> Vec<String> {
+pub fn default_file_system_mkfs() -> Vec<String> {
     vec![String::from("-t"), String::from("ext4"), String::from("-m"), String::from("0")]
 }
 
-#[derive(Debug, Deserialize)]
+/// Checks an existing filesystem before it is mounted; absent by default, in which case no check is run.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Fsck {
+    /// Seconds to allow the check to run before it is killed and treated as failed.
+    #[serde(default = "default_fsck_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_fsck_on_errors")]
+    pub on_errors: FsckErrorAction,
+}
+
+pub fn default_fsck_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsckErrorAction {
+    /// Fail the volume without mounting it.
+    Abort,
+    /// Mount the filesystem read-only, so that data can be recovered but not further damaged.
+    ReadOnly,
+    /// Mount the filesystem read-write regardless.
+    Mount,
+}
+
+fn default_fsck_on_errors() -> FsckErrorAction {
+    FsckErrorAction::Abort
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct Mount {
     #[serde(default = "default_mount_target")]
     pub target: String,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub context: Option<String>,
+    #[serde(default = "default_mount_apply_permissions")]
+    pub apply_permissions: ApplyPermissions,
+    #[serde(default)]
+    pub binds: Vec<Bind>,
 }
 
 fn default_mount() -> Mount {
-    Mount { target: default_mount_target() }
+    Mount {
+        target: default_mount_target(),
+        owner: None,
+        group: None,
+        mode: None,
+        context: None,
+        apply_permissions: default_mount_apply_permissions(),
+        binds: Vec::new(),
+    }
 }
 
-fn default_mount_target() -> String {
+pub fn default_mount_target() -> String {
     String::from("/mnt")
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApplyPermissions {
+    /// Only set ownership, mode and context on the filesystem root when the filesystem was just created.
+    OnCreate,
+    /// Set ownership, mode and context on the filesystem root every time it is mounted.
+    Always,
+}
+
+fn default_mount_apply_permissions() -> ApplyPermissions {
+    ApplyPermissions::OnCreate
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bind {
+    /// Directory relative to the mount target, created if missing.
+    pub source: String,
+    /// Absolute path that the source directory is bind-mounted onto.
+    pub target: String,
+}
+
+impl Mount {
+    /// Parses the configured octal `mode` string, eg. "0750", into permission bits.
+    pub fn mode_bits(&self) -> Option<Result<u32, std::num::ParseIntError>> {
+        self.mode.as_ref().map(|mode| u32::from_str_radix(mode, 8))
+    }
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     YamlParseError(serde_yaml::Error),
+    JsonParseError(serde_json::Error),
+    TomlParseError(String),
     IoError(std::io::Error),
     InvalidBlockProviderAwsEbs(String),
     InvalidFileSystem(String),
     InvalidMount(String),
+    InvalidVolumes(String),
+    InvalidLayout(String),
+    InvalidEncryption(String),
+    InvalidOverride(String),
+    InvalidTemplate(String),
+    SourceFailed(ConfigSourceError),
+    Invalid(Vec<ValidationError>),
 }
 
 impl Error for ConfigError {
     fn description(&self) -> &str {
         match *self {
             ConfigError::YamlParseError(ref err) => err.description(),
+            ConfigError::JsonParseError(ref err) => err.description(),
+            ConfigError::TomlParseError(_) => "invalid TOML configuration",
             ConfigError::IoError(ref err) => err.description(),
             ConfigError::InvalidBlockProviderAwsEbs(_) => "invalid configuration in block-provider aws-ebs",
             ConfigError::InvalidFileSystem(_) => "invalid configuration in file-system",
             ConfigError::InvalidMount(_) => "invalid configuration in mount",
+            ConfigError::InvalidVolumes(_) => "invalid configuration in volumes",
+            ConfigError::InvalidLayout(_) => "invalid configuration in layout",
+            ConfigError::InvalidEncryption(_) => "invalid configuration in encryption",
+            ConfigError::InvalidOverride(_) => "invalid configuration override",
+            ConfigError::InvalidTemplate(_) => "invalid variable reference in configuration",
+            ConfigError::SourceFailed(_) => "unable to read configuration source",
+            ConfigError::Invalid(_) => "invalid configuration",
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match *self {
             ConfigError::YamlParseError(ref err) => Some(err),
+            ConfigError::JsonParseError(ref err) => Some(err),
             ConfigError::IoError(ref err) => Some(err),
             _ => None,
         }
@@ -107,10 +581,22 @@ impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ConfigError::YamlParseError(ref err) => err.fmt(f),
+            ConfigError::JsonParseError(ref err) => err.fmt(f),
+            ConfigError::TomlParseError(ref msg) => write!(f, "{}", msg),
             ConfigError::IoError(ref err) => err.fmt(f),
             ConfigError::InvalidBlockProviderAwsEbs(ref msg) => write!(f, "{}", msg),
             ConfigError::InvalidFileSystem(ref msg) => write!(f, "{}", msg),
             ConfigError::InvalidMount(ref msg) => write!(f, "{}", msg),
+            ConfigError::InvalidVolumes(ref msg) => write!(f, "{}", msg),
+            ConfigError::InvalidLayout(ref msg) => write!(f, "{}", msg),
+            ConfigError::InvalidEncryption(ref msg) => write!(f, "{}", msg),
+            ConfigError::InvalidOverride(ref msg) => write!(f, "{}", msg),
+            ConfigError::InvalidTemplate(ref msg) => write!(f, "{}", msg),
+            ConfigError::SourceFailed(ref err) => err.fmt(f),
+            ConfigError::Invalid(ref errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", messages.join("; "))
+            }
         }
     }
 }
@@ -121,202 +607,1709 @@ impl From<serde_yaml::Error> for ConfigError {
     }
 }
 
+impl From<serde_json::Error> for ConfigError {
+    fn from(err: serde_json::Error) -> ConfigError {
+        ConfigError::JsonParseError(err)
+    }
+}
+
+impl From<ConfigSourceError> for ConfigError {
+    fn from(err: ConfigSourceError) -> ConfigError {
+        ConfigError::SourceFailed(err)
+    }
+}
+
 impl From<std::io::Error> for ConfigError {
     fn from(err: std::io::Error) -> ConfigError {
         ConfigError::IoError(err)
     }
 }
 
-pub fn read_config_from_file(config_path: &str) -> Result<Config, ConfigError> {
-    let config_file = File::open(config_path)?;
-    let config = serde_yaml::from_reader(config_file)?;
-    if let Some(err) = validate_config(&config) {
-        Err(err)
+/// Format of a configuration document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Chooses the format of a configuration file by its extension, defaulting to YAML.
+    pub fn from_path(path: &str) -> ConfigFormat {
+        if path.ends_with(".json") {
+            ConfigFormat::Json
+        } else if path.ends_with(".toml") {
+            ConfigFormat::Toml
+        } else {
+            ConfigFormat::Yaml
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConfigFormat, String> {
+        match s {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(format!("expected yaml, json or toml: {}", s)),
+        }
+    }
+}
+
+/// Reads the configuration from `source`, if there is one, and applies `overrides` on top of it before validating the
+/// result.  Each override is the name of one of the `OVERRIDE_OPTIONS` and its values.  Files are parsed as `format`
+/// if given, otherwise by their extension; other sources are YAML unless `format` is given.  `${...}` references are
+/// expanded from `variables`.
+pub fn read_config(source: Option<&ConfigSource>,
+                   format: Option<ConfigFormat>,
+                   overrides: &[(&str, Vec<String>)],
+                   variables: &mut Variables)
+                   -> Result<Config, ConfigError> {
+    // YAML documents are kept so that validation errors can be located in them
+    let mut yaml_text = None;
+    let mut config = {
+        let mut parse = |text: String, default_format: ConfigFormat| -> Result<Config, ConfigError> {
+            let format = format.unwrap_or(default_format);
+            let config = parse_config_document(&text, format)?;
+            if format == ConfigFormat::Yaml {
+                yaml_text = Some(text);
+            }
+            Ok(config)
+        };
+        match source {
+            None => Config::default(),
+            Some(&ConfigSource::File(ref path)) => {
+                let mut text = String::new();
+                File::open(path)?.read_to_string(&mut text)?;
+                parse(text, ConfigFormat::from_path(path))?
+            }
+            Some(&ConfigSource::UserData) => parse(config_source::user_data()?, ConfigFormat::Yaml)?,
+            Some(&ConfigSource::Ssm(ref name)) => parse(config_source::ssm_parameter(name)?, ConfigFormat::Yaml)?,
+            Some(&ConfigSource::InstanceTags(ref prefix)) => {
+                let settings = config_source::instance_tag_settings(prefix)?;
+                let mut config = Config::default();
+                for (name, values) in settings_overrides(&settings)? {
+                    for value in values {
+                        apply_override(&mut config, name, &value)?;
+                    }
+                }
+                config
+            }
+        }
+    };
+    for &(name, ref values) in overrides {
+        for value in values {
+            apply_override(&mut config, name, value)?;
+        }
+    }
+    match yaml_text {
+        Some(text) => locate_validation_errors(load_config_with_variables(config, variables), &text),
+        None => load_config_with_variables(config, variables),
+    }
+}
+
+/// Adds the line and column of each setting that failed validation within the YAML document it was read from.
+fn locate_validation_errors(result: Result<Config, ConfigError>, yaml_text: &str) -> Result<Config, ConfigError> {
+    match result {
+        Err(ConfigError::Invalid(mut errors)) => {
+            let locations = yaml_location::locate(yaml_text);
+            for error in &mut errors {
+                error.location = yaml_location::find(&locations, &error.path);
+            }
+            Err(ConfigError::Invalid(errors))
+        }
+        result => result,
+    }
+}
+
+/// Parses a configuration document, without normalizing or validating it.
+fn parse_config_document(text: &str, format: ConfigFormat) -> Result<Config, ConfigError> {
+    match format {
+        ConfigFormat::Yaml => Ok(serde_yaml::from_str(text)?),
+        ConfigFormat::Json => Ok(serde_json::from_str(text)?),
+        ConfigFormat::Toml => {
+            let mut parser = toml::Parser::new(text);
+            let table = match parser.parse() {
+                Some(table) => table,
+                None => {
+                    let messages: Vec<String> = parser.errors
+                        .iter()
+                        .map(|e| {
+                                 let (line, col) = parser.to_linecol(e.lo);
+                                 format!("line {} column {}: {}", line + 1, col + 1, e.desc)
+                             })
+                        .collect();
+                    return Err(ConfigError::TomlParseError(messages.join("; ")));
+                }
+            };
+            // the toml crate parses into its own value type; the configuration is deserialized from the equivalent
+            // JSON value, so that the same serde attributes apply
+            serde_json::from_value(toml_to_json(toml::Value::Table(table)))
+                .map_err(|e| ConfigError::TomlParseError(e.to_string()))
+        }
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(s) |
+        toml::Value::Datetime(s) => serde_json::Value::String(s),
+        toml::Value::Integer(i) => serde_json::Value::from(i),
+        toml::Value::Float(f) => serde_json::Value::from(f),
+        toml::Value::Boolean(b) => serde_json::Value::Bool(b),
+        toml::Value::Array(array) => serde_json::Value::Array(array.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => {
+            serde_json::Value::Object(table.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+        }
+    }
+}
+
+/// Converts settings named after `OVERRIDE_OPTIONS`, such as those read from instance tags, into overrides.  Settings
+/// are ordered as the options are, so that eg. `layout` applies before `layout-members`; the values of options that
+/// may be repeated are separated as by `split_values`.
+fn settings_overrides(settings: &HashMap<String, String>) -> Result<Vec<(&'static str, Vec<String>)>, ConfigError> {
+    if let Some(name) = settings.keys().find(|name| !OVERRIDE_OPTIONS.iter().any(|o| o.name == name.as_str())) {
+        return Err(ConfigError::InvalidOverride(format!("unknown setting: {}", name)));
+    }
+    Ok(OVERRIDE_OPTIONS.iter()
+           .filter_map(|option| {
+               settings.get(option.name).map(|value| if option.multiple {
+                                                 (option.name, split_values(value))
+                                             } else {
+                                                 (option.name, vec![value.to_owned()])
+                                             })
+           })
+           .collect())
+}
+
+/// Splits the value of a setting that may be repeated, given as a single string such as an environment variable, at
+/// each comma.  A comma within a value is written as `\,`, and a backslash before a comma as `\\`.
+pub fn split_values(value: &str) -> Vec<String> {
+    let mut values = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some(escaped @ ',') |
+                    Some(escaped @ '\\') => values.last_mut().unwrap().push(escaped),
+                    Some(other) => {
+                        values.last_mut().unwrap().push('\\');
+                        values.last_mut().unwrap().push(other);
+                    }
+                    None => values.last_mut().unwrap().push('\\'),
+                }
+            }
+            ',' => values.push(String::new()),
+            _ => values.last_mut().unwrap().push(c),
+        }
+    }
+    values
+}
+
+/// A setting that can be given on the command line as `--<name> <value>`, or in the environment as `CPS_<NAME>` (upper
+/// case, with dashes replaced by underscores).  Settings apply to the single-volume form of the configuration.
+pub struct OverrideOption {
+    pub name: &'static str,
+    pub hint: &'static str,
+    pub description: &'static str,
+    /// Whether the setting may be given more than once; in the environment, values are separated by commas (see
+    /// `split_values`).
+    pub multiple: bool,
+}
+
+macro_rules! override_option {
+    ($name:expr, $hint:expr, $description:expr) => {
+        OverrideOption { name: $name, hint: $hint, description: $description, multiple: false }
+    };
+    ($name:expr, $hint:expr, $description:expr, multiple) => {
+        OverrideOption { name: $name, hint: $hint, description: $description, multiple: true }
+    };
+}
+
+/// Settings that can be overridden, in the order they're applied; eg. `layout` is applied before the layout's own
+/// settings, and an encryption key source before the encryption `name`.
+pub const OVERRIDE_OPTIONS: &'static [OverrideOption] =
+    &[override_option!("block-device", "DEVICE", "block device to attach the volume to"),
+      override_option!("volume-type", "TYPE", "block-provider aws-ebs type"),
+      override_option!("size", "GB", "block-provider aws-ebs size"),
+      override_option!("tag", "KEY=VALUE", "block-provider aws-ebs tag", multiple),
+      override_option!("mkfs", "ARGS", "file-system mkfs arguments, separated by spaces"),
+      override_option!("fsck-timeout", "SECONDS", "file-system fsck timeout; enables fsck"),
+      override_option!("fsck-on-errors", "ACTION", "file-system fsck on-errors; enables fsck"),
+      override_option!("mount-target", "PATH", "mount target"),
+      override_option!("mount-owner", "USER", "mount owner"),
+      override_option!("mount-group", "GROUP", "mount group"),
+      override_option!("mount-mode", "MODE", "mount mode"),
+      override_option!("mount-context", "CONTEXT", "mount context"),
+      override_option!("mount-apply-permissions", "WHEN", "mount apply-permissions"),
+      override_option!("bind", "SOURCE:TARGET", "mount bind", multiple),
+      override_option!("layout", "LAYOUT", "layout; single, lvm or raid"),
+      override_option!("layout-members", "COUNT", "lvm or raid layout members"),
+      override_option!("lvm-volume-group", "NAME", "lvm layout volume-group"),
+      override_option!("lvm-logical-volume", "NAME", "lvm layout logical-volume"),
+      override_option!("lvm-stripe-size", "SIZE", "lvm layout stripe-size"),
+      override_option!("raid-level", "LEVEL", "raid layout level"),
+      override_option!("raid-device", "DEVICE", "raid layout device"),
+      override_option!("raid-allow-degraded", "BOOL", "raid layout allow-degraded"),
+      override_option!("encryption-key-file", "PATH", "encryption key-source file"),
+      override_option!("encryption-key-env", "NAME", "encryption key-source env"),
+      override_option!("encryption-kms-ciphertext", "BASE64", "encryption key-source kms ciphertext"),
+      override_option!("encryption-kms-ciphertext-file", "PATH", "encryption key-source kms ciphertext-file"),
+      override_option!("encryption-name", "NAME", "encryption name")];
+
+fn apply_override(config: &mut Config, name: &str, value: &str) -> Result<(), ConfigError> {
+    let invalid = |expected: &str| {
+        ConfigError::InvalidOverride(format!("invalid value for {}, expected {}: {}", name, expected, value))
+    };
+    let requires = |requirement: &str| ConfigError::InvalidOverride(format!("{} requires {}", name, requirement));
+
+    match name {
+        "block-device" => config.block_device = Some(String::from(value)),
+        "volume-type" => ebs_override(config).volume_type = String::from(value),
+        "size" => ebs_override(config).size = value.parse().map_err(|_| invalid("a number"))?,
+        "tag" => {
+            let mut parts = value.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(tag_value)) if !key.is_empty() => {
+                    ebs_override(config).ebs_tags.insert(String::from(key), String::from(tag_value));
+                }
+                _ => return Err(invalid("KEY=VALUE")),
+            }
+        }
+        "mkfs" => file_system_override(config).mkfs = value.split_whitespace().map(String::from).collect(),
+        "fsck-timeout" => fsck_override(config).timeout = value.parse().map_err(|_| invalid("a number"))?,
+        "fsck-on-errors" => {
+            fsck_override(config).on_errors = match value {
+                "abort" => FsckErrorAction::Abort,
+                "read-only" => FsckErrorAction::ReadOnly,
+                "mount" => FsckErrorAction::Mount,
+                _ => return Err(invalid("abort, read-only or mount")),
+            }
+        }
+        "mount-target" => mount_override(config).target = String::from(value),
+        "mount-owner" => mount_override(config).owner = Some(String::from(value)),
+        "mount-group" => mount_override(config).group = Some(String::from(value)),
+        "mount-mode" => mount_override(config).mode = Some(String::from(value)),
+        "mount-context" => mount_override(config).context = Some(String::from(value)),
+        "mount-apply-permissions" => {
+            mount_override(config).apply_permissions = match value {
+                "on-create" => ApplyPermissions::OnCreate,
+                "always" => ApplyPermissions::Always,
+                _ => return Err(invalid("on-create or always")),
+            }
+        }
+        "bind" => {
+            let mut parts = value.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(source), Some(target)) => {
+                    mount_override(config).binds.push(Bind {
+                                                          source: String::from(source),
+                                                          target: String::from(target),
+                                                      })
+                }
+                _ => return Err(invalid("SOURCE:TARGET")),
+            }
+        }
+        "layout" => {
+            config.layout = Some(match (value, config.layout.take()) {
+                                     ("single", _) => Layout::Single,
+                                     ("lvm", Some(Layout::Lvm(lvm))) => Layout::Lvm(lvm),
+                                     ("lvm", _) => {
+                                         Layout::Lvm(LvmLayout {
+                                                         members: 0,
+                                                         volume_group: default_lvm_volume_group(),
+                                                         logical_volume: default_lvm_logical_volume(),
+                                                         stripe_size: None,
+                                                     })
+                                     }
+                                     ("raid", Some(Layout::Raid(raid))) => Layout::Raid(raid),
+                                     ("raid", _) => {
+                                         Layout::Raid(RaidLayout {
+                                                          level: 1,
+                                                          members: 0,
+                                                          device: default_raid_device(),
+                                                          allow_degraded: false,
+                                                      })
+                                     }
+                                     _ => return Err(invalid("single, lvm or raid")),
+                                 })
+        }
+        "layout-members" => {
+            let members = value.parse().map_err(|_| invalid("a number"))?;
+            match config.layout {
+                Some(Layout::Lvm(ref mut lvm)) => lvm.members = members,
+                Some(Layout::Raid(ref mut raid)) => raid.members = members,
+                _ => return Err(requires("an lvm or raid layout")),
+            }
+        }
+        "lvm-volume-group" | "lvm-logical-volume" | "lvm-stripe-size" => {
+            match config.layout {
+                Some(Layout::Lvm(ref mut lvm)) => {
+                    match name {
+                        "lvm-volume-group" => lvm.volume_group = String::from(value),
+                        "lvm-logical-volume" => lvm.logical_volume = String::from(value),
+                        _ => lvm.stripe_size = Some(String::from(value)),
+                    }
+                }
+                _ => return Err(requires("an lvm layout")),
+            }
+        }
+        "raid-level" | "raid-device" | "raid-allow-degraded" => {
+            match config.layout {
+                Some(Layout::Raid(ref mut raid)) => {
+                    match name {
+                        "raid-level" => raid.level = value.parse().map_err(|_| invalid("a number"))?,
+                        "raid-device" => raid.device = String::from(value),
+                        _ => raid.allow_degraded = value.parse().map_err(|_| invalid("true or false"))?,
+                    }
+                }
+                _ => return Err(requires("a raid layout")),
+            }
+        }
+        "encryption-key-file" => encryption_override(config, KeySource::File(String::from(value))),
+        "encryption-key-env" => encryption_override(config, KeySource::Env(String::from(value))),
+        "encryption-kms-ciphertext" => {
+            encryption_override(config,
+                                KeySource::Kms(KmsKeySource {
+                                                   ciphertext: Some(String::from(value)),
+                                                   ciphertext_file: None,
+                                               }))
+        }
+        "encryption-kms-ciphertext-file" => {
+            encryption_override(config,
+                                KeySource::Kms(KmsKeySource {
+                                                   ciphertext: None,
+                                                   ciphertext_file: Some(String::from(value)),
+                                               }))
+        }
+        "encryption-name" => {
+            match config.encryption {
+                Some(ref mut encryption) => encryption.name = String::from(value),
+                None => return Err(requires("an encryption key source")),
+            }
+        }
+        _ => return Err(ConfigError::InvalidOverride(format!("unknown setting: {}", name))),
+    }
+    Ok(())
+}
+
+/// The aws-ebs block provider, created with an invalid size and no tags if it isn't configured, so that the merged
+/// configuration fails validation unless `size` and `tag` are also given.
+fn ebs_override(config: &mut Config) -> &mut EbsBlockProviderConfig {
+    if let Some(BlockProvider::AwsEbs(_)) = config.block_provider {
     } else {
-        Ok(config)
+        config.block_provider = Some(BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                               volume_type: default_ebs_volume_type(),
+                                                               size: -1,
+                                                               ebs_tags: HashMap::new(),
+                                                               selection: None,
+                                                               fingerprint_mismatch: FingerprintMismatch::default(),
+                                                               pool: None,
+                                                               timeouts: EbsTimeouts::default(),
+                                                           }));
+    }
+    match config.block_provider {
+        Some(BlockProvider::AwsEbs(ref mut ebs)) => ebs,
+        _ => unreachable!(),
+    }
+}
+
+fn file_system_override(config: &mut Config) -> &mut FileSystem {
+    if config.file_system.is_none() {
+        config.file_system = Some(default_file_system());
+    }
+    config.file_system.as_mut().unwrap()
+}
+
+fn fsck_override(config: &mut Config) -> &mut Fsck {
+    let file_system = file_system_override(config);
+    if file_system.fsck.is_none() {
+        file_system.fsck = Some(Fsck {
+                                    timeout: default_fsck_timeout(),
+                                    on_errors: default_fsck_on_errors(),
+                                });
+    }
+    file_system.fsck.as_mut().unwrap()
+}
+
+fn mount_override(config: &mut Config) -> &mut Mount {
+    if config.mount.is_none() {
+        config.mount = Some(default_mount());
+    }
+    config.mount.as_mut().unwrap()
+}
+
+/// Replaces the encryption key source, keeping the configured mapping name if there is one.
+fn encryption_override(config: &mut Config, key_source: KeySource) {
+    let name = config.encryption.take().map_or_else(default_encryption_name, |encryption| encryption.name);
+    config.encryption = Some(Encryption {
+                                 name: name,
+                                 key_source: key_source,
+                             });
+}
+
+fn load_config_with_variables(mut config: Config, variables: &mut Variables) -> Result<Config, ConfigError> {
+    let single_volume = normalize_volumes(&mut config)?;
+    for volume in &mut config.volumes {
+        expand_volume_templates(volume, variables)?;
+    }
+    let mut errors = validate_config(&config);
+    if errors.is_empty() {
+        return Ok(config);
+    }
+    if single_volume {
+        // report paths as they're written in the single-volume form
+        for error in &mut errors {
+            if error.path.starts_with("volumes[0].") {
+                error.path = String::from(&error.path["volumes[0].".len()..]);
+            }
+        }
+    }
+    Err(ConfigError::Invalid(errors))
+}
+
+/// Moves the single-volume top-level configuration, if present, into the `volumes` list.  Returns whether it was.
+fn normalize_volumes(config: &mut Config) -> Result<bool, ConfigError> {
+    let has_single_volume = config.block_device.is_some() || config.block_provider.is_some() ||
+                            config.file_system.is_some() || config.mount.is_some() ||
+                            config.layout.is_some() || config.encryption.is_some();
+    if !config.volumes.is_empty() {
+        if has_single_volume {
+            return Err(ConfigError::InvalidVolumes(String::from("block-device, block-provider, file-system, mount, \
+                                                                 layout and encryption must be configured within \
+                                                                 each volume when volumes is used")));
+        }
+        return Ok(false);
+    }
+
+    let block_provider = match config.block_provider.take() {
+        Some(block_provider) => block_provider,
+        None => return Err(ConfigError::InvalidVolumes(String::from("either block-provider or volumes is required"))),
+    };
+    config.volumes.push(Volume {
+                            block_device: config.block_device.take().unwrap_or_else(default_block_device),
+                            block_provider: block_provider,
+                            file_system: config.file_system.take().unwrap_or_else(default_file_system),
+                            mount: config.mount.take().unwrap_or_else(default_mount),
+                            layout: config.layout.take().unwrap_or_else(default_layout),
+                            encryption: config.encryption.take(),
+                        });
+    Ok(true)
+}
+
+/// Expands `${...}` references to instance facts and environment variables in the volume's string settings.
+fn expand_volume_templates(volume: &mut Volume, variables: &mut Variables) -> Result<(), ConfigError> {
+    let mut expand = |value: &mut String| -> Result<(), ConfigError> {
+        let expanded = template::expand(value, variables)
+            .map_err(|e| ConfigError::InvalidTemplate(format!("{}: {}", e, value)))?;
+        *value = expanded;
+        Ok(())
+    };
+
+    expand(&mut volume.block_device)?;
+    if let BlockProvider::AwsEbs(ref mut ebs) = volume.block_provider {
+        expand(&mut ebs.volume_type)?;
+        let mut ebs_tags = HashMap::with_capacity(ebs.ebs_tags.len());
+        for (mut key, mut value) in ebs.ebs_tags.drain() {
+            expand(&mut key)?;
+            expand(&mut value)?;
+            ebs_tags.insert(key, value);
+        }
+        ebs.ebs_tags = ebs_tags;
+        if let Some(VolumeSelection::PreferTag(ref mut preferred)) = ebs.selection {
+            expand(&mut preferred.key)?;
+            expand(&mut preferred.value)?;
+        }
+    }
+    for arg in &mut volume.file_system.mkfs {
+        expand(arg)?;
+    }
+
+    let mount = &mut volume.mount;
+    expand(&mut mount.target)?;
+    for value in vec![&mut mount.owner, &mut mount.group, &mut mount.mode, &mut mount.context] {
+        if let Some(ref mut value) = *value {
+            expand(value)?;
+        }
+    }
+    for bind in &mut mount.binds {
+        expand(&mut bind.source)?;
+        expand(&mut bind.target)?;
+    }
+
+    match volume.layout {
+        Layout::Single => {}
+        Layout::Lvm(ref mut lvm) => {
+            expand(&mut lvm.volume_group)?;
+            expand(&mut lvm.logical_volume)?;
+            if let Some(ref mut stripe_size) = lvm.stripe_size {
+                expand(stripe_size)?;
+            }
+        }
+        Layout::Raid(ref mut raid) => expand(&mut raid.device)?,
+    }
+
+    if let Some(ref mut encryption) = volume.encryption {
+        expand(&mut encryption.name)?;
+        match encryption.key_source {
+            KeySource::File(ref mut path) => expand(path)?,
+            KeySource::Env(ref mut name) => expand(name)?,
+            KeySource::Kms(ref mut kms) => {
+                for value in vec![&mut kms.ciphertext, &mut kms.ciphertext_file] {
+                    if let Some(ref mut value) = *value {
+                        expand(value)?;
+                    }
+                }
+            }
+        }
     }
+    Ok(())
 }
 
-pub fn validate_config(config: &Config) -> Option<ConfigError> {
-    validate_block_provider(&config.block_provider)
-        .or_else(|| validate_file_system(&config.file_system))
-        .or_else(|| validate_mount(&config.mount))
-}
+/// A problem found by `validate_config`.
+#[derive(Debug)]
+pub struct ValidationError {
+    /// Path of the setting, eg. `block-provider.aws-ebs.size`, or `volumes[1].mount.target`.
+    pub path: String,
+    pub error: ConfigError,
+    /// Line and column (both starting at 1) of the setting in the configuration file, where known.
+    pub location: Option<(usize, usize)>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some((line, column)) => write!(f, "{} (line {}, column {}): {}", self.path, line, column, self.error),
+            None => write!(f, "{}: {}", self.path, self.error),
+        }
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Records a validation error for the setting at `path`.
+fn invalid(errors: &mut Vec<ValidationError>, path: String, error: ConfigError) {
+    errors.push(ValidationError {
+                    path: path,
+                    error: error,
+                    location: None,
+                });
+}
+
+/// Checks every volume, returning all of the problems found rather than only the first.
+pub fn validate_config(config: &Config) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    for (i, volume) in config.volumes.iter().enumerate() {
+        validate_volume(volume, &format!("volumes[{}]", i), &mut errors);
+    }
+    validate_volumes_distinct(&config.volumes, &mut errors);
+    errors
+}
+
+fn validate_volume(volume: &Volume, path: &str, errors: &mut Vec<ValidationError>) {
+    validate_block_device(&volume.block_device, &child_path(path, "block-device"), errors);
+    validate_block_provider(&volume.block_provider, &child_path(path, "block-provider"), errors);
+    validate_file_system(&volume.file_system, &child_path(path, "file-system"), errors);
+    validate_mount(&volume.mount, &child_path(path, "mount"), errors);
+    validate_layout(volume, path, errors);
+    validate_pool_snapshot(volume, path, errors);
+    if let Some(ref encryption) = volume.encryption {
+        validate_encryption(encryption, &child_path(path, "encryption"), errors);
+    }
+}
+
+fn validate_block_device(block_device: &str, path: &str, errors: &mut Vec<ValidationError>) {
+    if block_device != AUTO_BLOCK_DEVICE && !block_device.starts_with("/dev/") || block_device.len() == "/dev/".len() {
+        invalid(errors,
+                String::from(path),
+                ConfigError::InvalidVolumes(String::from("block-device must be auto or a device under /dev: ") +
+                                            block_device));
+    } else if !is_valid_block_device(block_device) {
+        invalid(errors,
+                String::from(path),
+                ConfigError::InvalidVolumes(String::from("block-device must end in a drive letter, eg. /dev/xvdf: ") +
+                                            block_device));
+    }
+}
+
+/// Whether `block_device` is auto, or a device under /dev ending in the drive letter that the following devices of a
+/// volume set, or of a single volume whose device is in use, are counted from.
+fn is_valid_block_device(block_device: &str) -> bool {
+    block_device == AUTO_BLOCK_DEVICE ||
+    block_device.starts_with("/dev/") && block_device.len() > "/dev/".len() &&
+    block_device.chars().last().map_or(false, |c| c >= 'a' && c <= 'z')
+}
+
+fn validate_encryption(config: &Encryption, path: &str, errors: &mut Vec<ValidationError>) {
+    if config.name.is_empty() || config.name.contains('/') {
+        invalid(errors,
+                child_path(path, "name"),
+                ConfigError::InvalidEncryption(String::from("invalid dm-crypt mapping name: ") + &config.name));
+    }
+    let key_source_path = child_path(path, "key-source");
+    match config.key_source {
+        KeySource::File(ref file) if file.is_empty() => {
+            invalid(errors,
+                    child_path(&key_source_path, "file"),
+                    ConfigError::InvalidEncryption(String::from("key-source file must not be empty")))
+        }
+        KeySource::Env(ref name) if name.is_empty() => {
+            invalid(errors,
+                    child_path(&key_source_path, "env"),
+                    ConfigError::InvalidEncryption(String::from("key-source env must not be empty")))
+        }
+        KeySource::Kms(ref kms) if kms.ciphertext.is_some() == kms.ciphertext_file.is_some() => {
+            invalid(errors,
+                    child_path(&key_source_path, "kms"),
+                    ConfigError::InvalidEncryption(String::from("key-source kms requires exactly one of ciphertext \
+                                                                 or ciphertext-file")))
+        }
+        _ => {}
+    }
+}
+
+/// A snapshot holds a single volume, so pooled volumes can only be created from one for the single layout.
+fn validate_pool_snapshot(volume: &Volume, path: &str, errors: &mut Vec<ValidationError>) {
+    if let BlockProvider::AwsEbs(ref ebs) = volume.block_provider {
+        let snapshot_id = ebs.pool.as_ref().and_then(|pool| pool.snapshot_id.as_ref());
+        if snapshot_id.is_some() && volume.members() > 1 {
+            invalid(errors,
+                    child_path(&child_path(&child_path(&child_path(path, "block-provider"), "aws-ebs"), "pool"),
+                               "snapshot-id"),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("pool snapshot-id can only be used with \
+                                                                          the single layout")));
+        }
+    }
+}
+
+fn validate_layout(volume: &Volume, path: &str, errors: &mut Vec<ValidationError>) {
+    let path = child_path(path, "layout");
+    match volume.layout {
+        Layout::Single => {}
+        Layout::Lvm(ref lvm) => {
+            let path = child_path(&path, "lvm");
+            if lvm.members < 1 {
+                invalid(errors,
+                        child_path(&path, "members"),
+                        ConfigError::InvalidLayout(String::from("lvm members must be at least 1")));
+                return;
+            }
+            validate_layout_member_block_devices(volume, &path, errors);
+            if !is_valid_lvm_name(&lvm.volume_group) {
+                invalid(errors,
+                        child_path(&path, "volume-group"),
+                        ConfigError::InvalidLayout(String::from("invalid lvm volume-group name: ") +
+                                                   &lvm.volume_group));
+            }
+            if !is_valid_lvm_name(&lvm.logical_volume) {
+                invalid(errors,
+                        child_path(&path, "logical-volume"),
+                        ConfigError::InvalidLayout(String::from("invalid lvm logical-volume name: ") +
+                                                   &lvm.logical_volume));
+            }
+        }
+        Layout::Raid(ref raid) => {
+            let path = child_path(&path, "raid");
+            let min_members = match raid.level {
+                0 | 1 => 2,
+                10 => 4,
+                level => {
+                    invalid(errors,
+                            child_path(&path, "level"),
+                            ConfigError::InvalidLayout(format!("invalid raid level, expected 0, 1, 10: {}", level)));
+                    return;
+                }
+            };
+            if raid.members < min_members {
+                invalid(errors,
+                        child_path(&path, "members"),
+                        ConfigError::InvalidLayout(format!("raid level {} requires at least {} members",
+                                                           raid.level,
+                                                           min_members)));
+                return;
+            }
+            if raid.level == 0 && raid.allow_degraded {
+                invalid(errors,
+                        child_path(&path, "allow-degraded"),
+                        ConfigError::InvalidLayout(String::from("raid level 0 cannot run degraded")));
+            }
+            if !raid.device.starts_with("/dev/md") {
+                invalid(errors,
+                        child_path(&path, "device"),
+                        ConfigError::InvalidLayout(String::from("raid device must be an md device: ") +
+                                                   &raid.device));
+            }
+            validate_layout_member_block_devices(volume, &path, errors);
+        }
+    }
+}
+
+fn validate_layout_member_block_devices(volume: &Volume, path: &str, errors: &mut Vec<ValidationError>) {
+    if layout_members_fit(volume) {
+        return;
+    }
+    if volume.auto_block_device() {
+        invalid(errors,
+                child_path(path, "members"),
+                ConfigError::InvalidLayout(format!("block-device auto can choose at most {} layout members",
+                                                   block_devices::auto_block_devices().len())));
+    } else {
+        invalid(errors,
+                child_path(path, "members"),
+                ConfigError::InvalidLayout(String::from("block-device must end in a letter with room for all layout \
+                                                         members: ") + &volume.block_device));
+    }
+}
+
+/// Whether there are block devices for all of the volume's layout members: no more than `block-device: auto` can
+/// choose, or consecutive drive letters from `block-device`'s up to z.
+fn layout_members_fit(volume: &Volume) -> bool {
+    let members = volume.members();
+    if volume.auto_block_device() {
+        return members <= block_devices::auto_block_devices().len();
+    }
+    match volume.block_device.bytes().last() {
+        Some(last) if last >= b'a' && last <= b'z' => {
+            (last as usize).checked_add(members).map_or(false, |end| end <= b'z' as usize + 1)
+        }
+        _ => false,
+    }
+}
+
+fn is_valid_lvm_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('-') &&
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '_' || c == '.' || c == '-')
+}
+
+fn validate_volumes_distinct(volumes: &[Volume], errors: &mut Vec<ValidationError>) {
+    for (i, volume) in volumes.iter().enumerate() {
+        let path = format!("volumes[{}]", i);
+        for other in &volumes[..i] {
+            // invalid block devices, and layouts with too many members, have already been reported, and can't be
+            // expanded into member devices
+            if !is_valid_block_device(&volume.block_device) || !is_valid_block_device(&other.block_device) ||
+               !layout_members_fit(volume) || !layout_members_fit(other) {
+                continue;
+            }
+            let other_devices = other.member_block_devices();
+            if let Some(device) = volume.member_block_devices().into_iter().find(|d| other_devices.contains(d)) {
+                invalid(errors,
+                        child_path(&path, "block-device"),
+                        ConfigError::InvalidVolumes(String::from("block-device used by more than one volume: ") +
+                                                    &device));
+            }
+            if volume.mount.target == other.mount.target {
+                invalid(errors,
+                        child_path(&path, "mount.target"),
+                        ConfigError::InvalidVolumes(String::from("mount target used by more than one volume: ") +
+                                                    &volume.mount.target));
+            }
+            if let (&Layout::Lvm(ref a), &Layout::Lvm(ref b)) = (&volume.layout, &other.layout) {
+                if a.volume_group == b.volume_group {
+                    invalid(errors,
+                            child_path(&path, "layout.lvm.volume-group"),
+                            ConfigError::InvalidVolumes(String::from("volume group used by more than one volume: ") +
+                                                        &a.volume_group));
+                }
+            }
+            if let (&Layout::Raid(ref a), &Layout::Raid(ref b)) = (&volume.layout, &other.layout) {
+                if a.device == b.device {
+                    invalid(errors,
+                            child_path(&path, "layout.raid.device"),
+                            ConfigError::InvalidVolumes(String::from("raid device used by more than one volume: ") +
+                                                        &a.device));
+                }
+            }
+            if let (&Some(ref a), &Some(ref b)) = (&volume.encryption, &other.encryption) {
+                if a.name == b.name {
+                    invalid(errors,
+                            child_path(&path, "encryption.name"),
+                            ConfigError::InvalidVolumes(String::from("encryption name used by more than one volume: ") +
+                                                        &a.name));
+                }
+            }
+            if let (&BlockProvider::AwsEbs(ref a), &BlockProvider::AwsEbs(ref b)) =
+                (&volume.block_provider, &other.block_provider) {
+                // volumes are found by all of their tags, so one volume's tags being a subset of another's would let
+                // it claim the other's volumes
+                if is_subset(&a.ebs_tags, &b.ebs_tags) || is_subset(&b.ebs_tags, &a.ebs_tags) {
+                    invalid(errors,
+                            child_path(&path, "block-provider.aws-ebs.ebs-tags"),
+                            ConfigError::InvalidVolumes(String::from("ebs-tags must be distinct for each volume, and \
+                                                                      not a subset of another volume's")));
+                }
+            }
+        }
+    }
+}
+
+fn is_subset(a: &HashMap<String, String>, b: &HashMap<String, String>) -> bool {
+    a.iter().all(|(key, value)| b.get(key) == Some(value))
+}
+
+fn validate_block_provider(block_provider: &BlockProvider, path: &str, errors: &mut Vec<ValidationError>) {
+    match *block_provider {
+        BlockProvider::AwsEbs(ref ebs_block_provider_config) => {
+            validate_block_provider_aws_ebs_config(ebs_block_provider_config, &child_path(path, "aws-ebs"), errors)
+        }
+        BlockProvider::ReservedForFuture => panic!("huh"),
+    }
+}
+
+fn validate_block_provider_aws_ebs_config(config: &EbsBlockProviderConfig,
+                                          path: &str,
+                                          errors: &mut Vec<ValidationError>) {
+    match ebs_volume_size_range(&config.volume_type) {
+        None => {
+            invalid(errors,
+                    child_path(path, "type"),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("invalid volume type, expected gp2, io1, \
+                                                                          st1, sc1: ") +
+                                                            &config.volume_type))
+        }
+        Some(_) if config.size < 0 => {
+            invalid(errors,
+                    child_path(path, "size"),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("invalid volume size")))
+        }
+        Some((min, max)) if config.size < min || config.size > max => {
+            invalid(errors,
+                    child_path(path, "size"),
+                    ConfigError::InvalidBlockProviderAwsEbs(format!("{} volumes must be between {} and {} GiB: {}",
+                                                                    config.volume_type,
+                                                                    min,
+                                                                    max,
+                                                                    config.size)))
+        }
+        Some(_) => {}
+    }
+
+    let tags_path = child_path(path, "ebs-tags");
+    if config.ebs_tags.is_empty() {
+        invalid(errors,
+                tags_path.clone(),
+                ConfigError::InvalidBlockProviderAwsEbs(String::from("at least one ebs tag is required")));
+    }
+    let mut keys: Vec<&String> = config.ebs_tags.keys().collect();
+    keys.sort();
+    for key in keys {
+        let tag_path = child_path(&tags_path, key);
+        if key.is_empty() || key.chars().count() > 128 {
+            invalid(errors,
+                    tag_path.clone(),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("ebs tag keys must be 1 to 128 characters")));
+        }
+        if key.to_lowercase().starts_with("aws:") {
+            invalid(errors,
+                    tag_path.clone(),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("ebs tag keys must not start with aws:, \
+                                                                          which is reserved by AWS: ") +
+                                                            key));
+        }
+        if config.ebs_tags[key].chars().count() > 256 {
+            invalid(errors,
+                    tag_path,
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("ebs tag values must be at most 256 \
+                                                                          characters")));
+        }
+    }
+
+    if let Some(ref pool) = config.pool {
+        let pool_path = child_path(path, "pool");
+        if pool.size < 1 {
+            invalid(errors,
+                    child_path(&pool_path, "size"),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("pool size must be at least 1")));
+        }
+        if pool.availability_zones.iter().any(|zone| zone.is_empty()) {
+            invalid(errors,
+                    child_path(&pool_path, "availability-zones"),
+                    ConfigError::InvalidBlockProviderAwsEbs(String::from("pool availability zones must not be \
+                                                                          empty")));
+        }
+        if let Some(ref snapshot_id) = pool.snapshot_id {
+            if !snapshot_id.starts_with("snap-") {
+                invalid(errors,
+                        child_path(&pool_path, "snapshot-id"),
+                        ConfigError::InvalidBlockProviderAwsEbs(String::from("invalid pool snapshot-id, expected \
+                                                                              snap-...: ") +
+                                                                snapshot_id));
+            }
+        }
+    }
+
+    let timeouts_path = child_path(path, "timeouts");
+    validate_polling(&config.timeouts.volume_available,
+                     &child_path(&timeouts_path, "volume-available"),
+                     errors);
+    validate_polling(&config.timeouts.volume_attached,
+                     &child_path(&timeouts_path, "volume-attached"),
+                     errors);
+    validate_polling(&config.timeouts.volume_detached,
+                     &child_path(&timeouts_path, "volume-detached"),
+                     errors);
+}
+
+fn validate_polling(config: &Polling, path: &str, errors: &mut Vec<ValidationError>) {
+    if config.timeout == 0 {
+        invalid(errors,
+                child_path(path, "timeout"),
+                ConfigError::InvalidBlockProviderAwsEbs(String::from("timeout must be at least 1 second")));
+    }
+    if config.initial_interval == 0 {
+        invalid(errors,
+                child_path(path, "initial-interval"),
+                ConfigError::InvalidBlockProviderAwsEbs(String::from("initial-interval must be at least 1 second")));
+    } else if config.max_interval < config.initial_interval {
+        invalid(errors,
+                child_path(path, "max-interval"),
+                ConfigError::InvalidBlockProviderAwsEbs(String::from("max-interval must be at least \
+                                                                      initial-interval")));
+    }
+}
+
+/// Minimum and maximum sizes, in GiB, of each EBS volume type; `None` for an unknown type.
+fn ebs_volume_size_range(volume_type: &str) -> Option<(i32, i32)> {
+    match volume_type {
+        "gp2" => Some((1, 16384)),
+        "io1" => Some((4, 16384)),
+        "st1" | "sc1" => Some((500, 16384)),
+        _ => None,
+    }
+}
+
+fn validate_file_system(config: &FileSystem, path: &str, errors: &mut Vec<ValidationError>) {
+    if config.mkfs.is_empty() {
+        invalid(errors,
+                child_path(path, "mkfs"),
+                ConfigError::InvalidFileSystem(String::from("required parameter mkfs")));
+    }
+    if config.fsck.as_ref().map_or(false, |fsck| fsck.timeout == 0) {
+        invalid(errors,
+                child_path(path, "fsck.timeout"),
+                ConfigError::InvalidFileSystem(String::from("fsck timeout must be at least 1 second")));
+    }
+}
+
+fn validate_mount(config: &Mount, path: &str, errors: &mut Vec<ValidationError>) {
+    if config.target.is_empty() {
+        invalid(errors,
+                child_path(path, "target"),
+                ConfigError::InvalidMount(String::from("required parameter target")));
+    } else if !std::path::Path::new(&config.target).is_absolute() {
+        invalid(errors,
+                child_path(path, "target"),
+                ConfigError::InvalidMount(String::from("target must be an absolute path: ") + &config.target));
+    }
+    if config.owner.as_ref().map_or(false, |owner| owner.is_empty()) {
+        invalid(errors,
+                child_path(path, "owner"),
+                ConfigError::InvalidMount(String::from("owner must not be empty")));
+    }
+    if config.group.as_ref().map_or(false, |group| group.is_empty()) {
+        invalid(errors,
+                child_path(path, "group"),
+                ConfigError::InvalidMount(String::from("group must not be empty")));
+    }
+    if config.context.as_ref().map_or(false, |context| context.is_empty()) {
+        invalid(errors,
+                child_path(path, "context"),
+                ConfigError::InvalidMount(String::from("context must not be empty")));
+    }
+    match config.mode_bits() {
+        Some(Ok(bits)) if bits > 0o7777 => {
+            invalid(errors,
+                    child_path(path, "mode"),
+                    ConfigError::InvalidMount(String::from("mode out of range, expected octal 0000-7777: ") +
+                                              config.mode.as_ref().unwrap()));
+        }
+        Some(Err(_)) => {
+            invalid(errors,
+                    child_path(path, "mode"),
+                    ConfigError::InvalidMount(String::from("invalid mode, expected octal permissions: ") +
+                                              config.mode.as_ref().unwrap()));
+        }
+        _ => {}
+    }
+    for (i, bind) in config.binds.iter().enumerate() {
+        validate_bind(bind, &format!("{}[{}]", child_path(path, "binds"), i), errors);
+    }
+}
+
+fn validate_bind(bind: &Bind, path: &str, errors: &mut Vec<ValidationError>) {
+    let source = std::path::Path::new(&bind.source);
+    if bind.source.is_empty() || source.is_absolute() {
+        invalid(errors,
+                child_path(path, "source"),
+                ConfigError::InvalidMount(String::from("bind source must be a path relative to the mount target: ") +
+                                          &bind.source));
+    } else if source.components().any(|c| c == std::path::Component::ParentDir) {
+        invalid(errors,
+                child_path(path, "source"),
+                ConfigError::InvalidMount(String::from("bind source must not leave the mount target: ") +
+                                          &bind.source));
+    }
+    if !std::path::Path::new(&bind.target).is_absolute() {
+        invalid(errors,
+                child_path(path, "target"),
+                ConfigError::InvalidMount(String::from("bind target must be an absolute path: ") + &bind.target));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::InstanceVariables;
+
+    fn load_config(config: Config) -> Result<Config, ConfigError> {
+        load_config_with_variables(config, &mut InstanceVariables::new())
+    }
+
+    fn parse_config(config_str: &str) -> Result<Config, ConfigError> {
+        load_config(serde_yaml::from_str(config_str)?)
+    }
+
+    fn invalid_errors(result: Result<Config, ConfigError>) -> Vec<ValidationError> {
+        match result {
+            Err(ConfigError::Invalid(errors)) => errors,
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+
+    fn single_error(mut errors: Vec<ValidationError>) -> ValidationError {
+        assert_eq!(1, errors.len(), "expected a single validation error: {:?}", errors);
+        errors.remove(0)
+    }
+
+    fn single_volume_config(volume: Volume) -> Config {
+        Config {
+            block_device: None,
+            block_provider: None,
+            file_system: None,
+            mount: None,
+            layout: None,
+            encryption: None,
+            volumes: vec![volume],
+        }
+    }
+
+    #[test]
+    fn validate_block_provider_aws_ebs_volume_type() {
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: HashMap::new(),
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 200,
+                                                      volume_type: String::from("grr-arg"),
+                                                  }),
+            file_system: default_file_system(),
+            mount: default_mount(),
+            layout: default_layout(),
+            encryption: None,
+        });
+        let errors = validate_config(&config);
+        assert_eq!(2, errors.len());
+        assert_eq!("volumes[0].block-provider.aws-ebs.type", errors[0].path);
+        assert_eq!("invalid configuration in block-provider aws-ebs",
+                   errors[0].error.description());
+        assert_eq!("invalid volume type, expected gp2, io1, st1, sc1: grr-arg",
+                   format!("{}", errors[0].error));
+        assert_eq!("volumes[0].block-provider.aws-ebs.ebs-tags: at least one ebs tag is required",
+                   format!("{}", errors[1]));
+    }
+
+    #[test]
+    fn validate_block_provider_aws_ebs_size() {
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: HashMap::new(),
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: -100,
+                                                      volume_type: String::from("gp2"),
+                                                  }),
+            file_system: default_file_system(),
+            mount: default_mount(),
+            layout: default_layout(),
+            encryption: None,
+        });
+        let errors = validate_config(&config);
+        assert_eq!(2, errors.len());
+        assert_eq!("volumes[0].block-provider.aws-ebs.size", errors[0].path);
+        assert_eq!("invalid configuration in block-provider aws-ebs",
+                   errors[0].error.description());
+        assert_eq!("invalid volume size", format!("{}", errors[0].error));
+        assert_eq!("volumes[0].block-provider.aws-ebs.ebs-tags", errors[1].path);
+    }
+
+    #[test]
+    fn validate_block_provider_aws_ebs_tags() {
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: HashMap::new(),
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 200,
+                                                      volume_type: String::from("gp2"),
+                                                  }),
+            file_system: default_file_system(),
+            mount: default_mount(),
+            layout: default_layout(),
+            encryption: None,
+        });
+        let err = single_error(validate_config(&config));
+        assert_eq!("invalid configuration in block-provider aws-ebs",
+                   err.error.description());
+        assert_eq!("at least one ebs tag is required", format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_file_system_mkfs() {
+        let mut ebs_tags: HashMap<String, String> = HashMap::new();
+        ebs_tags.insert(String::from("a"), String::from("b"));
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: ebs_tags,
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 200,
+                                                      volume_type: String::from("gp2"),
+                                                  }),
+            file_system: FileSystem {
+                mkfs: Vec::new(),
+                fsck: None,
+            },
+            mount: default_mount(),
+            layout: default_layout(),
+            encryption: None,
+        });
+        let err = single_error(validate_config(&config));
+        assert_eq!("invalid configuration in file-system", err.error.description());
+        assert_eq!("required parameter mkfs", format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_mount_target() {
+        let mut ebs_tags: HashMap<String, String> = HashMap::new();
+        ebs_tags.insert(String::from("a"), String::from("b"));
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: ebs_tags,
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 200,
+                                                      volume_type: String::from("gp2"),
+                                                  }),
+            file_system: default_file_system(),
+            mount: Mount { target: String::from(""), ..default_mount() },
+            layout: default_layout(),
+            encryption: None,
+        });
+        let err = single_error(validate_config(&config));
+        assert_eq!("invalid configuration in mount", err.error.description());
+        assert_eq!("required parameter target", format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_mount_mode() {
+        let mut ebs_tags: HashMap<String, String> = HashMap::new();
+        ebs_tags.insert(String::from("a"), String::from("b"));
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: ebs_tags,
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 200,
+                                                      volume_type: String::from("gp2"),
+                                                  }),
+            file_system: default_file_system(),
+            mount: Mount { mode: Some(String::from("0789")), ..default_mount() },
+            layout: default_layout(),
+            encryption: None,
+        });
+        let err = single_error(validate_config(&config));
+        assert_eq!("invalid configuration in mount", err.error.description());
+        assert_eq!("invalid mode, expected octal permissions: 0789",
+                   format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_mount_bind_source() {
+        let mut ebs_tags: HashMap<String, String> = HashMap::new();
+        ebs_tags.insert(String::from("a"), String::from("b"));
+        let config = single_volume_config(Volume {
+            block_device: default_block_device(),
+            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
+                                                      ebs_tags: ebs_tags,
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 200,
+                                                      volume_type: String::from("gp2"),
+                                                  }),
+            file_system: default_file_system(),
+            mount: Mount {
+                binds: vec![Bind {
+                                source: String::from("data/../../etc"),
+                                target: String::from("/var/lib/postgresql"),
+                            }],
+                ..default_mount()
+            },
+            layout: default_layout(),
+            encryption: None,
+        });
+        let err = single_error(validate_config(&config));
+        assert_eq!("invalid configuration in mount", err.error.description());
+        assert_eq!("bind source must not leave the mount target: data/../../etc",
+                   format!("{}", err.error));
+    }
+
+    const EXAMPLE_MINIMAL_EBS_CONFIG: &'static str = r#"
+block-provider:
+  aws-ebs:
+    ebs-tags: { a: "b" }
+    size: 200
+"#;
+
+    const EXAMPLE_FULL_EBS_CONFIG: &'static str = r#"
+block-device: /dev/xvdf
+
+block-provider:
+  aws-ebs:
+    ebs-tags:
+      tag-a: value-a
+    type: gp2
+    size: 200
+
+file-system:
+  mkfs:
+    - -t
+    - ext4
+    - -m
+    - 5
+  fsck:
+    timeout: 600
+    on-errors: read-only
+
+mount:
+  target: /mnt/test
+  owner: postgres
+  group: postgres
+  mode: 0700
+  context: system_u:object_r:postgresql_db_t:s0
+  apply-permissions: always
+  binds:
+    - source: data/pg
+      target: /var/lib/postgresql
+    - source: logs
+      target: /var/log/app
+"#;
+
+    fn apply_overrides(mut config: Config, overrides: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        for &(name, value) in overrides {
+            apply_override(&mut config, name, value)?;
+        }
+        load_config(config)
+    }
+
+    #[test]
+    fn overrides_apply_on_top_of_config() {
+        let config = serde_yaml::from_str(EXAMPLE_FULL_EBS_CONFIG).unwrap();
+        let config = apply_overrides(config,
+                                     &[("size", "500"),
+                                       ("tag", "tag-b=value-b"),
+                                       ("mount-target", "/data"),
+                                       ("bind", "cache:/var/cache/app")])
+            .unwrap();
+        let volume = &config.volumes[0];
+        match volume.block_provider {
+            BlockProvider::AwsEbs(ref ebs) => {
+                assert_eq!(500, ebs.size);
+                assert_eq!("gp2", ebs.volume_type);
+                assert_eq!(2, ebs.ebs_tags.len());
+                assert_eq!("value-b", ebs.ebs_tags["tag-b"]);
+            }
+            _ => assert!(false, "expected aws-ebs block provider"),
+        }
+        assert_eq!("/data", volume.mount.target);
+        assert_eq!(Some(String::from("postgres")), volume.mount.owner);
+        assert_eq!(3, volume.mount.binds.len());
+        assert_eq!("/var/cache/app", volume.mount.binds[2].target);
+    }
+
+    #[test]
+    fn overrides_without_config_file() {
+        let config = apply_overrides(Config::default(),
+                                     &[("size", "100"),
+                                       ("tag", "Role=PostgreSQL"),
+                                       ("layout", "lvm"),
+                                       ("layout-members", "2"),
+                                       ("encryption-key-env", "VOLUME_KEY"),
+                                       ("encryption-name", "pg-crypt")])
+            .unwrap();
+        let volume = &config.volumes[0];
+        assert_eq!("/dev/xvdc", volume.block_device);
+        assert_eq!(vec![String::from("/dev/xvdc"), String::from("/dev/xvdd")],
+                   volume.member_block_devices());
+        assert_eq!("/dev/mapper/pg-crypt", volume.filesystem_device());
+    }
+
+    #[test]
+    fn overrides_validated_after_merge() {
+        let err = apply_overrides(Config::default(), &[("tag", "Role=PostgreSQL")]).unwrap_err();
+        assert_eq!("block-provider.aws-ebs.size: invalid volume size", format!("{}", err));
+
+        let err = apply_overrides(Config::default(), &[("layout-members", "2")]).unwrap_err();
+        assert_eq!("invalid configuration override", err.description());
+        assert_eq!("layout-members requires an lvm or raid layout", format!("{}", err));
+
+        let err = apply_overrides(Config::default(), &[("size", "big")]).unwrap_err();
+        assert_eq!("invalid value for size, expected a number: big", format!("{}", err));
+    }
+
+    struct TestVariables {}
+
+    impl Variables for TestVariables {
+        fn lookup(&mut self, name: &str) -> Result<String, template::TemplateError> {
+            match name {
+                "instance.az" => Ok(String::from("us-west-2c")),
+                "instance.tag.Role" => Ok(String::from("postgres")),
+                _ => Err(template::TemplateError::UnknownVariable(String::from(name))),
+            }
+        }
+    }
+
+    #[test]
+    fn expands_variable_references() {
+        let config = load_config_with_variables(serde_yaml::from_str(r#"
+block-provider:
+  aws-ebs:
+    size: 100
+    ebs-tags:
+      Role: ${instance.tag.Role}
+      ${instance.tag.Role}-az: ${instance.az}
+mount:
+  target: /data/${instance.tag.Role}
+  owner: ${instance.tag.Role}
+encryption:
+  key-source:
+    file: /etc/cps/${instance.az}.key
+"#)
+                                                    .unwrap(),
+                                                &mut TestVariables {})
+            .unwrap();
+        let volume = &config.volumes[0];
+        match volume.block_provider {
+            BlockProvider::AwsEbs(ref ebs) => {
+                assert_eq!(Some(&String::from("postgres")), ebs.ebs_tags.get("Role"));
+                assert_eq!(Some(&String::from("us-west-2c")), ebs.ebs_tags.get("postgres-az"));
+            }
+            _ => panic!("expected aws-ebs block provider"),
+        }
+        assert_eq!("/data/postgres", volume.mount.target);
+        assert_eq!(Some(String::from("postgres")), volume.mount.owner);
+        match volume.encryption.as_ref().unwrap().key_source {
+            KeySource::File(ref path) => assert_eq!("/etc/cps/us-west-2c.key", path),
+            _ => panic!("expected key-source file"),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_variable_references() {
+        let err = load_config_with_variables(serde_yaml::from_str(r#"
+block-provider:
+  aws-ebs:
+    size: 100
+    ebs-tags:
+      Role: ${instance.role}
+"#)
+                                                 .unwrap(),
+                                             &mut TestVariables {})
+            .unwrap_err();
+        assert_eq!("invalid variable reference in configuration", err.description());
+        assert_eq!("unknown variable: instance.role: ${instance.role}", format!("{}", err));
+    }
+
+    #[test]
+    fn settings_from_tags() {
+        let mut settings = HashMap::new();
+        settings.insert(String::from("layout-members"), String::from("2"));
+        settings.insert(String::from("layout"), String::from("lvm"));
+        settings.insert(String::from("tag"), String::from("Role=PostgreSQL,Environment=Production"));
+        let overrides = settings_overrides(&settings).unwrap();
+        assert_eq!(vec![("tag", vec![String::from("Role=PostgreSQL"), String::from("Environment=Production")]),
+                        ("layout", vec![String::from("lvm")]),
+                        ("layout-members", vec![String::from("2")])],
+                   overrides);
+
+        settings.insert(String::from("tag"), String::from(r"Owners=alice\,bob,Path=C:\\,Tier=2"));
+        assert_eq!(("tag", vec![String::from("Owners=alice,bob"), String::from(r"Path=C:\"), String::from("Tier=2")]),
+                   settings_overrides(&settings).unwrap()[0]);
+
+        settings.insert(String::from("colour"), String::from("blue"));
+        let err = settings_overrides(&settings).unwrap_err();
+        assert_eq!("unknown setting: colour", format!("{}", err));
+    }
+
+    #[test]
+    fn parses_json_config() {
+        let config = load_config(parse_config_document(r#"{
+  "block-provider": {"aws-ebs": {"size": 100, "ebs-tags": {"Role": "PostgreSQL"}}},
+  "mount": {"target": "/data", "mode": "0750"},
+  "layout": {"lvm": {"members": 2}}
+}"#,
+                                                       ConfigFormat::Json)
+                                         .unwrap())
+            .unwrap();
+        let volume = &config.volumes[0];
+        assert_eq!("/data", volume.mount.target);
+        assert_eq!(Some(Ok(0o750)), volume.mount.mode_bits());
+        assert_eq!("/dev/cps/data", volume.layout_device());
+    }
+
+    #[test]
+    fn parses_toml_config() {
+        let config = load_config(parse_config_document(r#"
+block-device = "/dev/xvdf"
+
+[block-provider.aws-ebs]
+size = 100
+type = "io1"
+
+[block-provider.aws-ebs.ebs-tags]
+Role = "PostgreSQL"
 
-fn validate_block_provider(block_provider: &BlockProvider) -> Option<ConfigError> {
-    match *block_provider {
-        BlockProvider::AwsEbs(ref ebs_block_provider_config) => {
-            validate_block_provider_aws_ebs_config(ebs_block_provider_config)
-        }
-        BlockProvider::ReservedForFuture => panic!("huh"),
-    }
-}
+[file-system]
+mkfs = ["-t", "xfs"]
 
-fn validate_block_provider_aws_ebs_config(config: &EbsBlockProviderConfig) -> Option<ConfigError> {
-    validate_block_provider_aws_ebs_volume_type(&config.volume_type)
-    .or_else(|| if config.size < 0 { Some(ConfigError::InvalidBlockProviderAwsEbs(String::from("invalid volume size"))) } else { None })
-    .or_else(|| if config.ebs_tags.is_empty() { Some(ConfigError::InvalidBlockProviderAwsEbs(String::from("at least one ebs tag is required"))) } else { None })
-}
+[file-system.fsck]
+on-errors = "read-only"
 
-fn validate_block_provider_aws_ebs_volume_type(volume_type: &str) -> Option<ConfigError> {
-    match volume_type {
-        "gp2" | "io1" | "st1" | "sc1" => None,
-        vt => {
-            Some(ConfigError::InvalidBlockProviderAwsEbs(String::from("invalid volume type, expected gp2, io1, st1, sc1: ") +
-                                                         vt))
+[mount]
+target = "/data"
+"#,
+                                                       ConfigFormat::Toml)
+                                         .unwrap())
+            .unwrap();
+        let volume = &config.volumes[0];
+        assert_eq!("/dev/xvdf", volume.block_device);
+        match volume.block_provider {
+            BlockProvider::AwsEbs(ref ebs) => {
+                assert_eq!(100, ebs.size);
+                assert_eq!("io1", ebs.volume_type);
+            }
+            _ => panic!("expected aws-ebs block provider"),
         }
+        assert_eq!(vec!["-t", "xfs"], volume.file_system.mkfs);
+        assert_eq!(FsckErrorAction::ReadOnly, volume.file_system.fsck.as_ref().unwrap().on_errors);
+        assert_eq!("/data", volume.mount.target);
     }
-}
 
-fn validate_file_system(config: &FileSystem) -> Option<ConfigError> {
-    if config.mkfs.is_empty() {
-        Some(ConfigError::InvalidFileSystem(String::from("required parameter mkfs")))
-    } else {
-        None
+    #[test]
+    fn reports_toml_errors() {
+        let err = parse_config_document("[mount]\ntarget = \n", ConfigFormat::Toml).unwrap_err();
+        assert_eq!("invalid TOML configuration", err.description());
+        assert!(format!("{}", err).starts_with("line 2 column"));
+
+        let err = parse_config_document("[mount]\ntargte = \"/data\"\n", ConfigFormat::Toml).unwrap_err();
+        assert!(format!("{}", err).contains("targte"));
     }
-}
 
-fn validate_mount(config: &Mount) -> Option<ConfigError> {
-    if config.target.is_empty() {
-        Some(ConfigError::InvalidMount(String::from("required parameter target")))
-    } else {
-        None
+    #[test]
+    fn config_format_from_path() {
+        assert_eq!(ConfigFormat::Json, ConfigFormat::from_path("/etc/cps.json"));
+        assert_eq!(ConfigFormat::Toml, ConfigFormat::from_path("cps.toml"));
+        assert_eq!(ConfigFormat::Yaml, ConfigFormat::from_path("cps.yml"));
+        assert_eq!(Ok(ConfigFormat::Yaml), "yaml".parse());
+        assert!("ini".parse::<ConfigFormat>().is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn validation_reports_all_errors_with_paths() {
+        let errors = invalid_errors(parse_config(r#"
+block-device: xvdf
+block-provider:
+  aws-ebs:
+    type: st1
+    size: 100
+    ebs-tags:
+      "aws:cloudformation:stack-name": db
+mount:
+  target: data
+  mode: "0999"
+"#));
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["block-device: block-device must be auto or a device under /dev: xvdf",
+                        "block-provider.aws-ebs.size: st1 volumes must be between 500 and 16384 GiB: 100",
+                        "block-provider.aws-ebs.ebs-tags.aws:cloudformation:stack-name: ebs tag keys must not \
+                         start with aws:, which is reserved by AWS: aws:cloudformation:stack-name",
+                        "mount.target: target must be an absolute path: data",
+                        "mount.mode: invalid mode, expected octal permissions: 0999"],
+                   messages);
+    }
 
-    fn parse_config(config_str: &str) -> Result<Config, ConfigError> {
-        let config = serde_yaml::from_str(config_str)?;
-        if let Some(err) = validate_config(&config) {
-            Err(err)
-        } else {
-            Ok(config)
-        }
+    #[test]
+    fn validation_reports_volume_paths() {
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("target: /var/lib/postgresql-wal", "target: wal");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("volumes[1].mount.target", err.path);
     }
 
     #[test]
-    fn validate_block_provider_aws_ebs_volume_type() {
-        let config = Config {
-            block_device: default_block_device(),
-            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
-                                                      ebs_tags: HashMap::new(),
-                                                      size: 200,
-                                                      volume_type: String::from("grr-arg"),
-                                                  }),
-            file_system: default_file_system(),
-            mount: default_mount(),
-        };
-        let err = validate_config(&config).expect("expected config error");
-        assert_eq!("invalid configuration in block-provider aws-ebs",
-                   err.description());
-        assert_eq!("invalid volume type, expected gp2, io1, st1, sc1: grr-arg",
-                   format!("{}", err));
+    fn validation_errors_located_in_yaml() {
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("size: 50\n", "size: 0\n");
+        let result = locate_validation_errors(parse_config(config_text.as_str()), config_text.as_str());
+        let err = single_error(invalid_errors(result));
+        assert_eq!("volumes[1].block-provider.aws-ebs.size (line 14, column 9): gp2 volumes must be between 1 and \
+                    16384 GiB: 0",
+                   err.to_string());
     }
 
     #[test]
-    fn validate_block_provider_aws_ebs_size() {
-        let config = Config {
+    fn validates_ebs_tag_lengths() {
+        let mut ebs_tags = HashMap::new();
+        ebs_tags.insert(String::from("role"), "x".repeat(257));
+        ebs_tags.insert("k".repeat(129), String::from("value"));
+        let config = single_volume_config(Volume {
             block_device: default_block_device(),
             block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
-                                                      ebs_tags: HashMap::new(),
-                                                      size: -100,
+                                                      ebs_tags: ebs_tags,
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: EbsTimeouts::default(),
+                                                      size: 100,
                                                       volume_type: String::from("gp2"),
                                                   }),
             file_system: default_file_system(),
             mount: default_mount(),
-        };
-        let err = validate_config(&config).expect("expected config error");
-        assert_eq!("invalid configuration in block-provider aws-ebs",
-                   err.description());
-        assert_eq!("invalid volume size", format!("{}", err));
+            layout: default_layout(),
+            encryption: None,
+        });
+        let messages: Vec<String> = validate_config(&config).iter().map(|e| e.error.to_string()).collect();
+        assert_eq!(vec!["ebs tag keys must be 1 to 128 characters", "ebs tag values must be at most 256 characters"],
+                   messages);
     }
 
     #[test]
-    fn validate_block_provider_aws_ebs_tags() {
-        let config = Config {
-            block_device: default_block_device(),
-            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
-                                                      ebs_tags: HashMap::new(),
-                                                      size: 200,
-                                                      volume_type: String::from("gp2"),
-                                                  }),
-            file_system: default_file_system(),
-            mount: default_mount(),
+    fn parses_ebs_timeouts() {
+        let config = parse_config_document("block-provider:
+  aws-ebs:
+    size: 100
+    ebs-tags:
+      Role: PostgreSQL
+    timeouts:
+      volume-attached:
+        timeout: 600
+        max-interval: 30
+      throttle-retries: 3
+",
+                                           ConfigFormat::Yaml)
+            .unwrap();
+        let timeouts = match config.block_provider {
+            Some(BlockProvider::AwsEbs(ref ebs)) => &ebs.timeouts,
+            _ => panic!("expected aws-ebs block provider"),
         };
-        let err = validate_config(&config).expect("expected config error");
-        assert_eq!("invalid configuration in block-provider aws-ebs",
-                   err.description());
-        assert_eq!("at least one ebs tag is required", format!("{}", err));
+        assert_eq!(600, timeouts.volume_attached.timeout);
+        assert_eq!(1, timeouts.volume_attached.initial_interval);
+        assert_eq!(30, timeouts.volume_attached.max_interval);
+        assert_eq!(300, timeouts.volume_available.timeout);
+        assert_eq!(3, timeouts.throttle_retries);
     }
 
     #[test]
-    fn validate_file_system_mkfs() {
-        let mut ebs_tags: HashMap<String, String> = HashMap::new();
-        ebs_tags.insert(String::from("a"), String::from("b"));
-        let config = Config {
-            block_device: default_block_device(),
-            block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
-                                                      ebs_tags: ebs_tags,
-                                                      size: 200,
-                                                      volume_type: String::from("gp2"),
-                                                  }),
-            file_system: FileSystem { mkfs: Vec::new() },
-            mount: default_mount(),
+    fn parses_volume_selection() {
+        let selection = |text: &str| {
+            let config = parse_config_document(&format!("block-provider:\n  aws-ebs:\n    size: 100\n    \
+                                                         ebs-tags: {{ Role: PostgreSQL }}\n    selection: {}\n",
+                                                        text),
+                                               ConfigFormat::Yaml)
+                .unwrap();
+            match config.block_provider {
+                Some(BlockProvider::AwsEbs(ebs)) => ebs.selection,
+                _ => panic!("expected aws-ebs block provider"),
+            }
         };
-        let err = validate_config(&config).expect("expected config error");
-        assert_eq!("invalid configuration in file-system", err.description());
-        assert_eq!("required parameter mkfs", format!("{}", err));
+        assert_eq!(Some(VolumeSelection::Newest), selection("newest"));
+        assert_eq!(Some(VolumeSelection::MostRecentSnapshot), selection("most-recent-snapshot"));
+        assert_eq!(Some(VolumeSelection::PreferTag(PreferredTag {
+                                                       key: String::from("Zone"),
+                                                       value: String::from("us-west-2a"),
+                                                   })),
+                   selection("{ prefer-tag: { key: Zone, value: us-west-2a } }"));
     }
 
     #[test]
-    fn validate_mount_target() {
-        let mut ebs_tags: HashMap<String, String> = HashMap::new();
-        ebs_tags.insert(String::from("a"), String::from("b"));
-        let config = Config {
+    fn validates_ebs_timeouts() {
+        let mut ebs_tags = HashMap::new();
+        ebs_tags.insert(String::from("Role"), String::from("PostgreSQL"));
+        let mut timeouts = EbsTimeouts::default();
+        timeouts.volume_available.timeout = 0;
+        timeouts.volume_detached.max_interval = 0;
+        let config = single_volume_config(Volume {
             block_device: default_block_device(),
             block_provider: BlockProvider::AwsEbs(EbsBlockProviderConfig {
                                                       ebs_tags: ebs_tags,
-                                                      size: 200,
+                                                      selection: None,
+                                                      fingerprint_mismatch: FingerprintMismatch::default(),
+                                                      pool: None,
+                                                      timeouts: timeouts,
+                                                      size: 100,
                                                       volume_type: String::from("gp2"),
                                                   }),
             file_system: default_file_system(),
-            mount: Mount { target: String::from("") },
-        };
-        let err = validate_config(&config).expect("expected config error");
-        assert_eq!("invalid configuration in mount", err.description());
-        assert_eq!("required parameter target", format!("{}", err));
+            mount: default_mount(),
+            layout: default_layout(),
+            encryption: None,
+        });
+        let paths: Vec<String> = validate_config(&config).into_iter().map(|e| e.path).collect();
+        assert_eq!(vec!["volumes[0].block-provider.aws-ebs.timeouts.volume-available.timeout",
+                        "volumes[0].block-provider.aws-ebs.timeouts.volume-detached.max-interval"],
+                   paths);
     }
 
-    const EXAMPLE_MINIMAL_EBS_CONFIG: &'static str = r#"
-block-provider:
-  aws-ebs:
-    ebs-tags: { a: "b" }
-    size: 200
-"#;
-
-    const EXAMPLE_FULL_EBS_CONFIG: &'static str = r#"
-block-device: /dev/xvdf
-
-block-provider:
-  aws-ebs:
-    ebs-tags:
-      tag-a: value-a
-    type: gp2
-    size: 200
-
-file-system:
-  mkfs:
-    - -t
-    - ext4
-    - -m
-    - 5
-
-mount:
-  target: /mnt/test
-"#;
+    #[test]
+    fn override_options_are_known() {
+        let mut config = Config::default();
+        for option in OVERRIDE_OPTIONS {
+            match apply_override(&mut config, option.name, "") {
+                Err(ConfigError::InvalidOverride(ref msg)) if msg.starts_with("unknown setting") => {
+                    assert!(false, "{}", msg)
+                }
+                _ => {}
+            }
+        }
+    }
 
     #[test]
     fn toplevel_deny_unknown_fields() {
@@ -330,20 +2323,20 @@ mount:
     #[test]
     fn parses_block_device() {
         let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
-        assert_eq!("/dev/xvdf", config.block_device);
+        assert_eq!("/dev/xvdf", config.volumes[0].block_device);
     }
 
     #[test]
     fn block_device_default() {
         let config = parse_config(EXAMPLE_MINIMAL_EBS_CONFIG).unwrap();
-        assert_eq!("/dev/xvdc", config.block_device);
+        assert_eq!("/dev/xvdc", config.volumes[0].block_device);
     }
 
     #[test]
     fn parses_block_provider_aws_ebs() {
         let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
-        match config.block_provider {
-            BlockProvider::AwsEbs(ebs_config) => {
+        match config.volumes[0].block_provider {
+            BlockProvider::AwsEbs(ref ebs_config) => {
                 assert_eq!("gp2", ebs_config.volume_type);
                 assert_eq!(200, ebs_config.size);
                 assert!(ebs_config.ebs_tags.get("tag-a").unwrap() == "value-a");
@@ -355,8 +2348,8 @@ mount:
     #[test]
     fn block_provider_aws_ebs_defaults() {
         let config = parse_config(EXAMPLE_MINIMAL_EBS_CONFIG).unwrap();
-        match config.block_provider {
-            BlockProvider::AwsEbs(ebs_config) => {
+        match config.volumes[0].block_provider {
+            BlockProvider::AwsEbs(ref ebs_config) => {
                 assert_eq!("gp2", ebs_config.volume_type);
             }
             _ => assert!(false, "expected AwsEbs block provider"),
@@ -386,7 +2379,7 @@ block-provider:
                         String::from("ext4"),
                         String::from("-m"),
                         String::from("5")],
-                   config.file_system.mkfs);
+                   config.volumes[0].file_system.mkfs);
     }
 
     #[test]
@@ -396,7 +2389,7 @@ block-provider:
                         String::from("ext4"),
                         String::from("-m"),
                         String::from("0")],
-                   config.file_system.mkfs);
+                   config.volumes[0].file_system.mkfs);
     }
 
     #[test]
@@ -407,7 +2400,35 @@ block-provider:
                         String::from("ext4"),
                         String::from("-m"),
                         String::from("0")],
-                   config.file_system.mkfs);
+                   config.volumes[0].file_system.mkfs);
+    }
+
+    #[test]
+    fn parses_file_system_fsck() {
+        let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
+        let fsck = config.volumes[0].file_system.fsck.as_ref().unwrap();
+        assert_eq!(600, fsck.timeout);
+        assert_eq!(FsckErrorAction::ReadOnly, fsck.on_errors);
+    }
+
+    #[test]
+    fn file_system_fsck_defaults() {
+        let config = parse_config(EXAMPLE_MINIMAL_EBS_CONFIG).unwrap();
+        assert!(config.volumes[0].file_system.fsck.is_none());
+
+        let config_text = String::from(EXAMPLE_MINIMAL_EBS_CONFIG) + "\n\nfile-system: { fsck: {} }";
+        let config = parse_config(config_text.as_str()).unwrap();
+        let fsck = config.volumes[0].file_system.fsck.as_ref().unwrap();
+        assert_eq!(300, fsck.timeout);
+        assert_eq!(FsckErrorAction::Abort, fsck.on_errors);
+    }
+
+    #[test]
+    fn validate_file_system_fsck_timeout() {
+        let config_text = String::from(EXAMPLE_MINIMAL_EBS_CONFIG) + "\n\nfile-system: { fsck: { timeout: 0 } }";
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("invalid configuration in file-system", err.error.description());
+        assert_eq!("fsck timeout must be at least 1 second", format!("{}", err.error));
     }
 
     #[test]
@@ -423,20 +2444,45 @@ block-provider:
     #[test]
     fn parses_mount() {
         let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
-        assert_eq!("/mnt/test", config.mount.target);
+        assert_eq!("/mnt/test", config.volumes[0].mount.target);
+    }
+
+    #[test]
+    fn parses_mount_permissions() {
+        let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
+        assert_eq!(Some(String::from("postgres")), config.volumes[0].mount.owner);
+        assert_eq!(Some(String::from("postgres")), config.volumes[0].mount.group);
+        assert_eq!(Some(0o700), config.volumes[0].mount.mode_bits().map(|m| m.unwrap()));
+        assert_eq!(Some(String::from("system_u:object_r:postgresql_db_t:s0")),
+                   config.volumes[0].mount.context);
+        assert_eq!(ApplyPermissions::Always, config.volumes[0].mount.apply_permissions);
+    }
+
+    #[test]
+    fn parses_mount_binds() {
+        let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
+        assert_eq!(2, config.volumes[0].mount.binds.len());
+        assert_eq!("data/pg", config.volumes[0].mount.binds[0].source);
+        assert_eq!("/var/lib/postgresql", config.volumes[0].mount.binds[0].target);
+        assert_eq!("logs", config.volumes[0].mount.binds[1].source);
+        assert_eq!("/var/log/app", config.volumes[0].mount.binds[1].target);
     }
 
     #[test]
     fn mount_default() {
         let config = parse_config(EXAMPLE_MINIMAL_EBS_CONFIG).unwrap();
-        assert_eq!("/mnt", config.mount.target);
+        assert_eq!("/mnt", config.volumes[0].mount.target);
+        assert_eq!(None, config.volumes[0].mount.owner);
+        assert_eq!(None, config.volumes[0].mount.mode);
+        assert_eq!(ApplyPermissions::OnCreate, config.volumes[0].mount.apply_permissions);
+        assert!(config.volumes[0].mount.binds.is_empty());
     }
 
     #[test]
     fn mount_default_target() {
         let config_text = String::from(EXAMPLE_MINIMAL_EBS_CONFIG) + "\n\nmount: {}";
         let config = parse_config(config_text.as_str()).unwrap();
-        assert_eq!("/mnt", config.mount.target);
+        assert_eq!("/mnt", config.volumes[0].mount.target);
     }
 
     #[test]
@@ -447,4 +2493,402 @@ block-provider:
             _ => assert!(false, "expected YamlParseError"),
         }
     }
+
+    const EXAMPLE_VOLUMES_CONFIG: &'static str = r#"
+volumes:
+  - block-device: /dev/xvdf
+    block-provider:
+      aws-ebs:
+        ebs-tags: { role: "db-data" }
+        size: 500
+    mount:
+      target: /var/lib/postgresql
+  - block-device: /dev/xvdg
+    block-provider:
+      aws-ebs:
+        ebs-tags: { role: "db-wal" }
+        size: 50
+    mount:
+      target: /var/lib/postgresql-wal
+"#;
+
+    #[test]
+    fn parses_volumes() {
+        let config = parse_config(EXAMPLE_VOLUMES_CONFIG).unwrap();
+        assert_eq!(2, config.volumes.len());
+        assert_eq!("/dev/xvdf", config.volumes[0].block_device);
+        assert_eq!("/var/lib/postgresql", config.volumes[0].mount.target);
+        assert_eq!("/dev/xvdg", config.volumes[1].block_device);
+        assert_eq!("/var/lib/postgresql-wal", config.volumes[1].mount.target);
+        assert!(config.block_provider.is_none());
+    }
+
+    #[test]
+    fn single_volume_folded_into_volumes() {
+        let config = parse_config(EXAMPLE_FULL_EBS_CONFIG).unwrap();
+        assert_eq!(1, config.volumes.len());
+        assert!(config.block_device.is_none());
+        assert!(config.block_provider.is_none());
+        assert!(config.file_system.is_none());
+        assert!(config.mount.is_none());
+        assert!(config.layout.is_none());
+        assert!(config.encryption.is_none());
+    }
+
+    #[test]
+    fn volumes_exclusive_with_single_volume() {
+        let config_text = String::from(EXAMPLE_VOLUMES_CONFIG) + "\nblock-device: /dev/xvdh";
+        let err = parse_config(config_text.as_str()).unwrap_err();
+        assert_eq!("invalid configuration in volumes", err.description());
+    }
+
+    #[test]
+    fn block_provider_or_volumes_required() {
+        let err = parse_config("mount: { target: /mnt }").unwrap_err();
+        assert_eq!("invalid configuration in volumes", err.description());
+        assert_eq!("either block-provider or volumes is required",
+                   format!("{}", err));
+    }
+
+    #[test]
+    fn validate_block_device_drive_letter() {
+        let block_device_error = |block_device: &str| {
+            let config_text = EXAMPLE_VOLUMES_CONFIG.replace("/dev/xvdf", block_device);
+            format!("{}", single_error(invalid_errors(parse_config(&config_text))).error)
+        };
+        assert_eq!("block-device must be auto or a device under /dev: ", block_device_error("\"\""));
+        assert_eq!("block-device must end in a drive letter, eg. /dev/xvdf: /dev/xvd\u{e9}",
+                   block_device_error("/dev/xvd\u{e9}"));
+        assert_eq!("block-device must end in a drive letter, eg. /dev/xvdf: /dev/xvdF",
+                   block_device_error("/dev/xvdF"));
+    }
+
+    #[test]
+    fn volumes_distinct_block_devices() {
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("/dev/xvdg", "/dev/xvdf");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("invalid configuration in volumes", err.error.description());
+        assert_eq!("block-device used by more than one volume: /dev/xvdf",
+                   format!("{}", err.error));
+    }
+
+    #[test]
+    fn volumes_distinct_ebs_tags() {
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("db-wal", "db-data");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("invalid configuration in volumes", err.error.description());
+        assert_eq!("ebs-tags must be distinct for each volume, and not a subset of another volume's",
+                   format!("{}", err.error));
+    }
+
+    #[test]
+    fn volumes_distinct_lvm_volume_groups() {
+        let lvm_layout = "      target: /var/lib/postgresql-wal\n    layout:\n      lvm:\n        members: 2\n";
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("      target: /var/lib/postgresql\n",
+                                                         &lvm_layout.replace("-wal", ""))
+            .replace("      target: /var/lib/postgresql-wal\n", lvm_layout)
+            .replace("/dev/xvdg", "/dev/xvdh");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("volumes[1].layout.lvm.volume-group", err.path);
+        assert_eq!("volume group used by more than one volume: cps", format!("{}", err.error));
+
+        let config_text = config_text.replacen("        members: 2\n",
+                                               "        members: 2\n        volume-group: wal\n",
+                                               1);
+        parse_config(config_text.as_str()).unwrap();
+    }
+
+    #[test]
+    fn volumes_distinct_raid_devices() {
+        let raid_layout = "      target: /var/lib/postgresql-wal\n    layout:\n      raid:\n        level: 1\n        \
+                           members: 2\n";
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("      target: /var/lib/postgresql\n",
+                                                         &raid_layout.replace("-wal", ""))
+            .replace("      target: /var/lib/postgresql-wal\n", raid_layout)
+            .replace("/dev/xvdg", "/dev/xvdh");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("volumes[1].layout.raid.device", err.path);
+        assert_eq!("raid device used by more than one volume: /dev/md0", format!("{}", err.error));
+
+        let config_text = config_text.replacen("        members: 2\n",
+                                               "        members: 2\n        device: /dev/md1\n",
+                                               1);
+        parse_config(config_text.as_str()).unwrap();
+    }
+
+    #[test]
+    fn volumes_distinct_encryption_names() {
+        let encryption = "      target: /var/lib/postgresql-wal\n    encryption:\n      \
+                          key-source: { env: CPS_LUKS_KEY }\n";
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("      target: /var/lib/postgresql\n",
+                                                         &encryption.replace("-wal", ""))
+            .replace("      target: /var/lib/postgresql-wal\n", encryption);
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("volumes[1].encryption.name", err.path);
+        assert_eq!("encryption name used by more than one volume: cps-crypt", format!("{}", err.error));
+
+        let config_text = config_text.replacen("    encryption:\n", "    encryption:\n      name: cps-data\n", 1);
+        parse_config(config_text.as_str()).unwrap();
+    }
+
+    #[test]
+    fn volumes_ebs_tags_not_subset() {
+        let config_text = EXAMPLE_VOLUMES_CONFIG.replace("{ role: \"db-wal\" }",
+                                                         "{ role: \"db-data\", tier: \"wal\" }");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("volumes[1].block-provider.aws-ebs.ebs-tags", err.path);
+        assert_eq!("ebs-tags must be distinct for each volume, and not a subset of another volume's",
+                   format!("{}", err.error));
+    }
+
+    const EXAMPLE_LVM_CONFIG: &'static str = r#"
+block-device: /dev/xvdf
+block-provider:
+  aws-ebs:
+    ebs-tags: { a: "b" }
+    size: 200
+layout:
+  lvm:
+    members: 3
+    stripe-size: 256k
+"#;
+
+    #[test]
+    fn parses_layout_lvm() {
+        let config = parse_config(EXAMPLE_LVM_CONFIG).unwrap();
+        let volume = &config.volumes[0];
+        match volume.layout {
+            Layout::Lvm(ref lvm) => {
+                assert_eq!(3, lvm.members);
+                assert_eq!("cps", lvm.volume_group);
+                assert_eq!("data", lvm.logical_volume);
+                assert_eq!(Some(String::from("256k")), lvm.stripe_size);
+            }
+            _ => assert!(false, "expected lvm layout"),
+        }
+        assert_eq!(vec!["/dev/xvdf", "/dev/xvdg", "/dev/xvdh"],
+                   volume.member_block_devices());
+        assert_eq!("/dev/cps/data", volume.filesystem_device());
+    }
+
+    #[test]
+    fn layout_default_single() {
+        let config = parse_config(EXAMPLE_MINIMAL_EBS_CONFIG).unwrap();
+        let volume = &config.volumes[0];
+        assert_eq!(vec!["/dev/xvdc"], volume.member_block_devices());
+        assert_eq!("/dev/xvdc", volume.filesystem_device());
+    }
+
+    #[test]
+    fn attachment_block_devices() {
+        let config = parse_config(&EXAMPLE_MINIMAL_EBS_CONFIG.replace("size: 200", "size: 200\nblock-device: /dev/sdm"))
+            .unwrap();
+        assert_eq!(vec!["/dev/sdm", "/dev/sdn", "/dev/sdo", "/dev/sdp"],
+                   config.volumes[0].attachment_block_devices());
+
+        let config = parse_config(EXAMPLE_LVM_CONFIG).unwrap();
+        assert_eq!(vec!["/dev/xvdf", "/dev/xvdg", "/dev/xvdh"],
+                   config.volumes[0].attachment_block_devices());
+    }
+
+    #[test]
+    fn parses_auto_block_device() {
+        let config = parse_config(&EXAMPLE_MINIMAL_EBS_CONFIG.replace("size: 200", "size: 200\nblock-device: auto"))
+            .unwrap();
+        let volume = &config.volumes[0];
+        assert!(volume.auto_block_device());
+        assert!(volume.member_block_devices().is_empty());
+        assert_eq!(11, volume.attachment_block_devices().len());
+        assert_eq!("/dev/xvdf", volume.attachment_block_devices()[0]);
+
+        let config_text = EXAMPLE_LVM_CONFIG.replace("block-device: /dev/xvdf", "block-device: auto")
+            .replace("members: 3", "members: 12");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("block-device auto can choose at most 11 layout members", format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_layout_lvm_block_device() {
+        let config_text = r#"
+volumes:
+  - block-device: /dev/xvdy
+    block-provider:
+      aws-ebs:
+        ebs-tags: { a: "b" }
+        size: 200
+    layout:
+      lvm: { members: 3 }
+"#;
+        let err = single_error(invalid_errors(parse_config(config_text)));
+        assert_eq!("invalid configuration in layout", err.error.description());
+        assert_eq!("block-device must end in a letter with room for all layout members: /dev/xvdy",
+                   format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_layout_too_many_members() {
+        let lvm_layout = |members: &str| {
+            format!("      target: /var/lib/postgresql\n    layout:\n      lvm:\n        members: {}\n", members)
+        };
+        for members in &["200", "4294967295"] {
+            let config_text = EXAMPLE_VOLUMES_CONFIG.replace("/dev/xvdf", "/dev/xvdz")
+                .replace("      target: /var/lib/postgresql\n", &lvm_layout(members));
+            let err = single_error(invalid_errors(parse_config(&config_text)));
+            assert_eq!("volumes[0].layout.lvm.members", err.path);
+            assert_eq!("block-device must end in a letter with room for all layout members: /dev/xvdz",
+                       format!("{}", err.error));
+        }
+    }
+
+    #[test]
+    fn parses_layout_raid() {
+        let config_text = EXAMPLE_LVM_CONFIG.replace("lvm:\n    members: 3\n    stripe-size: 256k",
+                                                     "raid: { level: 1, members: 2, allow-degraded: true }");
+        let config = parse_config(config_text.as_str()).unwrap();
+        let volume = &config.volumes[0];
+        match volume.layout {
+            Layout::Raid(ref raid) => {
+                assert_eq!(1, raid.level);
+                assert_eq!(2, raid.members);
+                assert!(raid.allow_degraded);
+            }
+            _ => assert!(false, "expected raid layout"),
+        }
+        assert_eq!(vec!["/dev/xvdf", "/dev/xvdg"], volume.member_block_devices());
+        assert_eq!("/dev/md0", volume.filesystem_device());
+    }
+
+    #[test]
+    fn validate_layout_raid_members() {
+        let config_text = EXAMPLE_LVM_CONFIG.replace("lvm:\n    members: 3\n    stripe-size: 256k",
+                                                     "raid: { level: 10, members: 2 }");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("invalid configuration in layout", err.error.description());
+        assert_eq!("raid level 10 requires at least 4 members", format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_layout_raid0_degraded() {
+        let config_text = EXAMPLE_LVM_CONFIG.replace("lvm:\n    members: 3\n    stripe-size: 256k",
+                                                     "raid: { level: 0, members: 2, allow-degraded: true }");
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("raid level 0 cannot run degraded", format!("{}", err.error));
+    }
+
+    #[test]
+    fn validate_pool() {
+        let pool = "    pool: { size: 2, availability-zones: [us-west-2a, us-west-2b], snapshot-id: snap-1 }\n";
+        let config = parse_config(&(String::from(EXAMPLE_MINIMAL_EBS_CONFIG) + pool)).unwrap();
+        match config.volumes[0].block_provider {
+            BlockProvider::AwsEbs(ref ebs) => {
+                let pool = ebs.pool.as_ref().unwrap();
+                assert_eq!(2, pool.size);
+                assert_eq!(vec!["us-west-2a", "us-west-2b"], pool.availability_zones);
+                assert_eq!(Some(String::from("snap-1")), pool.snapshot_id);
+            }
+            _ => panic!("expected an aws-ebs block provider"),
+        }
+
+        let config_text = EXAMPLE_LVM_CONFIG.replace("size: 200",
+                                                     "size: 200\n    pool: { size: 0, snapshot-id: snap-1 }");
+        let errors = invalid_errors(parse_config(&config_text));
+        assert_eq!(vec!["block-provider.aws-ebs.pool.size",
+                        "block-provider.aws-ebs.pool.snapshot-id"],
+                   errors.iter().map(|e| e.path.as_str()).collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn parses_encryption() {
+        let config_text = String::from(EXAMPLE_MINIMAL_EBS_CONFIG) + r#"
+encryption:
+  name: pgdata
+  key-source:
+    kms:
+      ciphertext-file: /etc/cps/key.enc
+"#;
+        let config = parse_config(config_text.as_str()).unwrap();
+        let volume = &config.volumes[0];
+        let encryption = volume.encryption.as_ref().unwrap();
+        assert_eq!("pgdata", encryption.name);
+        match encryption.key_source {
+            KeySource::Kms(ref kms) => {
+                assert_eq!(None, kms.ciphertext);
+                assert_eq!(Some(String::from("/etc/cps/key.enc")), kms.ciphertext_file);
+            }
+            _ => assert!(false, "expected kms key source"),
+        }
+        assert_eq!("/dev/xvdc", volume.layout_device());
+        assert_eq!("/dev/mapper/pgdata", volume.filesystem_device());
+    }
+
+    #[test]
+    fn encryption_default_name() {
+        let config_text = String::from(EXAMPLE_MINIMAL_EBS_CONFIG) +
+                          "\nencryption: { key-source: { env: CPS_LUKS_KEY } }";
+        let config = parse_config(config_text.as_str()).unwrap();
+        let encryption = config.volumes[0].encryption.as_ref().unwrap();
+        assert_eq!("cps-crypt", encryption.name);
+        match encryption.key_source {
+            KeySource::Env(ref name) => assert_eq!("CPS_LUKS_KEY", name),
+            _ => assert!(false, "expected env key source"),
+        }
+    }
+
+    #[test]
+    fn validate_encryption_kms_ciphertext() {
+        let config_text = String::from(EXAMPLE_MINIMAL_EBS_CONFIG) +
+                          "\nencryption: { key-source: { kms: {} } }";
+        let err = single_error(invalid_errors(parse_config(config_text.as_str())));
+        assert_eq!("invalid configuration in encryption", err.error.description());
+        assert_eq!("key-source kms requires exactly one of ciphertext or ciphertext-file",
+                   format!("{}", err.error));
+    }
+
+    #[test]
+    fn config_hash_ignores_setting_order() {
+        let hash = |text: &str| parse_config(text).unwrap().volumes[0].config_hash();
+        let config_hash = hash("block-provider:\n  aws-ebs:\n    size: 100\n    \
+                                ebs-tags: { Role: PostgreSQL, Environment: Production }\n");
+        assert_eq!(16, config_hash.len());
+        assert_eq!(config_hash,
+                   hash("block-provider:\n  aws-ebs:\n    ebs-tags: { Environment: Production, Role: PostgreSQL }\n    \
+                         size: 100\n"));
+        assert!(config_hash !=
+                hash("block-provider:\n  aws-ebs:\n    size: 200\n    \
+                      ebs-tags: { Role: PostgreSQL, Environment: Production }\n"));
+    }
+
+    #[test]
+    fn config_fingerprint() {
+        let fingerprint = |text: &str| {
+            let text = String::from("block-provider:\n  aws-ebs:\n    size: 100\n    ebs-tags: { Role: db }\n") + text;
+            parse_config(&text).unwrap().volumes[0].config_fingerprint()
+        };
+        assert_eq!("ext4/single/none", fingerprint(""));
+        assert_eq!("xfs/lvm-3/luks",
+                   fingerprint("layout:\n  lvm:\n    members: 3\nfile-system:\n  mkfs: [--type=xfs]\n\
+                                encryption:\n  key-source:\n    file: /etc/cps.key\n"));
+        assert_eq!("ext2/raid1-2/none",
+                   fingerprint("layout:\n  raid:\n    level: 1\n    members: 2\nfile-system:\n  mkfs: [-m, 0]\n"));
+        assert_eq!("btrfs",
+                   FileSystem {
+                           mkfs: vec![String::from("--type"), String::from("btrfs")],
+                           fsck: None,
+                       }
+                       .filesystem_type());
+    }
+
+    #[test]
+    fn parses_fingerprint_mismatch() {
+        let parse = |fingerprint_mismatch: &str| {
+            parse_config(&format!("block-provider:\n  aws-ebs:\n    size: 100\n    ebs-tags: {{ Role: db }}\n    \
+                                   fingerprint-mismatch: {}\n",
+                                  fingerprint_mismatch))
+        };
+        match parse("skip").unwrap().volumes[0].block_provider {
+            BlockProvider::AwsEbs(ref ebs) => assert_eq!(FingerprintMismatch::Skip, ebs.fingerprint_mismatch),
+            _ => panic!("expected an aws-ebs block provider"),
+        }
+        assert!(parse("ignore").is_err());
+    }
 }
@@ -0,0 +1,265 @@
+use std;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+use hyper;
+use aws_instance_metadata;
+use aws_instance_metadata::myerr::MetadataRetrievalError;
+use rusoto::{DefaultCredentialsProvider, ProvideAwsCredentials, DispatchSignedRequest};
+use rusoto::default_tls_client;
+use rusoto::ec2::DescribeTagsError;
+use rusoto::ssm::{SsmClient, GetParametersRequest, GetParametersError};
+use ebs;
+
+/// Where the configuration is read from.
+#[derive(Debug, PartialEq)]
+pub enum ConfigSource {
+    /// A YAML file on the local filesystem.
+    File(String),
+    /// The instance's EC2 user-data, which must be a YAML configuration.
+    UserData,
+    /// A YAML configuration stored in the named SSM Parameter Store parameter.
+    Ssm(String),
+    /// Instance tags whose keys start with the given prefix, each followed by the name of a setting.
+    InstanceTags(String),
+}
+
+impl FromStr for ConfigSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConfigSource, String> {
+        if s == "user-data" {
+            Ok(ConfigSource::UserData)
+        } else if s.starts_with("file:") && s.len() > "file:".len() {
+            Ok(ConfigSource::File(String::from(&s["file:".len()..])))
+        } else if s.starts_with("ssm:") && s.len() > "ssm:".len() {
+            Ok(ConfigSource::Ssm(String::from(&s["ssm:".len()..])))
+        } else if s.starts_with("instance-tags:") && s.len() > "instance-tags:".len() {
+            Ok(ConfigSource::InstanceTags(String::from(&s["instance-tags:".len()..])))
+        } else {
+            Err(format!("expected user-data, file:PATH, ssm:NAME or instance-tags:PREFIX: {}", s))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigSourceError {
+    HttpRequestError(hyper::Error),
+    IoError(std::io::Error),
+    UnexpectedMetadataStatus(hyper::status::StatusCode),
+    UserDataMissing,
+    InstanceMetadataFailed(MetadataRetrievalError),
+    GetParametersFailed(GetParametersError),
+    ParameterMissing(String),
+    DescribeTagsFailed(DescribeTagsError),
+}
+
+impl fmt::Display for ConfigSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigSourceError::HttpRequestError(ref err) => write!(f, "instance metadata request failed: {}", err),
+            ConfigSourceError::IoError(ref err) => write!(f, "reading instance metadata failed: {}", err),
+            ConfigSourceError::UnexpectedMetadataStatus(ref status) => {
+                write!(f, "instance metadata request failed: {}", status)
+            }
+            ConfigSourceError::UserDataMissing => write!(f, "instance has no user-data"),
+            ConfigSourceError::InstanceMetadataFailed(ref err) => {
+                write!(f, "unable to retrieve instance metadata: {:?}", err)
+            }
+            ConfigSourceError::GetParametersFailed(ref err) => write!(f, "unable to get SSM parameter: {:?}", err),
+            ConfigSourceError::ParameterMissing(ref name) => write!(f, "SSM parameter not found: {}", name),
+            ConfigSourceError::DescribeTagsFailed(ref err) => {
+                write!(f, "unable to describe instance tags: {:?}", err)
+            }
+        }
+    }
+}
+
+impl From<GetParametersError> for ConfigSourceError {
+    fn from(err: GetParametersError) -> ConfigSourceError {
+        ConfigSourceError::GetParametersFailed(err)
+    }
+}
+
+/// Retrieves documents from the instance metadata service.
+pub trait FetchMetadata {
+    /// Returns the document at `path` under http://169.254.169.254/latest/, or `None` if it doesn't exist.
+    fn fetch(&self, path: &str) -> Result<Option<String>, ConfigSourceError>;
+}
+
+pub struct MetadataServiceClient {}
+
+impl FetchMetadata for MetadataServiceClient {
+    fn fetch(&self, path: &str) -> Result<Option<String>, ConfigSourceError> {
+        let client = hyper::Client::new();
+        let url = String::from("http://169.254.169.254/latest/") + path;
+        trace!("fetching instance metadata: {}", url);
+        let mut response = client.get(&url).send().map_err(ConfigSourceError::HttpRequestError)?;
+        match response.status {
+            hyper::status::StatusCode::Ok => {}
+            hyper::status::StatusCode::NotFound => return Ok(None),
+            status => return Err(ConfigSourceError::UnexpectedMetadataStatus(status)),
+        }
+        let mut body = String::new();
+        response.read_to_string(&mut body).map_err(ConfigSourceError::IoError)?;
+        Ok(Some(body))
+    }
+}
+
+/// Reads the instance's user-data.
+pub fn user_data() -> Result<String, ConfigSourceError> {
+    read_user_data(&MetadataServiceClient {})
+}
+
+fn read_user_data<M: FetchMetadata>(metadata_client: &M) -> Result<String, ConfigSourceError> {
+    match metadata_client.fetch("user-data")? {
+        Some(ref user_data) if !user_data.trim().is_empty() => Ok(user_data.to_owned()),
+        _ => Err(ConfigSourceError::UserDataMissing),
+    }
+}
+
+/// Reads the value of an SSM Parameter Store parameter, decrypting it if it's a SecureString.
+pub fn ssm_parameter(name: &str) -> Result<String, ConfigSourceError> {
+    let metadata = aws_instance_metadata::get().map_err(ConfigSourceError::InstanceMetadataFailed)?;
+    let ssm_client = SsmClient::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    get_parameter(name, &ssm_client)
+}
+
+fn get_parameter<P, D>(name: &str, ssm_client: &SsmClient<P, D>) -> Result<String, ConfigSourceError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = GetParametersRequest {
+        names: vec![String::from(name)],
+        with_decryption: Some(true),
+    };
+    trace!("executing SSM GetParameters");
+    ssm_client.get_parameters(&request)?
+        .parameters
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|parameter| parameter.value)
+        .next()
+        .ok_or_else(|| ConfigSourceError::ParameterMissing(String::from(name)))
+}
+
+/// Reads the instance's tags that start with `prefix`, keyed by the remainder of the tag name.
+pub fn instance_tag_settings(prefix: &str) -> Result<HashMap<String, String>, ConfigSourceError> {
+    let metadata = aws_instance_metadata::get().map_err(ConfigSourceError::InstanceMetadataFailed)?;
+    let tags = ebs::instance_tags(&metadata).map_err(ConfigSourceError::DescribeTagsFailed)?;
+    Ok(prefixed_tags(tags, prefix))
+}
+
+fn prefixed_tags(tags: HashMap<String, String>, prefix: &str) -> HashMap<String, String> {
+    tags.into_iter()
+        .filter(|&(ref key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (String::from(&key[prefix.len()..]), value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate hyper;
+
+    use super::*;
+    use rusoto;
+    use chrono::{Duration, UTC};
+
+    struct MockFetchMetadata {
+        user_data: Option<&'static str>,
+    }
+
+    impl FetchMetadata for MockFetchMetadata {
+        fn fetch(&self, path: &str) -> Result<Option<String>, ConfigSourceError> {
+            assert_eq!("user-data", path);
+            Ok(self.user_data.map(String::from))
+        }
+    }
+
+    struct MockProvideAwsCredentials {}
+
+    impl rusoto::ProvideAwsCredentials for MockProvideAwsCredentials {
+        fn credentials(&self) -> Result<rusoto::AwsCredentials, rusoto::CredentialsError> {
+            Ok(rusoto::AwsCredentials::new("key",
+                                           "secret",
+                                           None,
+                                           UTC::now() + Duration::seconds(600)))
+        }
+    }
+
+    struct SsmRequestDispatcherGetParameters {
+        body: &'static str,
+    }
+
+    impl rusoto::DispatchSignedRequest for SsmRequestDispatcherGetParameters {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let payload = String::from_utf8(request.payload.clone().unwrap()).unwrap();
+            assert!(payload.contains("\"Names\":[\"/cps/db\"]"));
+            assert!(payload.contains("\"WithDecryption\":true"));
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: String::from(self.body),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn parses_config_sources() {
+        assert_eq!(Ok(ConfigSource::UserData), "user-data".parse());
+        assert_eq!(Ok(ConfigSource::File(String::from("/etc/cps.yml"))), "file:/etc/cps.yml".parse());
+        assert_eq!(Ok(ConfigSource::Ssm(String::from("/cps/db"))), "ssm:/cps/db".parse());
+        assert_eq!(Ok(ConfigSource::InstanceTags(String::from("cps:"))), "instance-tags:cps:".parse());
+        assert!("ssm:".parse::<ConfigSource>().is_err());
+        assert!("s3://bucket/config.yml".parse::<ConfigSource>().is_err());
+    }
+
+    #[test]
+    fn test_read_user_data() {
+        let user_data = read_user_data(&MockFetchMetadata { user_data: Some("block-provider: {}\n") }).unwrap();
+        assert_eq!("block-provider: {}\n", user_data);
+        match read_user_data(&MockFetchMetadata { user_data: None }) {
+            Err(ConfigSourceError::UserDataMissing) => {}
+            other => panic!("expected missing user-data, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_get_parameter() {
+        let ssm_client = SsmClient::new(SsmRequestDispatcherGetParameters {
+                                            body: "{\"InvalidParameters\":[],\"Parameters\":[{\"Name\":\"/cps/db\",\
+                                                   \"Type\":\"String\",\"Value\":\"mount:\\n  target: /data\\n\"}]}",
+                                        },
+                                        MockProvideAwsCredentials {},
+                                        rusoto::Region::UsWest2);
+        assert_eq!("mount:\n  target: /data\n", get_parameter("/cps/db", &ssm_client).unwrap());
+
+        let ssm_client = SsmClient::new(SsmRequestDispatcherGetParameters {
+                                            body: "{\"InvalidParameters\":[\"/cps/db\"],\"Parameters\":[]}",
+                                        },
+                                        MockProvideAwsCredentials {},
+                                        rusoto::Region::UsWest2);
+        match get_parameter("/cps/db", &ssm_client) {
+            Err(ConfigSourceError::ParameterMissing(ref name)) if name == "/cps/db" => {}
+            other => panic!("expected missing parameter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn filters_prefixed_tags() {
+        let mut tags = HashMap::new();
+        tags.insert(String::from("Name"), String::from("db-1"));
+        tags.insert(String::from("cps:size"), String::from("100"));
+        tags.insert(String::from("cps:tag"), String::from("Role=PostgreSQL"));
+        let settings = prefixed_tags(tags, "cps:");
+        assert_eq!(2, settings.len());
+        assert_eq!(Some(&String::from("100")), settings.get("size"));
+        assert_eq!(Some(&String::from("Role=PostgreSQL")), settings.get("tag"));
+    }
+}
@@ -3,9 +3,45 @@ use std;
 use aws_instance_metadata;
 use rusoto::{DefaultCredentialsProvider, ProvideAwsCredentials, DispatchSignedRequest};
 use rusoto::ec2::{Ec2Client, DescribeVolumesRequest, DescribeVolumesError, Filter,
-                  AttachVolumeRequest, CreateVolumeRequest, CreateTagsRequest, Tag};
+                  AttachVolumeRequest, CreateVolumeRequest, CreateTagsRequest, DetachVolumeRequest, Tag,
+                  Volume, DescribeTagsRequest, DescribeTagsError, DescribeInstancesRequest, DescribeInstancesError,
+                  DescribeSnapshotsRequest, DescribeSnapshotsError, DeleteTagsRequest, DeleteVolumeRequest,
+                  CreateSnapshotRequest, DeleteSnapshotRequest};
 use rusoto::default_tls_client;
-use config::EbsBlockProviderConfig;
+use hyper;
+use aws_instance_metadata::metadata::InstanceMetadata;
+use aws_instance_metadata::myerr::MetadataRetrievalError;
+use chrono::{Datelike, DateTime, Duration, UTC};
+use std::collections::{BTreeMap, HashMap};
+use config::{self, EbsBlockProviderConfig, EbsTimeouts, FingerprintMismatch, VolumePool, VolumeSelection};
+use retry::{is_throttled, poll_until, retry_throttled};
+
+/// Tag identifying the set that a multi-member (eg. LVM striped) volume belongs to.
+pub const SET_ID_TAG: &'static str = "cps:set-id";
+/// Tag recording a volume's zero-based position within its set.
+pub const SET_MEMBER_TAG: &'static str = "cps:set-member";
+/// Tag recording when `gc` first found a volume available, in RFC 3339; it's removed when the volume is attached.
+pub const AVAILABLE_SINCE_TAG: &'static str = "cps:available-since";
+/// Tag recording when the `snapshot` command snapshotted a volume, in RFC 3339; the snapshots of a volume set's
+/// members all have the same time.
+pub const SNAPSHOT_TIME_TAG: &'static str = "cps:snapshot-time";
+/// Tag identifying the volume, or volume set, that the `snapshot` command snapshotted; retention is applied to each
+/// separately.
+pub const SNAPSHOT_OF_TAG: &'static str = "cps:snapshot-of";
+/// Tag recording the instance that created a volume.
+pub const CREATED_BY_TAG: &'static str = "cps:created-by";
+/// Tags recording the instance that a volume was most recently attached to, and when, in RFC 3339.
+pub const LAST_ATTACHED_INSTANCE_TAG: &'static str = "cps:last-attached-instance";
+pub const LAST_ATTACHED_AT_TAG: &'static str = "cps:last-attached-at";
+/// Tag recording the hash of the configuration that a volume was most recently attached with; see
+/// `config::Volume::config_hash`.
+pub const CONFIG_HASH_TAG: &'static str = "cps:config-hash";
+/// Tag recording the configuration fingerprint that a volume was created with; see
+/// `config::Volume::config_fingerprint`.
+pub const CONFIG_FINGERPRINT_TAG: &'static str = "cps:config-fingerprint";
+/// Tag recording the instance that created a volume for the pool with the `pool` command.  Pooled volumes that have
+/// never been attached aren't deleted by gc.
+pub const POOLED_BY_TAG: &'static str = "cps:pooled-by";
 
 #[derive(Debug)]
 pub enum AttachVolumeError {
@@ -13,9 +49,68 @@ pub enum AttachVolumeError {
     AllAttachesFailed,
     DescribeVolumesFailed(DescribeVolumesError),
     DescribeVolumesPaginationSupportRequired,
+    DescribeSnapshotsFailed(DescribeSnapshotsError),
     TimeoutWaitingForVolumeToAttach,
     CreatingVolumeFailed(CreateVolumeError),
     AttachingCreatedVolumeFailed(rusoto::ec2::AttachVolumeError),
+    /// This instance isn't permitted to attach volumes; no other volume would fare better.
+    AttachPermissionDenied(rusoto::ec2::AttachVolumeError),
+    /// AttachVolume was still throttled after retrying.
+    AttachThrottled(rusoto::ec2::AttachVolumeError),
+    /// Every block device that the volume could be attached at is already in use on this instance.
+    BlockDeviceInUse(rusoto::ec2::AttachVolumeError),
+    /// An available volume, whose id is given, was created with a different configuration fingerprint, which is
+    /// also given.
+    FingerprintMismatch(String, String),
+}
+
+/// Why an AttachVolume request failed, from the error code in EC2's response.
+#[derive(Debug, PartialEq)]
+pub enum AttachFailure {
+    /// The volume was attached by another instance after it was described, eg. by an instance booting at the same
+    /// time.
+    VolumeInUse,
+    /// The volume isn't `available`, eg. it's still being created or detached.
+    IncorrectState,
+    /// The volume is in a different availability zone than this instance.
+    ZoneMismatch,
+    /// Another volume is already attached to this instance at the requested block device.
+    DeviceInUse,
+    PermissionDenied,
+    Throttled,
+    Other,
+}
+
+/// Classifies an AttachVolume error.  rusoto reports EC2's error responses as unknown errors carrying the response
+/// body, so the error code is matched in its text.
+pub fn classify_attach_error(err: &rusoto::ec2::AttachVolumeError) -> AttachFailure {
+    let err_text = format!("{:?}", err);
+    if err_text.contains("UnauthorizedOperation") {
+        AttachFailure::PermissionDenied
+    } else if err_text.contains("VolumeInUse") {
+        AttachFailure::VolumeInUse
+    } else if err_text.contains("IncorrectState") {
+        AttachFailure::IncorrectState
+    } else if err_text.contains("InvalidVolume.ZoneMismatch") {
+        AttachFailure::ZoneMismatch
+    } else if err_text.contains("InvalidParameterValue") && err_text.contains("already in use") {
+        AttachFailure::DeviceInUse
+    } else if is_throttled(err) {
+        AttachFailure::Throttled
+    } else {
+        AttachFailure::Other
+    }
+}
+
+/// The error to fail with when an attach fails in a way that attempting other volumes wouldn't avoid, or how it
+/// failed if another volume might still attach.
+fn fatal_attach_error(err: rusoto::ec2::AttachVolumeError) -> Result<AttachFailure, AttachVolumeError> {
+    match classify_attach_error(&err) {
+        AttachFailure::PermissionDenied => Err(AttachVolumeError::AttachPermissionDenied(err)),
+        AttachFailure::Throttled => Err(AttachVolumeError::AttachThrottled(err)),
+        AttachFailure::DeviceInUse => Err(AttachVolumeError::BlockDeviceInUse(err)),
+        failure => Ok(failure),
+    }
 }
 
 impl From<rusoto::ec2::DescribeVolumesError> for AttachVolumeError {
@@ -24,6 +119,12 @@ impl From<rusoto::ec2::DescribeVolumesError> for AttachVolumeError {
     }
 }
 
+impl From<DescribeSnapshotsError> for AttachVolumeError {
+    fn from(err: DescribeSnapshotsError) -> AttachVolumeError {
+        AttachVolumeError::DescribeSnapshotsFailed(err)
+    }
+}
+
 impl From<CreateVolumeError> for AttachVolumeError {
     fn from(err: CreateVolumeError) -> AttachVolumeError {
         AttachVolumeError::CreatingVolumeFailed(err)
@@ -56,7 +157,247 @@ impl From<rusoto::ec2::DescribeVolumesError> for CreateVolumeError {
     }
 }
 
-pub fn create_filters(config: &EbsBlockProviderConfig) -> Vec<Filter> {
+#[derive(Debug)]
+pub enum DetachVolumeError {
+    DescribeVolumesFailed(DescribeVolumesError),
+    DetachingVolumeFailed(rusoto::ec2::DetachVolumeError),
+    TimeoutWaitingForVolumeToDetach,
+}
+
+impl From<rusoto::ec2::DescribeVolumesError> for DetachVolumeError {
+    fn from(err: rusoto::ec2::DescribeVolumesError) -> DetachVolumeError {
+        DetachVolumeError::DescribeVolumesFailed(err)
+    }
+}
+
+impl From<rusoto::ec2::DetachVolumeError> for DetachVolumeError {
+    fn from(err: rusoto::ec2::DetachVolumeError) -> DetachVolumeError {
+        DetachVolumeError::DetachingVolumeFailed(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum GcError {
+    DescribeVolumesFailed(DescribeVolumesError),
+    TaggingVolumesFailed(rusoto::ec2::CreateTagsError),
+    UntaggingVolumesFailed(rusoto::ec2::DeleteTagsError),
+    CreatingSnapshotFailed(rusoto::ec2::CreateSnapshotError),
+    TaggingSnapshotFailed(rusoto::ec2::CreateTagsError),
+    DeletingVolumeFailed(rusoto::ec2::DeleteVolumeError),
+}
+
+impl From<rusoto::ec2::DescribeVolumesError> for GcError {
+    fn from(err: rusoto::ec2::DescribeVolumesError) -> GcError {
+        GcError::DescribeVolumesFailed(err)
+    }
+}
+
+impl From<rusoto::ec2::DeleteTagsError> for GcError {
+    fn from(err: rusoto::ec2::DeleteTagsError) -> GcError {
+        GcError::UntaggingVolumesFailed(err)
+    }
+}
+
+impl From<rusoto::ec2::CreateSnapshotError> for GcError {
+    fn from(err: rusoto::ec2::CreateSnapshotError) -> GcError {
+        GcError::CreatingSnapshotFailed(err)
+    }
+}
+
+impl From<rusoto::ec2::DeleteVolumeError> for GcError {
+    fn from(err: rusoto::ec2::DeleteVolumeError) -> GcError {
+        GcError::DeletingVolumeFailed(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// A volume to snapshot was described without its id.
+    MissingVolumeId,
+    /// CreateSnapshot for the given volume succeeded without returning a snapshot id.
+    MissingSnapshotId(String),
+    CreatingSnapshotFailed(rusoto::ec2::CreateSnapshotError),
+    TaggingSnapshotFailed(rusoto::ec2::CreateTagsError),
+    DescribeSnapshotsFailed(DescribeSnapshotsError),
+    DeletingSnapshotFailed(rusoto::ec2::DeleteSnapshotError),
+}
+
+impl From<rusoto::ec2::CreateSnapshotError> for SnapshotError {
+    fn from(err: rusoto::ec2::CreateSnapshotError) -> SnapshotError {
+        SnapshotError::CreatingSnapshotFailed(err)
+    }
+}
+
+impl From<rusoto::ec2::CreateTagsError> for SnapshotError {
+    fn from(err: rusoto::ec2::CreateTagsError) -> SnapshotError {
+        SnapshotError::TaggingSnapshotFailed(err)
+    }
+}
+
+impl From<DescribeSnapshotsError> for SnapshotError {
+    fn from(err: DescribeSnapshotsError) -> SnapshotError {
+        SnapshotError::DescribeSnapshotsFailed(err)
+    }
+}
+
+impl From<rusoto::ec2::DeleteSnapshotError> for SnapshotError {
+    fn from(err: rusoto::ec2::DeleteSnapshotError) -> SnapshotError {
+        SnapshotError::DeletingSnapshotFailed(err)
+    }
+}
+
+/// An EC2 client for this instance's region, using the default credentials.
+pub type InstanceEc2Client = Ec2Client<DefaultCredentialsProvider, hyper::Client>;
+
+#[derive(Debug)]
+pub enum Ec2ClientError {
+    InstanceMetadataFailed(MetadataRetrievalError),
+    InvalidRegion(rusoto::ParseRegionError),
+    TlsClientFailed(rusoto::TlsError),
+    CredentialsFailed(rusoto::CredentialsError),
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    DescribeVolumesFailed(DescribeVolumesError),
+    DescribeSnapshotsFailed(DescribeSnapshotsError),
+    /// The snapshot, and the fingerprint of the volume it was taken of.
+    SnapshotFingerprintMismatch(String, String),
+    CreatingVolumeFailed(CreateVolumeError),
+}
+
+impl From<rusoto::ec2::DescribeVolumesError> for PoolError {
+    fn from(err: rusoto::ec2::DescribeVolumesError) -> PoolError {
+        PoolError::DescribeVolumesFailed(err)
+    }
+}
+
+impl From<DescribeSnapshotsError> for PoolError {
+    fn from(err: DescribeSnapshotsError) -> PoolError {
+        PoolError::DescribeSnapshotsFailed(err)
+    }
+}
+
+impl From<CreateVolumeError> for PoolError {
+    fn from(err: CreateVolumeError) -> PoolError {
+        PoolError::CreatingVolumeFailed(err)
+    }
+}
+
+/// What the configuration says about the volumes it attaches, beyond their EBS settings.
+#[derive(Debug)]
+pub struct VolumeIdentity {
+    /// `config::Volume::config_fingerprint`, recorded on created volumes and compared with available volumes'.
+    pub fingerprint: String,
+    /// `config::Volume::config_hash`, recorded on attached volumes.
+    pub config_hash: String,
+}
+
+/// The volumes that were attached, as (block device, volume id) pairs, and whether they were newly created.
+#[derive(Debug)]
+pub struct AttachedVolumes {
+    pub volumes: Vec<(String, String)>,
+    pub created: bool,
+}
+
+/// What attaching a volume, or volume set, would do given the volumes that are currently available.
+#[derive(Debug, PartialEq)]
+pub enum AttachPlan {
+    /// Existing volumes would be attached; each candidate is a list of (block device, volume id) pairs, tried in
+    /// order until one attaches.
+    AttachExisting(Vec<Vec<(String, String)>>),
+    /// No volume is available, so new volumes would be created in the instance's availability zone.
+    CreateNew { availability_zone: String },
+}
+
+/// How `collect_garbage` chooses and deletes unattached volumes.
+#[derive(Debug)]
+pub struct GcOptions {
+    /// How long a volume must have been available before it's deleted.
+    pub retention: Duration,
+    /// The number of available volumes (or volume sets) to leave undeleted, even if they're past `retention`.
+    pub keep: usize,
+    /// Whether to snapshot each volume before deleting it.
+    pub snapshot: bool,
+    /// Whether to only report what would be done, without tagging, snapshotting or deleting anything.
+    pub dry_run: bool,
+}
+
+/// What `collect_garbage` did, or would do, with an available volume.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GcAction {
+    /// Found available for the first time, and tagged with `AVAILABLE_SINCE_TAG`.
+    Marked,
+    /// Available for less than the retention period, or a member of a set that isn't entirely available.
+    Retained,
+    /// Past the retention period, but kept to leave the minimum number of volumes available.
+    Kept,
+    /// Deleted, after being snapshotted if a snapshot id is given.
+    Deleted { snapshot_id: Option<String> },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GcVolume {
+    pub volume_id: String,
+    pub available_since: DateTime<UTC>,
+    pub action: GcAction,
+}
+
+/// The pool in one availability zone, as `fill_pool` found and filled it.
+#[derive(Debug, PartialEq)]
+pub struct PoolZone {
+    pub availability_zone: String,
+    /// Available volumes, or complete volume sets, that were already in the zone.
+    pub available: usize,
+    /// Volumes, or volume sets, that were created to fill the pool, or would be with `dry_run`.
+    pub created: usize,
+    /// Ids of the created volumes.
+    pub volume_ids: Vec<String>,
+}
+
+/// How many of the `snapshot` command's snapshots of a volume are kept: the most recent snapshot in each of the last
+/// `hourly` hours that have a snapshot, and likewise for days and weeks (in UTC, with ISO weeks starting on Monday).
+/// A snapshot kept for one period also counts towards the others.
+#[derive(Debug, Default)]
+pub struct SnapshotRetention {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl SnapshotRetention {
+    /// Whether no retention is given, in which case no snapshots are deleted.
+    pub fn is_empty(&self) -> bool {
+        self.hourly == 0 && self.daily == 0 && self.weekly == 0
+    }
+}
+
+/// Result of an EC2 API call made with `DryRun` set, which checks whether the caller is permitted to make the call
+/// without performing it.
+#[derive(Debug, PartialEq)]
+pub enum PermissionCheck {
+    Allowed,
+    Denied,
+    /// The call couldn't be made, eg. because there are no credentials, EC2 is unreachable, or the request was
+    /// throttled, so the real call would fail too; the error is included.
+    Failed(String),
+    /// EC2 rejected the placeholder volume id the call was made with, so permission for it is unknown; the error
+    /// response is included.
+    Unverified(String),
+}
+
+fn instance_metadata() -> InstanceMetadata {
+    match aws_instance_metadata::get() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Unable to retrieve instance metadata.  Am I running on EC2?  {:?}",
+                   e);
+            std::process::exit(100);
+        }
+    }
+}
+
+fn tag_filters(config: &EbsBlockProviderConfig) -> Vec<Filter> {
     let mut filters = Vec::with_capacity(config.ebs_tags.len() + 1);
     for (tag_name, tag_value) in &config.ebs_tags {
         filters.push(Filter {
@@ -64,6 +405,11 @@ pub fn create_filters(config: &EbsBlockProviderConfig) -> Vec<Filter> {
                          values: Some(vec![tag_value.to_owned()]),
                      })
     }
+    filters
+}
+
+pub fn create_filters(config: &EbsBlockProviderConfig) -> Vec<Filter> {
+    let mut filters = tag_filters(config);
     filters.push(Filter {
                      name: Some("status".to_owned()),
                      values: Some(vec!["available".to_owned()]),
@@ -71,36 +417,439 @@ pub fn create_filters(config: &EbsBlockProviderConfig) -> Vec<Filter> {
     filters
 }
 
-pub fn find_and_attach_volume(block_device: &str,
-                              config: &EbsBlockProviderConfig)
-                              -> Result<(), AttachVolumeError> {
-    let metadata = match aws_instance_metadata::get() {
-        Ok(metadata) => metadata,
-        Err(e) => {
-            error!("Unable to retrieve instance metadata.  Am I running on EC2?  {:?}",
-                   e);
-            std::process::exit(100);
-        }
+/// Attaches a volume at the first of `block_devices` that isn't already in use on this instance.  Only volumes created
+/// with `identity`'s fingerprint, or before fingerprints were recorded, are attached, and the attached volume is
+/// tagged with this instance and `identity`'s config hash.
+pub fn find_and_attach_volume(block_devices: &[String],
+                              config: &EbsBlockProviderConfig,
+                              identity: &VolumeIdentity)
+                              -> Result<AttachedVolumes, AttachVolumeError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+
+    let instance_id = metadata.instance_id.as_str();
+    let mut device = 0;
+    let fingerprint = identity.fingerprint.as_str();
+    let attached =
+        attach_to_existing_volume(instance_id, block_devices, &mut device, config, fingerprint, &ec2_client)
+            .or_else(|e| {
+                create_and_attach_if_advisable(&ec2_client,
+                                               config,
+                                               fingerprint,
+                                               metadata.availability_zone.as_str(),
+                                               block_devices,
+                                               &mut device,
+                                               instance_id,
+                                               e)
+            })?;
+    tag_attached_volumes(&attached, instance_id, identity, &config.timeouts, &ec2_client);
+    Ok(attached)
+}
+
+/// Attaches a set of volumes, one per block device, that were created together.  Only sets with every member
+/// available are attached unless `allow_partial` is set, in which case incomplete sets are attached when no complete
+/// set is available; each member is always attached to the block device matching its position in the set.  Volumes
+/// are checked and tagged as by `find_and_attach_volume`.
+pub fn find_and_attach_volume_set(block_devices: &[String],
+                                  config: &EbsBlockProviderConfig,
+                                  allow_partial: bool,
+                                  identity: &VolumeIdentity)
+                                  -> Result<AttachedVolumes, AttachVolumeError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+
+    let instance_id = metadata.instance_id.as_str();
+    let fingerprint = identity.fingerprint.as_str();
+    let attached =
+        attach_to_existing_volume_set(instance_id, block_devices, config, fingerprint, allow_partial, &ec2_client)
+            .or_else(|e| match e {
+                AttachVolumeError::NoVolumesAvailable |
+                AttachVolumeError::AllAttachesFailed => {
+                    info!("no complete volume set is available for attaching; creating a new volume set");
+                    create_and_attach_volume_set(&ec2_client,
+                                                 config,
+                                                 fingerprint,
+                                                 metadata.availability_zone.as_str(),
+                                                 block_devices,
+                                                 instance_id)
+                }
+                _ => Err(e),
+            })?;
+    tag_attached_volumes(&attached, instance_id, identity, &config.timeouts, &ec2_client);
+    Ok(attached)
+}
+
+/// Describes every volume matching the configured tags, in any state.
+pub fn list_volumes(config: &EbsBlockProviderConfig) -> Result<Vec<Volume>, DescribeVolumesError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    describe_volumes(tag_filters(config), &config.timeouts, &ec2_client)
+}
+
+/// Describes the volumes matching the configured tags that are attached to this instance at `block_devices`.
+pub fn list_attached_volumes(block_devices: &[String],
+                             config: &EbsBlockProviderConfig)
+                             -> Result<Vec<Volume>, DescribeVolumesError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    describe_volumes(attached_filters(metadata.instance_id.as_str(), block_devices, config),
+                     &config.timeouts,
+                     &ec2_client)
+}
+
+/// Finds the volumes matching the configured tags that are already attached to this instance at `block_devices`, eg.
+/// by an earlier `attach`, so that attaching again reuses them rather than attaching more volumes.
+pub fn find_attached_volumes(block_devices: &[String],
+                             config: &EbsBlockProviderConfig)
+                             -> Result<Option<AttachedVolumes>, DescribeVolumesError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    attached_volumes(metadata.instance_id.as_str(), block_devices, config, &ec2_client)
+}
+
+fn attached_volumes<P, D>(instance_id: &str,
+                          block_devices: &[String],
+                          config: &EbsBlockProviderConfig,
+                          ec2_client: &Ec2Client<P, D>)
+                          -> Result<Option<AttachedVolumes>, DescribeVolumesError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let volumes = describe_volumes(attached_filters(instance_id, block_devices, config),
+                                   &config.timeouts,
+                                   ec2_client)?;
+    if volumes.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(AttachedVolumes {
+                volumes: attached_devices(&volumes),
+                created: false,
+            }))
+}
+
+/// The (block device, volume id) pairs of volumes described as attached to this instance, with members of a volume
+/// set in member order.
+pub fn attached_devices(volumes: &[Volume]) -> Vec<(String, String)> {
+    let mut attached: Vec<(usize, String, String)> = volumes.iter()
+        .map(|vol| {
+            let member = tag_value(vol, SET_MEMBER_TAG).and_then(|m| m.parse::<usize>().ok()).unwrap_or(0);
+            let device = vol.attachments
+                .as_ref()
+                .and_then(|attachments| attachments.get(0))
+                .and_then(|attachment| attachment.device.to_owned())
+                .unwrap_or_default();
+            (member, device, vol.volume_id.to_owned().unwrap_or_default())
+        })
+        .collect();
+    attached.sort_by(|a, b| a.0.cmp(&b.0));
+    attached.into_iter().map(|(_, device, volume_id)| (device, volume_id)).collect()
+}
+
+/// Detaches the volumes matching the configured tags that are attached to this instance at `block_devices`, and
+/// waits for them to become available to other instances.  Returns the number of volumes detached.
+pub fn detach_volumes(block_devices: &[String], config: &EbsBlockProviderConfig) -> Result<usize, DetachVolumeError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+
+    let instance_id = metadata.instance_id.as_str();
+    let volumes = describe_volumes(attached_filters(instance_id, block_devices, config),
+                                   &config.timeouts,
+                                   &ec2_client)?;
+    for vol in &volumes {
+        let volume_id = vol.volume_id.as_ref().unwrap();
+        info!("detaching volume {}", volume_id);
+        detach_specific_volume(instance_id, volume_id, &config.timeouts, &ec2_client)?;
+    }
+    for vol in &volumes {
+        ensure_volume_detached(&ec2_client, vol.volume_id.as_ref().unwrap(), &config.timeouts)?;
+    }
+    Ok(volumes.len())
+}
+
+/// Deletes the volumes matching the configured tags that have been available for longer than `options.retention`.
+/// EC2 doesn't record when a volume was detached, so the first time a volume is found available it's tagged with the
+/// current time, and its retention period starts then; the tag is removed when the volume is attached again.
+pub fn collect_garbage(config: &EbsBlockProviderConfig, options: &GcOptions) -> Result<Vec<GcVolume>, GcError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    collect_volume_garbage(config, options, UTC::now(), &ec2_client)
+}
+
+/// Builds an EC2 client for this instance's region, reporting failures as errors rather than exiting, for callers
+/// that have to clean up after a failure, such as a frozen filesystem that must be thawed.
+pub fn instance_ec2_client() -> Result<InstanceEc2Client, Ec2ClientError> {
+    let metadata = aws_instance_metadata::get().map_err(Ec2ClientError::InstanceMetadataFailed)?;
+    let region = metadata.region().map_err(Ec2ClientError::InvalidRegion)?;
+    let tls_client = default_tls_client().map_err(Ec2ClientError::TlsClientFailed)?;
+    let credentials = DefaultCredentialsProvider::new().map_err(Ec2ClientError::CredentialsFailed)?;
+    Ok(Ec2Client::new(tls_client, credentials, region))
+}
+
+/// Deletes the `snapshot` command's snapshots of `volumes` that `retention` doesn't keep, and returns their ids.
+pub fn prune_snapshots<P, D>(volumes: &[Volume],
+                             retention: &SnapshotRetention,
+                             config: &EbsBlockProviderConfig,
+                             ec2_client: &Ec2Client<P, D>)
+                             -> Result<Vec<String>, SnapshotError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let mut snapshots_of: Vec<String> = volumes.iter().map(snapshot_of).collect();
+    snapshots_of.sort();
+    snapshots_of.dedup();
+    let mut deleted = Vec::new();
+    for of in snapshots_of {
+        deleted.extend(delete_expired_snapshots(&of, retention, &config.timeouts, ec2_client)?);
+    }
+    Ok(deleted)
+}
+
+/// Creates volumes (or, if `members` is more than one, volume sets) in each of the pool's availability zones until
+/// each has `pool.size` of them available, and waits for the new volumes to become available.  Only volumes with
+/// `fingerprint`, or without a fingerprint, are counted, since only they can be attached.
+pub fn fill_pool(config: &EbsBlockProviderConfig,
+                 pool: &VolumePool,
+                 fingerprint: &str,
+                 members: usize,
+                 dry_run: bool)
+                 -> Result<Vec<PoolZone>, PoolError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    let availability_zones = if pool.availability_zones.is_empty() {
+        vec![metadata.availability_zone.to_owned()]
+    } else {
+        pool.availability_zones.to_owned()
+    };
+    let request = PoolRequest {
+        availability_zones: &availability_zones,
+        size: pool.size as usize,
+        snapshot_id: pool.snapshot_id.as_ref().map(|id| id.as_str()),
+        fingerprint: fingerprint,
+        members: members,
+    };
+    fill_volume_pool(config, &request, metadata.instance_id.as_str(), dry_run, &ec2_client)
+}
+
+/// Determines what `find_and_attach_volume` (if `volume_set` is false) or `find_and_attach_volume_set` would do,
+/// without attaching or creating anything.
+pub fn plan_attach(block_devices: &[String],
+                   config: &EbsBlockProviderConfig,
+                   fingerprint: &str,
+                   volume_set: bool,
+                   allow_partial: bool)
+                   -> Result<AttachPlan, AttachVolumeError> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+
+    let volumes = layout_candidates(describe_available_volumes(config, &ec2_client)?, volume_set);
+    let volumes = matching_fingerprint(volumes, fingerprint, config)?;
+    let candidates = attach_candidates(&volumes, block_devices, volume_set, allow_partial);
+    if candidates.is_empty() {
+        Ok(AttachPlan::CreateNew { availability_zone: metadata.availability_zone.to_owned() })
+    } else {
+        Ok(AttachPlan::AttachExisting(candidates))
+    }
+}
+
+/// Issues `DryRun` requests for the EC2 calls that carrying out `plan` would make, to verify that this instance's
+/// credentials permit them.  Calls that need a volume id use the first planned volume, or a placeholder if new
+/// volumes would be created.
+pub fn check_attach_permissions(block_devices: &[String],
+                                config: &EbsBlockProviderConfig,
+                                plan: &AttachPlan)
+                                -> Vec<(&'static str, PermissionCheck)> {
+    let metadata = instance_metadata();
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    let volume_id = match *plan {
+        AttachPlan::AttachExisting(ref candidates) => candidates[0][0].1.to_owned(),
+        AttachPlan::CreateNew { .. } => String::from(PLACEHOLDER_VOLUME_ID),
     };
-    let credentials = DefaultCredentialsProvider::new().unwrap();
+    dry_run_attach_calls(metadata.instance_id.as_str(),
+                         metadata.availability_zone.as_str(),
+                         &block_devices[0],
+                         volume_id.as_str(),
+                         config,
+                         &ec2_client)
+}
 
+/// Issues `DryRun` requests for every EC2 call this tool makes, to verify that this instance's credentials permit
+/// them.  Calls that need a volume id use a volume matching the configured tags if there is one, or a placeholder.
+pub fn check_all_permissions(block_device: &str,
+                             config: &EbsBlockProviderConfig)
+                             -> Result<Vec<(&'static str, PermissionCheck)>, MetadataRetrievalError> {
+    let metadata = aws_instance_metadata::get()?;
     let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
-                                    credentials,
+                                    DefaultCredentialsProvider::new().unwrap(),
                                     metadata.region().unwrap());
 
     let instance_id = metadata.instance_id.as_str();
-    attach_to_existing_volume(instance_id, block_device, config, &ec2_client).or_else(|e| {
-        create_and_attach_if_advisable(&ec2_client, config, metadata.availability_zone.as_str(), block_device, instance_id, e)
-    })
+    let volume_id = describe_volumes(tag_filters(config), &config.timeouts, &ec2_client)
+        .ok()
+        .and_then(|volumes| volumes.into_iter().next())
+        .and_then(|vol| vol.volume_id)
+        .unwrap_or_else(|| String::from(PLACEHOLDER_VOLUME_ID));
+
+    let mut checks = dry_run_attach_calls(instance_id,
+                                          metadata.availability_zone.as_str(),
+                                          block_device,
+                                          volume_id.as_str(),
+                                          config,
+                                          &ec2_client);
+
+    let describe_volumes = DescribeVolumesRequest {
+        dry_run: Some(true),
+        filters: None,
+        max_results: None,
+        next_token: None,
+        volume_ids: Some(vec![volume_id.to_owned()]),
+    };
+    trace!("executing DescribeVolumes with DryRun");
+    checks.push(("ec2:DescribeVolumes", dry_run_result(ec2_client.describe_volumes(&describe_volumes))));
+
+    let detach_volume = DetachVolumeRequest {
+        device: None,
+        dry_run: Some(true),
+        force: None,
+        instance_id: Some(String::from(instance_id)),
+        volume_id: volume_id,
+    };
+    trace!("executing DetachVolume with DryRun");
+    checks.push(("ec2:DetachVolume", dry_run_result(ec2_client.detach_volume(&detach_volume))));
+
+    Ok(checks)
+}
+
+/// A syntactically valid volume id that doesn't exist, used to dry-run calls when no real volume is available.
+const PLACEHOLDER_VOLUME_ID: &'static str = "vol-00000000000000000";
+
+fn dry_run_attach_calls<P, D>(instance_id: &str,
+                              availability_zone: &str,
+                              block_device: &str,
+                              volume_id: &str,
+                              config: &EbsBlockProviderConfig,
+                              ec2_client: &Ec2Client<P, D>)
+                              -> Vec<(&'static str, PermissionCheck)>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let mut checks = Vec::with_capacity(3);
+
+    let create_volume = CreateVolumeRequest {
+        availability_zone: String::from(availability_zone),
+        dry_run: Some(true),
+        encrypted: None,
+        iops: None,
+        kms_key_id: None,
+        size: Some(config.size),
+        snapshot_id: None,
+        volume_type: Some(config.volume_type.to_owned()),
+    };
+    trace!("executing CreateVolume with DryRun");
+    checks.push(("ec2:CreateVolume", dry_run_result(ec2_client.create_volume(&create_volume))));
+
+    let create_tags = CreateTagsRequest {
+        dry_run: Some(true),
+        resources: vec![String::from(volume_id)],
+        tags: config.ebs_tags
+            .iter()
+            .map(|(tag_name, tag_value)| {
+                Tag {
+                    key: Some(tag_name.to_owned()),
+                    value: Some(tag_value.to_owned()),
+                }
+            })
+            .collect(),
+    };
+    trace!("executing CreateTags with DryRun");
+    checks.push(("ec2:CreateTags", dry_run_result(ec2_client.create_tags(&create_tags))));
+
+    let attach_volume = AttachVolumeRequest {
+        device: String::from(block_device),
+        dry_run: Some(true),
+        instance_id: String::from(instance_id),
+        volume_id: String::from(volume_id),
+    };
+    trace!("executing AttachVolume with DryRun");
+    checks.push(("ec2:AttachVolume", dry_run_result(ec2_client.attach_volume(&attach_volume))));
+
+    checks
+}
+
+/// EC2 responds to a permitted `DryRun` request with a `DryRunOperation` error, and to a forbidden one with
+/// `UnauthorizedOperation`.  rusoto reports both as unknown errors carrying the response body.  EC2 checks that a
+/// volume exists before the caller's permissions, so a call made with `PLACEHOLDER_VOLUME_ID` fails with
+/// `InvalidVolume.NotFound` instead; any other error means the call itself can't be made.
+fn dry_run_result<T, E>(result: Result<T, E>) -> PermissionCheck
+    where E: std::fmt::Debug
+{
+    match result {
+        Ok(_) => PermissionCheck::Allowed,
+        Err(e) => {
+            let err_text = format!("{:?}", e);
+            if err_text.contains("DryRunOperation") {
+                PermissionCheck::Allowed
+            } else if err_text.contains("UnauthorizedOperation") {
+                PermissionCheck::Denied
+            } else if err_text.contains("InvalidVolume.NotFound") {
+                PermissionCheck::Unverified(err_text)
+            } else {
+                PermissionCheck::Failed(err_text)
+            }
+        }
+    }
+}
+
+fn attach_candidates(volumes: &[Volume],
+                     block_devices: &[String],
+                     volume_set: bool,
+                     allow_partial: bool)
+                     -> Vec<Vec<(String, String)>> {
+    if volume_set {
+        volume_sets(volumes, block_devices.len(), allow_partial)
+            .iter()
+            .map(|set| {
+                set.iter()
+                    .map(|(member, vol)| (block_devices[*member].to_owned(), vol.volume_id.to_owned().unwrap()))
+                    .collect()
+            })
+            .collect()
+    } else {
+        volumes.iter()
+            .filter(|vol| tag_value(vol, SET_ID_TAG).is_none())
+            .map(|vol| vec![(block_devices[0].to_owned(), vol.volume_id.to_owned().unwrap())])
+            .collect()
+    }
 }
 
 fn create_and_attach_if_advisable<P, D>(ec2_client: &Ec2Client<P, D>,
                                         config: &EbsBlockProviderConfig,
+                                        fingerprint: &str,
                                         availability_zone: &str,
-                                        block_device: &str,
+                                        block_devices: &[String],
+                                        device: &mut usize,
                                         instance_id: &str,
                                         e: AttachVolumeError)
-                                        -> Result<(), AttachVolumeError>
+                                        -> Result<AttachedVolumes, AttachVolumeError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
@@ -108,17 +857,23 @@ fn create_and_attach_if_advisable<P, D>(ec2_client: &Ec2Client<P, D>,
         AttachVolumeError::NoVolumesAvailable |
         AttachVolumeError::AllAttachesFailed => {
             info!("no existing volume is available for attaching; creating a new volume");
-            let volume_id = create_volume(availability_zone, ec2_client, config)?;
+            let volume_id =
+                create_volume(availability_zone, instance_id, Some(fingerprint), None, ec2_client, config, Vec::new())?;
             info!("waiting for volume to become available");
-            ensure_volume_available(ec2_client, volume_id.as_str())?;
+            ensure_volume_available(ec2_client, volume_id.as_str(), &config.timeouts)?;
             info!("attaching new volume");
-            match attach_specific_volume(block_device,
-                                         instance_id,
-                                         volume_id.as_str(),
-                                         ec2_client) {
+            match attach_at_free_block_device(block_devices,
+                                              device,
+                                              instance_id,
+                                              volume_id.as_str(),
+                                              &config.timeouts,
+                                              ec2_client) {
                 Ok(_) => {
-                    ensure_volume_attached(ec2_client, volume_id.as_str())?;
-                    Ok(())
+                    ensure_volume_attached(ec2_client, volume_id.as_str(), &config.timeouts)?;
+                    Ok(AttachedVolumes {
+                           volumes: vec![(block_devices[*device].to_owned(), volume_id)],
+                           created: true,
+                       })
                 }
                 Err(e) => Err(AttachVolumeError::AttachingCreatedVolumeFailed(e)),
             }
@@ -127,9 +882,68 @@ fn create_and_attach_if_advisable<P, D>(ec2_client: &Ec2Client<P, D>,
     }
 }
 
+fn create_and_attach_volume_set<P, D>(ec2_client: &Ec2Client<P, D>,
+                                     config: &EbsBlockProviderConfig,
+                                     fingerprint: &str,
+                                     availability_zone: &str,
+                                     block_devices: &[String],
+                                     instance_id: &str)
+                                     -> Result<AttachedVolumes, AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let set_id = format!("{}-{}", instance_id, UTC::now().timestamp());
+    let mut volume_ids = Vec::with_capacity(block_devices.len());
+    for member in 0..block_devices.len() {
+        let set_tags = set_member_tags(&set_id, member);
+        match create_volume(availability_zone, instance_id, Some(fingerprint), None, ec2_client, config, set_tags) {
+            Ok(volume_id) => volume_ids.push(volume_id),
+            Err(err) => {
+                // an incomplete set would never be attached, so only its members' storage would be left behind
+                delete_created_volumes(&volume_ids, &config.timeouts, ec2_client);
+                return Err(AttachVolumeError::from(err));
+            }
+        }
+    }
+    info!("created volume set {}: {:?}", set_id, volume_ids);
+
+    info!("waiting for volume set to become available");
+    for volume_id in &volume_ids {
+        ensure_volume_available(ec2_client, volume_id, &config.timeouts)?;
+    }
+
+    info!("attaching new volume set");
+    for (block_device, volume_id) in block_devices.iter().zip(&volume_ids) {
+        attach_specific_volume(block_device, instance_id, volume_id, &config.timeouts, ec2_client)
+            .map_err(AttachVolumeError::AttachingCreatedVolumeFailed)?;
+    }
+    for volume_id in &volume_ids {
+        ensure_volume_attached(ec2_client, volume_id, &config.timeouts)?;
+    }
+    Ok(AttachedVolumes {
+           volumes: block_devices.iter().cloned().zip(volume_ids).collect(),
+           created: true,
+       })
+}
+
+fn set_member_tags(set_id: &str, member: usize) -> Vec<Tag> {
+    vec![Tag {
+             key: Some(String::from(SET_ID_TAG)),
+             value: Some(String::from(set_id)),
+         },
+         Tag {
+             key: Some(String::from(SET_MEMBER_TAG)),
+             value: Some(member.to_string()),
+         }]
+}
+
 fn create_volume<P, D>(availability_zone: &str,
+                       instance_id: &str,
+                       fingerprint: Option<&str>,
+                       snapshot_id: Option<&str>,
                        ec2_client: &Ec2Client<P, D>,
-                       config: &EbsBlockProviderConfig)
+                       config: &EbsBlockProviderConfig,
+                       extra_tags: Vec<Tag>)
                        -> Result<String, CreateVolumeError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
@@ -141,37 +955,115 @@ fn create_volume<P, D>(availability_zone: &str,
         iops: None,
         kms_key_id: None,
         size: Some(config.size),
-        snapshot_id: None,
+        snapshot_id: snapshot_id.map(String::from),
         volume_type: Some(config.volume_type.to_owned()),
     };
-    let volume = ec2_client.create_volume(&request)?;
+    let volume = retry_throttled(config.timeouts.throttle_retries,
+                                 || ec2_client.create_volume(&request))?;
     trace!("created volume: {:?}", volume);
     let volume_id = volume.volume_id.unwrap();
 
-    let mut tags = Vec::with_capacity(config.ebs_tags.len());
+    let mut tags = extra_tags;
     for (tag_name, tag_value) in &config.ebs_tags {
         tags.push(Tag {
                       key: Some(tag_name.to_owned()),
                       value: Some(tag_value.to_owned()),
                   });
     }
-
-    let create_tags = CreateTagsRequest {
-        dry_run: None,
-        resources: vec![volume_id.to_owned()],
-        tags: tags,
-    };
-    ec2_client.create_tags(&create_tags)?;
-    // FIXME: should attempt to delete volume if create tags failed
+    tags.push(Tag {
+                  key: Some(String::from(CREATED_BY_TAG)),
+                  value: Some(String::from(instance_id)),
+              });
+    if let Some(fingerprint) = fingerprint {
+        tags.push(Tag {
+                      key: Some(String::from(CONFIG_FINGERPRINT_TAG)),
+                      value: Some(String::from(fingerprint)),
+                  });
+    }
+    if let Err(err) = tag_resources(vec![volume_id.to_owned()], tags, &config.timeouts, ec2_client) {
+        // without its tags the volume would never be found again
+        delete_created_volumes(&[volume_id], &config.timeouts, ec2_client);
+        return Err(CreateVolumeError::from(err));
+    }
 
     Ok(volume_id)
 }
 
-fn attach_to_existing_volume<P, D>(instance_id: &str,
-                                   block_device: &str,
-                                   config: &EbsBlockProviderConfig,
+/// Deletes volumes that were created but can't be used, once they're available, since EC2 only deletes available
+/// volumes.  Failures are only logged, since the error that made the volumes unusable is reported either way.
+fn delete_created_volumes<P, D>(volume_ids: &[String], timeouts: &EbsTimeouts, ec2_client: &Ec2Client<P, D>)
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    for volume_id in volume_ids {
+        info!("deleting created volume {}", volume_id);
+        if let Err(e) = ensure_volume_available(ec2_client, volume_id, timeouts) {
+            warn!("failed to delete created volume {}: {:?}", volume_id, e);
+            continue;
+        }
+        let request = DeleteVolumeRequest {
+            dry_run: None,
+            volume_id: volume_id.to_owned(),
+        };
+        if let Err(e) = retry_throttled(timeouts.throttle_retries, || ec2_client.delete_volume(&request)) {
+            warn!("failed to delete created volume {}: {:?}", volume_id, e);
+        }
+    }
+}
+
+fn tag_resources<P, D>(resources: Vec<String>,
+                       tags: Vec<Tag>,
+                       timeouts: &EbsTimeouts,
+                       ec2_client: &Ec2Client<P, D>)
+                       -> Result<(), rusoto::ec2::CreateTagsError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = CreateTagsRequest {
+        dry_run: None,
+        resources: resources,
+        tags: tags,
+    };
+    retry_throttled(timeouts.throttle_retries, || ec2_client.create_tags(&request))
+}
+
+/// Tags attached volumes with this instance, the time, and the configuration they were attached with, for auditing.
+/// Volumes are only attached if they have `identity`'s fingerprint or none, so the fingerprint is (re)written too,
+/// which records it on volumes created before fingerprints were.  Failures are only logged, since the volumes are
+/// attached either way.
+fn tag_attached_volumes<P, D>(attached: &AttachedVolumes,
+                              instance_id: &str,
+                              identity: &VolumeIdentity,
+                              timeouts: &EbsTimeouts,
+                              ec2_client: &Ec2Client<P, D>)
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let volume_ids = attached.volumes.iter().map(|&(_, ref volume_id)| volume_id.to_owned()).collect();
+    let tags = vec![Tag {
+                        key: Some(String::from(LAST_ATTACHED_INSTANCE_TAG)),
+                        value: Some(String::from(instance_id)),
+                    },
+                    Tag {
+                        key: Some(String::from(LAST_ATTACHED_AT_TAG)),
+                        value: Some(UTC::now().to_rfc3339()),
+                    },
+                    Tag {
+                        key: Some(String::from(CONFIG_HASH_TAG)),
+                        value: Some(identity.config_hash.to_owned()),
+                    },
+                    Tag {
+                        key: Some(String::from(CONFIG_FINGERPRINT_TAG)),
+                        value: Some(identity.fingerprint.to_owned()),
+                    }];
+    if let Err(e) = tag_resources(volume_ids, tags, timeouts, ec2_client) {
+        warn!("failed to tag attached volumes: {:?}", e);
+    }
+}
+
+fn describe_available_volumes<P, D>(config: &EbsBlockProviderConfig,
                                    ec2_client: &Ec2Client<P, D>)
-                                   -> Result<(), AttachVolumeError>
+                                   -> Result<Vec<Volume>, AttachVolumeError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
@@ -184,188 +1076,1108 @@ fn attach_to_existing_volume<P, D>(instance_id: &str,
     };
 
     trace!("executing DescribeVolumes");
-    let response = try!(ec2_client.describe_volumes(&request));
+    let response = try!(retry_throttled(config.timeouts.throttle_retries,
+                                        || ec2_client.describe_volumes(&request)));
 
     if response.next_token.is_some() {
         error!("DescribeVolumes returned multiple pages of results; this is not currently supported");
         return Err(AttachVolumeError::DescribeVolumesPaginationSupportRequired);
     }
 
-    if let Some(volumes) = response.volumes {
-        if volumes.is_empty() {
-            return Err(AttachVolumeError::NoVolumesAvailable);
+    let mut volumes = response.volumes.unwrap_or_default();
+    if let Some(ref selection) = config.selection {
+        let latest_snapshots = if *selection == VolumeSelection::MostRecentSnapshot && !volumes.is_empty() {
+            let volume_ids: Vec<String> = volumes.iter().filter_map(|vol| vol.volume_id.to_owned()).collect();
+            describe_latest_snapshots(volume_ids, &config.timeouts, ec2_client)?
+        } else {
+            HashMap::new()
+        };
+        sort_by_selection(&mut volumes, selection, &latest_snapshots);
+    }
+    Ok(volumes)
+}
+
+/// Orders volumes by `selection`, most preferred first.  Volumes that the policy doesn't distinguish stay in the
+/// order EC2 described them.  `latest_snapshots` holds the start time of each volume's most recent snapshot, for
+/// `most-recent-snapshot`.
+fn sort_by_selection(volumes: &mut [Volume], selection: &VolumeSelection, latest_snapshots: &HashMap<String, String>) {
+    // timestamps are all ISO 8601 in UTC, so they order as strings; volumes without one sort last
+    match *selection {
+        VolumeSelection::Newest => volumes.sort_by(|a, b| b.create_time.cmp(&a.create_time)),
+        VolumeSelection::Oldest => {
+            volumes.sort_by_key(|vol| (vol.create_time.is_none(), vol.create_time.to_owned()))
         }
+        VolumeSelection::Largest => volumes.sort_by(|a, b| b.size.cmp(&a.size)),
+        VolumeSelection::MostRecentSnapshot => {
+            let latest = |vol: &Volume| vol.volume_id.as_ref().and_then(|volume_id| latest_snapshots.get(volume_id));
+            volumes.sort_by(|a, b| latest(b).cmp(&latest(a)))
+        }
+        VolumeSelection::PreferTag(ref preferred) => {
+            volumes.sort_by_key(|vol| tag_value(vol, &preferred.key) != Some(preferred.value.as_str()))
+        }
+    }
+}
 
-        for vol in &volumes {
-            debug!("attempting to attach target volume: {:?}", vol);
-            let attach_volume_result = attach_specific_volume(block_device,
-                                                              instance_id,
-                                                              vol.volume_id.as_ref().unwrap(),
-                                                              ec2_client);
-            if attach_volume_result.is_ok() {
-                info!("successfully issued attach request");
-                return ensure_volume_attached(ec2_client, vol.volume_id.as_ref().unwrap());
-            } else {
-                debug!("failed to attach volume: {:?}", attach_volume_result.err())
+/// Describes the start time of the most recent snapshot of each of `volume_ids` that has been snapshotted, keyed by
+/// volume id.
+fn describe_latest_snapshots<P, D>(volume_ids: Vec<String>,
+                                   timeouts: &EbsTimeouts,
+                                   ec2_client: &Ec2Client<P, D>)
+                                   -> Result<HashMap<String, String>, DescribeSnapshotsError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = DescribeSnapshotsRequest {
+        dry_run: None,
+        filters: Some(vec![Filter {
+                               name: Some(String::from("volume-id")),
+                               values: Some(volume_ids),
+                           }]),
+        max_results: None,
+        next_token: None,
+        owner_ids: Some(vec![String::from("self")]),
+        restorable_by_user_ids: None,
+        snapshot_ids: None,
+    };
+    trace!("executing DescribeSnapshots");
+    let response = retry_throttled(timeouts.throttle_retries, || ec2_client.describe_snapshots(&request))?;
+    if response.next_token.is_some() {
+        warn!("DescribeSnapshots returned multiple pages of results; only the first page is used");
+    }
+    let mut latest_snapshots: HashMap<String, String> = HashMap::new();
+    for snapshot in response.snapshots.unwrap_or_default() {
+        if let (Some(volume_id), Some(start_time)) = (snapshot.volume_id, snapshot.start_time) {
+            let latest = latest_snapshots.entry(volume_id).or_insert_with(String::new);
+            if start_time > *latest {
+                *latest = start_time;
             }
         }
+    }
+    Ok(latest_snapshots)
+}
 
-        info!("all queried volumes have been attempted");
-        Err(AttachVolumeError::AllAttachesFailed)
-    } else {
-        Err(AttachVolumeError::NoVolumesAvailable)
+fn collect_volume_garbage<P, D>(config: &EbsBlockProviderConfig,
+                                options: &GcOptions,
+                                now: DateTime<UTC>,
+                                ec2_client: &Ec2Client<P, D>)
+                                -> Result<Vec<GcVolume>, GcError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let volumes = describe_volumes(tag_filters(config), &config.timeouts, ec2_client)?;
+    let mut collected = plan_gc(&volumes, now, options.retention, options.keep);
+    if options.dry_run {
+        return Ok(collected);
+    }
+
+    // volumes attached since they were marked, by an instance that didn't remove the mark itself
+    let reattached: Vec<String> = volumes.iter()
+        .filter(|vol| vol.state.as_ref().map_or(false, |state| state != "available"))
+        .filter(|vol| tag_value(vol, AVAILABLE_SINCE_TAG).is_some())
+        .filter_map(|vol| vol.volume_id.to_owned())
+        .collect();
+    if !reattached.is_empty() {
+        info!("removing {} from attached volumes: {:?}", AVAILABLE_SINCE_TAG, reattached);
+        remove_available_since(reattached, &config.timeouts, ec2_client)?;
+    }
+
+    let marked: Vec<String> = collected.iter()
+        .filter(|vol| vol.action == GcAction::Marked)
+        .map(|vol| vol.volume_id.to_owned())
+        .collect();
+    if !marked.is_empty() {
+        info!("marking newly available volumes: {:?}", marked);
+        let tags = vec![Tag {
+                            key: Some(String::from(AVAILABLE_SINCE_TAG)),
+                            value: Some(now.to_rfc3339()),
+                        }];
+        tag_resources(marked, tags, &config.timeouts, ec2_client).map_err(GcError::TaggingVolumesFailed)?;
+    }
+
+    for collected_volume in &mut collected {
+        if let GcAction::Deleted { ref mut snapshot_id } = collected_volume.action {
+            let volume_id = collected_volume.volume_id.as_str();
+            if options.snapshot {
+                let vol = volumes.iter().find(|vol| vol.volume_id.as_ref().map_or(false, |id| id == volume_id));
+                *snapshot_id = Some(snapshot_volume(vol.unwrap(), &config.timeouts, ec2_client)?);
+            }
+            // a snapshot is taken of the volume as it was when it was requested, so the volume can be deleted
+            // while the snapshot is still pending
+            info!("deleting volume {}", volume_id);
+            let request = DeleteVolumeRequest {
+                dry_run: None,
+                volume_id: String::from(volume_id),
+            };
+            retry_throttled(config.timeouts.throttle_retries,
+                            || ec2_client.delete_volume(&request))?;
+        }
     }
+    Ok(collected)
 }
 
-fn attach_specific_volume<P, D>(block_device: &str,
-                                instance_id: &str,
-                                volume_id: &str,
+/// Decides what garbage collection does with each available volume.  Members of a volume set are only useful
+/// together, so a set is retained, kept or deleted as a whole, and only once every member is available; a set's
+/// retention period starts when its last member became available.  Of the volumes past the retention period, those
+/// that became available most recently are kept, as many as are needed to leave `keep` available.  Volumes created by
+/// the `pool` command are retained until they've been attached, since they're waiting for an instance to need them.
+fn plan_gc(volumes: &[Volume], now: DateTime<UTC>, retention: Duration, keep: usize) -> Vec<GcVolume> {
+    let mut groups: Vec<(Option<&str>, Vec<&Volume>)> = Vec::new();
+    for vol in volumes {
+        match tag_value(vol, SET_ID_TAG) {
+            Some(set_id) => {
+                match groups.iter().position(|&(id, _)| id == Some(set_id)) {
+                    Some(i) => groups[i].1.push(vol),
+                    None => groups.push((Some(set_id), vec![vol])),
+                }
+            }
+            None => groups.push((None, vec![vol])),
+        }
+    }
+
+    let mut groups: Vec<(bool, Vec<GcVolume>)> = groups.into_iter()
+        .filter(|&(_, ref members)| members.iter().any(|vol| vol.state.as_ref().map_or(false, |s| s == "available")))
+        .map(|(_, members)| {
+            let complete = members.iter().all(|vol| vol.state.as_ref().map_or(false, |s| s == "available"));
+            let pooled = members.iter().any(|vol| {
+                tag_value(vol, POOLED_BY_TAG).is_some() && tag_value(vol, LAST_ATTACHED_INSTANCE_TAG).is_none()
+            });
+            let members = members.into_iter()
+                .filter(|vol| vol.state.as_ref().map_or(false, |s| s == "available"))
+                .map(|vol| {
+                    let available_since = tag_value(vol, AVAILABLE_SINCE_TAG)
+                        .and_then(|since| DateTime::parse_from_rfc3339(since).ok())
+                        .map(|since| since.with_timezone(&UTC));
+                    GcVolume {
+                        volume_id: vol.volume_id.to_owned().unwrap_or_default(),
+                        available_since: available_since.unwrap_or(now),
+                        action: if available_since.is_some() {
+                            GcAction::Retained
+                        } else {
+                            GcAction::Marked
+                        },
+                    }
+                })
+                .collect();
+            (complete && !pooled, members)
+        })
+        .collect();
+
+    let available_since = |members: &[GcVolume]| members.iter().map(|vol| vol.available_since).max().unwrap_or(now);
+    let mut expired: Vec<usize> = (0..groups.len())
+        .filter(|&i| groups[i].0 && available_since(&groups[i].1) + retention <= now)
+        .collect();
+    expired.sort_by(|&a, &b| available_since(&groups[b].1).cmp(&available_since(&groups[a].1)));
+    // only complete, unpooled volumes and sets could be attached, so only they count towards those kept
+    let unexpired = groups.iter().filter(|&&(attachable, _)| attachable).count() - expired.len();
+    for (n, &i) in expired.iter().enumerate() {
+        let action = if unexpired + n < keep {
+            GcAction::Kept
+        } else {
+            GcAction::Deleted { snapshot_id: None }
+        };
+        for vol in &mut groups[i].1 {
+            vol.action = action.clone();
+        }
+    }
+    groups.into_iter().flat_map(|(_, members)| members).collect()
+}
+
+/// Snapshots a volume that's about to be deleted, copying its tags to the snapshot so that it can be found by them,
+/// and returns the snapshot id.
+fn snapshot_volume<P, D>(vol: &Volume, timeouts: &EbsTimeouts, ec2_client: &Ec2Client<P, D>) -> Result<String, GcError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let volume_id = vol.volume_id.to_owned().unwrap();
+    let request = CreateSnapshotRequest {
+        description: Some(format!("cloud-persistent-storage gc of {}", volume_id)),
+        dry_run: None,
+        volume_id: volume_id.to_owned(),
+    };
+    let snapshot = retry_throttled(timeouts.throttle_retries, || ec2_client.create_snapshot(&request))?;
+    let snapshot_id = snapshot.snapshot_id.unwrap();
+    info!("created snapshot {} of volume {}", snapshot_id, volume_id);
+
+    let tags: Vec<Tag> = vol.tags
+        .to_owned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|tag| {
+                    tag.key.as_ref().map_or(false, |key| !key.starts_with("aws:") && key != AVAILABLE_SINCE_TAG)
+                })
+        .collect();
+    if !tags.is_empty() {
+        tag_resources(vec![snapshot_id.to_owned()], tags, timeouts, ec2_client)
+            .map_err(GcError::TaggingSnapshotFailed)?;
+    }
+    Ok(snapshot_id)
+}
+
+/// Removes `AVAILABLE_SINCE_TAG` from volumes that have just been attached, so that their retention period restarts
+/// when they next become available.  Failures are only logged, since the volumes are attached either way.
+fn clear_available_since<P, D>(volumes: &[&Volume], timeouts: &EbsTimeouts, ec2_client: &Ec2Client<P, D>)
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let marked: Vec<String> = volumes.iter()
+        .filter(|vol| tag_value(vol, AVAILABLE_SINCE_TAG).is_some())
+        .filter_map(|vol| vol.volume_id.to_owned())
+        .collect();
+    if !marked.is_empty() {
+        if let Err(e) = remove_available_since(marked, timeouts, ec2_client) {
+            warn!("failed to remove {} from attached volumes: {:?}", AVAILABLE_SINCE_TAG, e);
+        }
+    }
+}
+
+fn remove_available_since<P, D>(volume_ids: Vec<String>,
+                                timeouts: &EbsTimeouts,
                                 ec2_client: &Ec2Client<P, D>)
-                                -> Result<(), rusoto::ec2::AttachVolumeError>
+                                -> Result<(), rusoto::ec2::DeleteTagsError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
-    let request = AttachVolumeRequest {
-        device: String::from(block_device),
+    let request = DeleteTagsRequest {
         dry_run: None,
-        instance_id: String::from(instance_id),
-        volume_id: String::from(volume_id),
+        resources: volume_ids,
+        tags: Some(vec![Tag {
+                            key: Some(String::from(AVAILABLE_SINCE_TAG)),
+                            value: None,
+                        }]),
     };
-    try!(ec2_client.attach_volume(&request));
+    retry_throttled(timeouts.throttle_retries, || ec2_client.delete_tags(&request))
+}
+
+/// The value of `SNAPSHOT_OF_TAG` for snapshots of `vol`: its set id if it's a member of a volume set, since the
+/// members are snapshotted together, or else its volume id.
+fn snapshot_of(vol: &Volume) -> String {
+    String::from(tag_value(vol, SET_ID_TAG).unwrap_or_else(|| vol.volume_id.as_ref().map_or("", |id| id.as_str())))
+}
+
+/// Starts a snapshot of each of `volumes`, and returns the snapshot ids in the same order.  Only CreateSnapshot is
+/// called, so that a filesystem frozen for the snapshots can be thawed as soon as they've started; `tag_snapshots`
+/// tags them afterwards.  Throttled requests are retried up to `throttle_retries` times.
+pub fn create_snapshots<P, D>(volumes: &[Volume],
+                              throttle_retries: u32,
+                              ec2_client: &Ec2Client<P, D>)
+                              -> Result<Vec<String>, SnapshotError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let mut snapshot_ids = Vec::with_capacity(volumes.len());
+    for vol in volumes {
+        let volume_id = vol.volume_id.to_owned().ok_or(SnapshotError::MissingVolumeId)?;
+        let request = CreateSnapshotRequest {
+            description: Some(format!("cloud-persistent-storage snapshot of {}", volume_id)),
+            dry_run: None,
+            volume_id: volume_id.to_owned(),
+        };
+        let snapshot = retry_throttled(throttle_retries, || ec2_client.create_snapshot(&request))?;
+        let snapshot_id = snapshot.snapshot_id.ok_or_else(|| SnapshotError::MissingSnapshotId(volume_id.to_owned()))?;
+        info!("created snapshot {} of volume {}", snapshot_id, volume_id);
+        snapshot_ids.push(snapshot_id);
+    }
+    Ok(snapshot_ids)
+}
+
+/// Tags the snapshots that `create_snapshots` started of `volumes` with the configured tags, the volume set tags, the
+/// volumes' configuration fingerprint, the time `now`, and the volume (or volume set) they're of.
+pub fn tag_snapshots<P, D>(volumes: &[Volume],
+                           snapshot_ids: &[String],
+                           config: &EbsBlockProviderConfig,
+                           now: DateTime<UTC>,
+                           ec2_client: &Ec2Client<P, D>)
+                           -> Result<(), SnapshotError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    for (vol, snapshot_id) in volumes.iter().zip(snapshot_ids) {
+        let mut tags: Vec<Tag> = config.ebs_tags
+            .iter()
+            .map(|(tag_name, tag_value)| {
+                     Tag {
+                         key: Some(tag_name.to_owned()),
+                         value: Some(tag_value.to_owned()),
+                     }
+                 })
+            .collect();
+        for key in &[SET_ID_TAG, SET_MEMBER_TAG, CONFIG_FINGERPRINT_TAG] {
+            if let Some(value) = tag_value(vol, key) {
+                tags.push(Tag {
+                              key: Some(String::from(*key)),
+                              value: Some(String::from(value)),
+                          });
+            }
+        }
+        tags.push(Tag {
+                      key: Some(String::from(SNAPSHOT_TIME_TAG)),
+                      value: Some(now.to_rfc3339()),
+                  });
+        tags.push(Tag {
+                      key: Some(String::from(SNAPSHOT_OF_TAG)),
+                      value: Some(snapshot_of(vol)),
+                  });
+        tag_resources(vec![snapshot_id.to_owned()], tags, &config.timeouts, ec2_client)?;
+    }
     Ok(())
 }
 
-fn ensure_volume_available<P, D>(ec2_client: &Ec2Client<P, D>,
-                                 volume_id: &str)
-                                 -> Result<(), CreateVolumeError>
+/// Deletes the snapshots tagged as being of `snapshot_of` whose times `retention` doesn't keep.  Snapshots without a
+/// valid `SNAPSHOT_TIME_TAG` are never deleted.
+fn delete_expired_snapshots<P, D>(snapshot_of: &str,
+                                  retention: &SnapshotRetention,
+                                  timeouts: &EbsTimeouts,
+                                  ec2_client: &Ec2Client<P, D>)
+                                  -> Result<Vec<String>, SnapshotError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
-    info!("waiting for volume to attach");
-    let request = DescribeVolumesRequest {
+    let request = DescribeSnapshotsRequest {
         dry_run: None,
-        filters: None,
+        filters: Some(vec![Filter {
+                               name: Some(String::from("tag:") + SNAPSHOT_OF_TAG),
+                               values: Some(vec![String::from(snapshot_of)]),
+                           }]),
         max_results: None,
         next_token: None,
-        volume_ids: Some(vec![String::from(volume_id)]),
+        owner_ids: Some(vec![String::from("self")]),
+        restorable_by_user_ids: None,
+        snapshot_ids: None,
     };
+    trace!("executing DescribeSnapshots");
+    let response = retry_throttled(timeouts.throttle_retries, || ec2_client.describe_snapshots(&request))?;
+    if response.next_token.is_some() {
+        warn!("DescribeSnapshots returned multiple pages of results; only the first page is pruned");
+    }
+    let snapshots: Vec<(String, DateTime<UTC>)> = response.snapshots
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|snapshot| {
+            let time = find_tag(snapshot.tags.as_ref(), SNAPSHOT_TIME_TAG)
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&UTC));
+            match (snapshot.snapshot_id, time) {
+                (Some(snapshot_id), Some(time)) => Some((snapshot_id, time)),
+                _ => None,
+            }
+        })
+        .collect();
 
-    let start = std::time::Instant::now();
-    let timeout = std::time::Duration::from_secs(5 * 60);
-    let sleep = std::time::Duration::from_secs(5);
-    while std::time::Instant::now().duration_since(start) < timeout {
-        if check_volume_available(ec2_client, &request)? {
-            return Ok(());
+    let times: Vec<DateTime<UTC>> = snapshots.iter().map(|&(_, time)| time).collect();
+    let retained = retained_snapshot_times(&times, retention);
+    let mut deleted = Vec::new();
+    for (snapshot_id, time) in snapshots {
+        if !retained.contains(&time) {
+            info!("deleting snapshot {} taken at {}", snapshot_id, time.to_rfc3339());
+            let request = DeleteSnapshotRequest {
+                dry_run: None,
+                snapshot_id: snapshot_id.to_owned(),
+            };
+            retry_throttled(timeouts.throttle_retries, || ec2_client.delete_snapshot(&request))?;
+            deleted.push(snapshot_id);
         }
-        std::thread::sleep(sleep);
     }
-    Err(CreateVolumeError::TimeoutWaitingForVolumeToBecomeAvailable)
+    Ok(deleted)
 }
 
-fn check_volume_available<P, D>(ec2_client: &Ec2Client<P, D>,
-                                request: &DescribeVolumesRequest)
-                                -> Result<bool, CreateVolumeError>
+/// Chooses which of the snapshot `times` `retention` keeps.
+fn retained_snapshot_times(times: &[DateTime<UTC>], retention: &SnapshotRetention) -> Vec<DateTime<UTC>> {
+    let mut times = times.to_vec();
+    times.sort_by(|a, b| b.cmp(a));
+    times.dedup();
+
+    let periods: [(usize, fn(&DateTime<UTC>) -> String); 3] =
+        [(retention.hourly, |time| time.format("%Y-%m-%dT%H").to_string()),
+         (retention.daily, |time| time.format("%Y-%m-%d").to_string()),
+         (retention.weekly, |time| {
+            let (year, week, _) = time.isoweekdate();
+            format!("{}-W{}", year, week)
+        })];
+    let mut retained = Vec::new();
+    for &(count, period) in &periods {
+        let mut last_period = None;
+        let mut kept = 0;
+        for time in &times {
+            if kept == count {
+                break;
+            }
+            let this_period = Some(period(time));
+            if this_period != last_period {
+                if !retained.contains(time) {
+                    retained.push(*time);
+                }
+                kept += 1;
+                last_period = this_period;
+            }
+        }
+    }
+    retained
+}
+
+/// What `fill_volume_pool` keeps available in each of `availability_zones`.
+struct PoolRequest<'a> {
+    availability_zones: &'a [String],
+    size: usize,
+    snapshot_id: Option<&'a str>,
+    fingerprint: &'a str,
+    members: usize,
+}
+
+fn fill_volume_pool<P, D>(config: &EbsBlockProviderConfig,
+                          request: &PoolRequest,
+                          instance_id: &str,
+                          dry_run: bool,
+                          ec2_client: &Ec2Client<P, D>)
+                          -> Result<Vec<PoolZone>, PoolError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
-    trace!("checking DescribeVolumes to see if volume is attached");
-    ec2_client.describe_volumes(request)?
-        .volumes
-        .as_ref()
-        .and_then(|volume_list| volume_list.get(0))
-        .and_then(|volume| volume.state.as_ref())
-        .map_or(Ok(false), |state| Ok(state == "available"))
+    let fingerprint = match request.snapshot_id {
+        Some(snapshot_id) => snapshot_volume_fingerprint(snapshot_id, request.fingerprint, config, ec2_client)?,
+        None => Some(request.fingerprint),
+    };
+    let volumes = describe_volumes(create_filters(config), &config.timeouts, ec2_client)?;
+    // set ids only need to be unique among this run's sets, so they're numbered after the time
+    let set_prefix = format!("{}-{}", instance_id, UTC::now().timestamp());
+    let mut sets = 0;
+    let mut zones = Vec::with_capacity(request.availability_zones.len());
+    for availability_zone in request.availability_zones {
+        let available = pool_available(&volumes, availability_zone, request.fingerprint, request.members);
+        let mut zone = PoolZone {
+            availability_zone: availability_zone.to_owned(),
+            available: available,
+            created: request.size.saturating_sub(available),
+            volume_ids: Vec::new(),
+        };
+        if !dry_run {
+            for _ in 0..zone.created {
+                let set_id = format!("{}-{}", set_prefix, sets);
+                sets += 1;
+                let mut set_volume_ids = Vec::with_capacity(request.members);
+                for member in 0..request.members {
+                    let mut tags = vec![Tag {
+                                            key: Some(String::from(POOLED_BY_TAG)),
+                                            value: Some(String::from(instance_id)),
+                                        }];
+                    if request.members > 1 {
+                        tags.extend(set_member_tags(&set_id, member));
+                    }
+                    match create_volume(availability_zone,
+                                        instance_id,
+                                        fingerprint,
+                                        request.snapshot_id,
+                                        ec2_client,
+                                        config,
+                                        tags) {
+                        Ok(volume_id) => set_volume_ids.push(volume_id),
+                        Err(err) => {
+                            // an incomplete set would never be attached, and gc keeps pooled volumes until they are
+                            delete_created_volumes(&set_volume_ids, &config.timeouts, ec2_client);
+                            return Err(PoolError::from(err));
+                        }
+                    }
+                }
+                zone.volume_ids.extend(set_volume_ids);
+            }
+            info!("created pooled volumes in {}: {:?}", availability_zone, zone.volume_ids);
+        }
+        zones.push(zone);
+    }
+
+    for volume_id in zones.iter().flat_map(|zone| zone.volume_ids.iter()) {
+        ensure_volume_available(ec2_client, volume_id, &config.timeouts)?;
+    }
+    Ok(zones)
 }
 
-fn ensure_volume_attached<P, D>(ec2_client: &Ec2Client<P, D>,
-                                volume_id: &str)
-                                -> Result<(), AttachVolumeError>
+/// Decides the fingerprint to record on volumes created from `snapshot_id`.  Such volumes have the filesystem, layout
+/// and encryption of the volume the snapshot was taken of, so the snapshot's fingerprint must be `fingerprint`.  A
+/// snapshot without one (eg. one not taken by the `snapshot` command) can't be checked, so its volumes are left without
+/// a fingerprint, like volumes created before fingerprints were recorded.
+fn snapshot_volume_fingerprint<'a, P, D>(snapshot_id: &str,
+                                         fingerprint: &'a str,
+                                         config: &EbsBlockProviderConfig,
+                                         ec2_client: &Ec2Client<P, D>)
+                                         -> Result<Option<&'a str>, PoolError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
-    info!("waiting for volume to attach");
-    let request = DescribeVolumesRequest {
+    let request = DescribeSnapshotsRequest {
         dry_run: None,
         filters: None,
         max_results: None,
         next_token: None,
-        volume_ids: Some(vec![String::from(volume_id)]),
+        owner_ids: None,
+        restorable_by_user_ids: None,
+        snapshot_ids: Some(vec![String::from(snapshot_id)]),
     };
-
-    let start = std::time::Instant::now();
-    let timeout = std::time::Duration::from_secs(5 * 60);
-    let sleep = std::time::Duration::from_secs(5);
-    while std::time::Instant::now().duration_since(start) < timeout {
-        if check_volume_attached(ec2_client, &request)? {
-            return Ok(());
+    trace!("executing DescribeSnapshots");
+    let snapshots = retry_throttled(config.timeouts.throttle_retries,
+                                    || ec2_client.describe_snapshots(&request))?
+        .snapshots
+        .unwrap_or_default();
+    let snapshot_fingerprint = snapshots.get(0)
+        .and_then(|snapshot| find_tag(snapshot.tags.as_ref(), CONFIG_FINGERPRINT_TAG));
+    match snapshot_fingerprint {
+        Some(snapshot_fingerprint) if snapshot_fingerprint == fingerprint => Ok(Some(fingerprint)),
+        Some(snapshot_fingerprint) => {
+            Err(PoolError::SnapshotFingerprintMismatch(String::from(snapshot_id), String::from(snapshot_fingerprint)))
+        }
+        None => {
+            warn!("snapshot {} has no {} tag; pooled volumes created from it won't have one either",
+                  snapshot_id,
+                  CONFIG_FINGERPRINT_TAG);
+            Ok(None)
         }
-        std::thread::sleep(sleep);
     }
-    Err(AttachVolumeError::TimeoutWaitingForVolumeToAttach)
 }
 
-fn check_volume_attached<P, D>(ec2_client: &Ec2Client<P, D>,
-                               request: &DescribeVolumesRequest)
-                               -> Result<bool, AttachVolumeError>
+/// Counts the volumes, or complete volume sets of `members` volumes, that an instance in `availability_zone` could
+/// attach: those that are in the zone, and were created with `fingerprint` or before fingerprints were recorded.
+fn pool_available(volumes: &[Volume], availability_zone: &str, fingerprint: &str, members: usize) -> usize {
+    let zone_volumes: Vec<Volume> = volumes.iter()
+        .filter(|vol| vol.availability_zone.as_ref().map_or(false, |zone| zone == availability_zone))
+        .filter(|vol| tag_value(vol, CONFIG_FINGERPRINT_TAG).map_or(true, |f| f == fingerprint))
+        .cloned()
+        .collect();
+    if members == 1 {
+        zone_volumes.iter().filter(|vol| tag_value(vol, SET_ID_TAG).is_none()).count()
+    } else {
+        volume_sets(&zone_volumes, members, false).len()
+    }
+}
+
+/// Describes the tags on this instance, keyed by tag name.
+pub fn instance_tags(metadata: &InstanceMetadata) -> Result<HashMap<String, String>, DescribeTagsError> {
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    describe_instance_tags(metadata.instance_id.as_str(), &ec2_client)
+}
+
+fn describe_instance_tags<P, D>(instance_id: &str,
+                                ec2_client: &Ec2Client<P, D>)
+                                -> Result<HashMap<String, String>, DescribeTagsError>
     where P: ProvideAwsCredentials,
           D: DispatchSignedRequest
 {
-    trace!("checking DescribeVolumes to see if volume is attached");
-    ec2_client.describe_volumes(request)?
-        .volumes
-        .as_ref()
-        .and_then(|volume_list| volume_list.get(0))
-        .and_then(|volume| volume.attachments.as_ref())
-        .and_then(|attachments| attachments.get(0))
-        .and_then(|attachment| attachment.state.as_ref())
-        .map_or(Ok(false), |state| Ok(state == "attached"))
+    let request = DescribeTagsRequest {
+        dry_run: None,
+        filters: Some(vec![Filter {
+                               name: Some(String::from("resource-id")),
+                               values: Some(vec![String::from(instance_id)]),
+                           }]),
+        max_results: None,
+        next_token: None,
+    };
+    trace!("executing DescribeTags");
+    let response = retry_throttled(config::default_throttle_retries(),
+                                   || ec2_client.describe_tags(&request))?;
+    if response.next_token.is_some() {
+        warn!("DescribeTags returned multiple pages of results; only the first page is used");
+    }
+    Ok(response.tags
+           .unwrap_or_default()
+           .into_iter()
+           .filter_map(|tag| match (tag.key, tag.value) {
+                           (Some(key), Some(value)) => Some((key, value)),
+                           _ => None,
+                       })
+           .collect())
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate hyper;
+/// Describes the device names in this instance's block device mapping, including attached EBS volumes.
+pub fn instance_block_devices(metadata: &InstanceMetadata) -> Result<Vec<String>, DescribeInstancesError> {
+    let ec2_client = Ec2Client::new(default_tls_client().unwrap(),
+                                    DefaultCredentialsProvider::new().unwrap(),
+                                    metadata.region().unwrap());
+    describe_instance_block_devices(metadata.instance_id.as_str(), &ec2_client)
+}
 
-    use super::*;
-    use chrono::{Duration, UTC};
-    use std::collections::HashMap;
+fn describe_instance_block_devices<P, D>(instance_id: &str,
+                                         ec2_client: &Ec2Client<P, D>)
+                                         -> Result<Vec<String>, DescribeInstancesError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = DescribeInstancesRequest {
+        dry_run: None,
+        filters: None,
+        instance_ids: Some(vec![String::from(instance_id)]),
+        max_results: None,
+        next_token: None,
+    };
+    trace!("executing DescribeInstances");
+    let response = retry_throttled(config::default_throttle_retries(),
+                                   || ec2_client.describe_instances(&request))?;
+    Ok(response.reservations
+           .unwrap_or_default()
+           .into_iter()
+           .flat_map(|reservation| reservation.instances.unwrap_or_default())
+           .flat_map(|instance| instance.block_device_mappings.unwrap_or_default())
+           .filter_map(|mapping| mapping.device_name)
+           .collect())
+}
 
-    struct MockProvideAwsCredentials {}
+fn attached_filters(instance_id: &str, block_devices: &[String], config: &EbsBlockProviderConfig) -> Vec<Filter> {
+    let mut filters = tag_filters(config);
+    filters.push(Filter {
+                     name: Some(String::from("attachment.instance-id")),
+                     values: Some(vec![String::from(instance_id)]),
+                 });
+    filters.push(Filter {
+                     name: Some(String::from("attachment.device")),
+                     values: Some(block_devices.to_vec()),
+                 });
+    filters
+}
 
-    impl rusoto::ProvideAwsCredentials for MockProvideAwsCredentials {
-        fn credentials(&self) -> Result<rusoto::AwsCredentials, rusoto::CredentialsError> {
-            Ok(rusoto::AwsCredentials::new("key",
-                                           "secret",
-                                           None,
-                                           UTC::now() + Duration::seconds(600)))
-        }
+fn describe_volumes<P, D>(filters: Vec<Filter>,
+                          timeouts: &EbsTimeouts,
+                          ec2_client: &Ec2Client<P, D>)
+                          -> Result<Vec<Volume>, DescribeVolumesError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = DescribeVolumesRequest {
+        dry_run: None,
+        filters: Some(filters),
+        max_results: None,
+        next_token: None,
+        volume_ids: None,
+    };
+    trace!("executing DescribeVolumes");
+    let response = retry_throttled(timeouts.throttle_retries, || ec2_client.describe_volumes(&request))?;
+    if response.next_token.is_some() {
+        warn!("DescribeVolumes returned multiple pages of results; only the first page is used");
     }
+    Ok(response.volumes.unwrap_or_default())
+}
 
-    struct Ec2RequestDispatcherAttachSpecificVolumeSuccess {}
+pub fn tag_value<'a>(volume: &'a Volume, key: &str) -> Option<&'a str> {
+    find_tag(volume.tags.as_ref(), key)
+}
 
-    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherAttachSpecificVolumeSuccess {
-        fn dispatch(&self,
-                    request: &rusoto::SignedRequest)
-                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
-            assert!(request.params.get("Device") == Some(&Some(String::from("/dev/xvdh"))));
-            assert!(request.params.get("InstanceId") == Some(&Some(String::from("i-1234"))));
-            assert!(request.params.get("VolumeId") == Some(&Some(String::from("vol-4321"))));
-            assert!(request.params.get("Action") == Some(&Some(String::from("AttachVolume"))));
-            Ok(rusoto::HttpResponse {
-                   status: hyper::status::StatusCode::Ok,
-                   body: String::from(""),
-                   raw_body: vec![],
-                   headers: HashMap::new(),
-               })
+fn find_tag<'a>(tags: Option<&'a Vec<Tag>>, key: &str) -> Option<&'a str> {
+    tags.and_then(|tags| tags.iter().find(|tag| tag.key.as_ref().map_or(false, |k| k == key)))
+        .and_then(|tag| tag.value.as_ref())
+        .map(|value| value.as_str())
+}
+
+/// Keeps the volumes that are members of a volume set if `volume_set` is set, and the others if not.  Members of a
+/// volume set are only useful together, so they're never attached as a single volume.
+fn layout_candidates(volumes: Vec<Volume>, volume_set: bool) -> Vec<Volume> {
+    volumes.into_iter()
+        .filter(|vol| tag_value(vol, SET_ID_TAG).is_some() == volume_set)
+        .collect()
+}
+
+/// Drops available volumes whose configuration fingerprint differs from `fingerprint`.  If that leaves none and
+/// `fingerprint-mismatch` is `fail`, fails on the first of them instead, so that their data isn't abandoned for a new
+/// volume.  Volumes created before fingerprints were recorded don't have one, and are kept.
+fn matching_fingerprint(volumes: Vec<Volume>,
+                        fingerprint: &str,
+                        config: &EbsBlockProviderConfig)
+                        -> Result<Vec<Volume>, AttachVolumeError> {
+    let mut matching = Vec::with_capacity(volumes.len());
+    let mut mismatched = None;
+    for vol in volumes {
+        match tag_value(&vol, CONFIG_FINGERPRINT_TAG).map(String::from) {
+            Some(volume_fingerprint) => {
+                if volume_fingerprint == fingerprint {
+                    matching.push(vol);
+                    continue;
+                }
+                let volume_id = vol.volume_id.unwrap_or_default();
+                warn!("skipping volume {}, which was created for {}, not {}",
+                      volume_id,
+                      volume_fingerprint,
+                      fingerprint);
+                if mismatched.is_none() {
+                    mismatched = Some((volume_id, volume_fingerprint));
+                }
+            }
+            None => matching.push(vol),
         }
     }
+    match (mismatched, &config.fingerprint_mismatch) {
+        (Some((volume_id, volume_fingerprint)), &FingerprintMismatch::Fail) if matching.is_empty() => {
+            Err(AttachVolumeError::FingerprintMismatch(volume_id, volume_fingerprint))
+        }
+        _ => Ok(matching),
+    }
+}
 
-    struct Ec2RequestDispatcherAttachSpecificVolumeFailure {}
-
-    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherAttachSpecificVolumeFailure {
-        fn dispatch(&self,
-                    _: &rusoto::SignedRequest)
-                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
-            Ok(rusoto::HttpResponse {
+fn attach_to_existing_volume<P, D>(instance_id: &str,
+                                   block_devices: &[String],
+                                   device: &mut usize,
+                                   config: &EbsBlockProviderConfig,
+                                   fingerprint: &str,
+                                   ec2_client: &Ec2Client<P, D>)
+                                   -> Result<AttachedVolumes, AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let volumes = layout_candidates(describe_available_volumes(config, ec2_client)?, false);
+    let volumes = matching_fingerprint(volumes, fingerprint, config)?;
+    if volumes.is_empty() {
+        return Err(AttachVolumeError::NoVolumesAvailable);
+    }
+
+    for vol in &volumes {
+        debug!("attempting to attach target volume: {:?}", vol);
+        let volume_id = vol.volume_id.to_owned().unwrap();
+        match attach_at_free_block_device(block_devices,
+                                          device,
+                                          instance_id,
+                                          volume_id.as_str(),
+                                          &config.timeouts,
+                                          ec2_client) {
+            Ok(_) => {
+                info!("successfully issued attach request");
+                ensure_volume_attached(ec2_client, volume_id.as_str(), &config.timeouts)?;
+                clear_available_since(&[vol], &config.timeouts, ec2_client);
+                return Ok(AttachedVolumes {
+                              volumes: vec![(block_devices[*device].to_owned(), volume_id)],
+                              created: false,
+                          });
+            }
+            Err(e) => {
+                let failure = fatal_attach_error(e)?;
+                debug!("failed to attach volume {}: {:?}", volume_id, failure);
+            }
+        }
+    }
+
+    info!("all queried volumes have been attempted");
+    Err(AttachVolumeError::AllAttachesFailed)
+}
+
+/// Attaches a volume at the first of `block_devices`, starting from `*device`, that isn't already in use on this
+/// instance.  `*device` is left at the last block device attempted, so that later attempts skip those found to be in
+/// use.
+fn attach_at_free_block_device<P, D>(block_devices: &[String],
+                                     device: &mut usize,
+                                     instance_id: &str,
+                                     volume_id: &str,
+                                     timeouts: &EbsTimeouts,
+                                     ec2_client: &Ec2Client<P, D>)
+                                     -> Result<(), rusoto::ec2::AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    loop {
+        match attach_specific_volume(&block_devices[*device], instance_id, volume_id, timeouts, ec2_client) {
+            Err(ref e) if *device + 1 < block_devices.len() &&
+                          classify_attach_error(e) == AttachFailure::DeviceInUse => {
+                warn!("block device {} is already in use; trying {}",
+                      block_devices[*device],
+                      block_devices[*device + 1]);
+                *device += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Groups volumes into sets of up to `members` volumes by their set id, keyed by member index.  Complete sets are
+/// returned first; sets that are missing a member (eg. because it is attached elsewhere, or was lost) are only
+/// returned if `allow_partial` is set, largest first.
+fn volume_sets(volumes: &[Volume], members: usize, allow_partial: bool) -> Vec<BTreeMap<usize, &Volume>> {
+    // sets are kept in the order their first member was described in, which follows the selection policy
+    let mut sets: Vec<(&str, BTreeMap<usize, &Volume>)> = Vec::new();
+    for vol in volumes {
+        let set_id = tag_value(vol, SET_ID_TAG);
+        let member = tag_value(vol, SET_MEMBER_TAG).and_then(|m| m.parse::<usize>().ok());
+        if let (Some(set_id), Some(member)) = (set_id, member) {
+            if member < members {
+                let i = match sets.iter().position(|&(id, _)| id == set_id) {
+                    Some(i) => i,
+                    None => {
+                        sets.push((set_id, BTreeMap::new()));
+                        sets.len() - 1
+                    }
+                };
+                sets[i].1.insert(member, vol);
+            }
+        }
+    }
+    let mut sets: Vec<BTreeMap<usize, &Volume>> = sets.into_iter()
+        .map(|(_, set)| set)
+        .filter(|set| allow_partial || set.len() == members)
+        .collect();
+    sets.sort_by(|a, b| b.len().cmp(&a.len()));
+    sets
+}
+
+fn attach_to_existing_volume_set<P, D>(instance_id: &str,
+                                       block_devices: &[String],
+                                       config: &EbsBlockProviderConfig,
+                                       fingerprint: &str,
+                                       allow_partial: bool,
+                                       ec2_client: &Ec2Client<P, D>)
+                                       -> Result<AttachedVolumes, AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let volumes = layout_candidates(describe_available_volumes(config, ec2_client)?, true);
+    let volumes = matching_fingerprint(volumes, fingerprint, config)?;
+    let sets = volume_sets(&volumes, block_devices.len(), allow_partial);
+    if sets.is_empty() {
+        return Err(AttachVolumeError::NoVolumesAvailable);
+    }
+
+    for set in &sets {
+        debug!("attempting to attach target volume set: {:?}", set);
+        if set.len() < block_devices.len() {
+            warn!("attaching incomplete volume set with {} of {} members",
+                  set.len(),
+                  block_devices.len());
+        }
+        match attach_volume_set(block_devices, instance_id, set, &config.timeouts, ec2_client) {
+            Ok(_) => {
+                info!("successfully issued attach requests for volume set");
+                for vol in set.values() {
+                    ensure_volume_attached(ec2_client, vol.volume_id.as_ref().unwrap(), &config.timeouts)?;
+                }
+                clear_available_since(&set.values().cloned().collect::<Vec<_>>(), &config.timeouts, ec2_client);
+                return Ok(AttachedVolumes {
+                              volumes: set.iter()
+                                  .map(|(member, vol)| {
+                                           (block_devices[*member].to_owned(), vol.volume_id.to_owned().unwrap())
+                                       })
+                                  .collect(),
+                              created: false,
+                          });
+            }
+            Err(e) => {
+                let failure = fatal_attach_error(e)?;
+                debug!("failed to attach volume set: {:?}", failure);
+            }
+        }
+    }
+
+    info!("all queried volume sets have been attempted");
+    Err(AttachVolumeError::AllAttachesFailed)
+}
+
+/// Attaches every member of a volume set to the block device matching its member index.  If any member fails to
+/// attach, members that were already attached are detached again so that the set remains available as a whole to
+/// other instances.
+fn attach_volume_set<P, D>(block_devices: &[String],
+                           instance_id: &str,
+                           set: &BTreeMap<usize, &Volume>,
+                           timeouts: &EbsTimeouts,
+                           ec2_client: &Ec2Client<P, D>)
+                           -> Result<(), rusoto::ec2::AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let mut attached = Vec::with_capacity(set.len());
+    for (member, vol) in set {
+        let volume_id = vol.volume_id.as_ref().unwrap();
+        if let Err(e) = attach_specific_volume(&block_devices[*member], instance_id, volume_id, timeouts, ec2_client) {
+            for volume_id in attached {
+                if let Err(detach_err) = detach_specific_volume(instance_id, volume_id, timeouts, ec2_client) {
+                    warn!("failed to detach volume {} after partial set attach: {:?}",
+                          volume_id,
+                          detach_err);
+                }
+            }
+            return Err(e);
+        }
+        attached.push(volume_id);
+    }
+    Ok(())
+}
+
+fn detach_specific_volume<P, D>(instance_id: &str,
+                                volume_id: &str,
+                                timeouts: &EbsTimeouts,
+                                ec2_client: &Ec2Client<P, D>)
+                                -> Result<(), rusoto::ec2::DetachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = DetachVolumeRequest {
+        device: None,
+        dry_run: None,
+        force: None,
+        instance_id: Some(String::from(instance_id)),
+        volume_id: String::from(volume_id),
+    };
+    try!(retry_throttled(timeouts.throttle_retries, || ec2_client.detach_volume(&request)));
+    Ok(())
+}
+
+fn attach_specific_volume<P, D>(block_device: &str,
+                                instance_id: &str,
+                                volume_id: &str,
+                                timeouts: &EbsTimeouts,
+                                ec2_client: &Ec2Client<P, D>)
+                                -> Result<(), rusoto::ec2::AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = AttachVolumeRequest {
+        device: String::from(block_device),
+        dry_run: None,
+        instance_id: String::from(instance_id),
+        volume_id: String::from(volume_id),
+    };
+    try!(retry_throttled(timeouts.throttle_retries, || ec2_client.attach_volume(&request)));
+    Ok(())
+}
+
+fn ensure_volume_available<P, D>(ec2_client: &Ec2Client<P, D>,
+                                 volume_id: &str,
+                                 timeouts: &EbsTimeouts)
+                                 -> Result<(), CreateVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    info!("waiting for volume to become available");
+    let request = describe_volume_request(volume_id);
+    if poll_until(&timeouts.volume_available,
+                  || check_volume_available(ec2_client, &request, timeouts))? {
+        Ok(())
+    } else {
+        Err(CreateVolumeError::TimeoutWaitingForVolumeToBecomeAvailable)
+    }
+}
+
+fn check_volume_available<P, D>(ec2_client: &Ec2Client<P, D>,
+                                request: &DescribeVolumesRequest,
+                                timeouts: &EbsTimeouts)
+                                -> Result<bool, CreateVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    trace!("checking DescribeVolumes to see if volume is available");
+    retry_throttled(timeouts.throttle_retries, || ec2_client.describe_volumes(request))?
+        .volumes
+        .as_ref()
+        .and_then(|volume_list| volume_list.get(0))
+        .and_then(|volume| volume.state.as_ref())
+        .map_or(Ok(false), |state| Ok(state == "available"))
+}
+
+fn ensure_volume_attached<P, D>(ec2_client: &Ec2Client<P, D>,
+                                volume_id: &str,
+                                timeouts: &EbsTimeouts)
+                                -> Result<(), AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    info!("waiting for volume to attach");
+    let request = describe_volume_request(volume_id);
+    if poll_until(&timeouts.volume_attached,
+                  || check_volume_attached(ec2_client, &request, timeouts))? {
+        Ok(())
+    } else {
+        Err(AttachVolumeError::TimeoutWaitingForVolumeToAttach)
+    }
+}
+
+fn ensure_volume_detached<P, D>(ec2_client: &Ec2Client<P, D>,
+                                volume_id: &str,
+                                timeouts: &EbsTimeouts)
+                                -> Result<(), DetachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    info!("waiting for volume to detach");
+    let request = describe_volume_request(volume_id);
+    let detached = poll_until(&timeouts.volume_detached, || -> Result<bool, DetachVolumeError> {
+        Ok(retry_throttled(timeouts.throttle_retries, || ec2_client.describe_volumes(&request))?
+               .volumes
+               .as_ref()
+               .and_then(|volume_list| volume_list.get(0))
+               .and_then(|volume| volume.state.as_ref())
+               .map_or(false, |state| state == "available"))
+    })?;
+    if detached {
+        Ok(())
+    } else {
+        Err(DetachVolumeError::TimeoutWaitingForVolumeToDetach)
+    }
+}
+
+fn check_volume_attached<P, D>(ec2_client: &Ec2Client<P, D>,
+                               request: &DescribeVolumesRequest,
+                               timeouts: &EbsTimeouts)
+                               -> Result<bool, AttachVolumeError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    trace!("checking DescribeVolumes to see if volume is attached");
+    retry_throttled(timeouts.throttle_retries, || ec2_client.describe_volumes(request))?
+        .volumes
+        .as_ref()
+        .and_then(|volume_list| volume_list.get(0))
+        .and_then(|volume| volume.attachments.as_ref())
+        .and_then(|attachments| attachments.get(0))
+        .and_then(|attachment| attachment.state.as_ref())
+        .map_or(Ok(false), |state| Ok(state == "attached"))
+}
+
+fn describe_volume_request(volume_id: &str) -> DescribeVolumesRequest {
+    DescribeVolumesRequest {
+        dry_run: None,
+        filters: None,
+        max_results: None,
+        next_token: None,
+        volume_ids: Some(vec![String::from(volume_id)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate hyper;
+
+    use super::*;
+    use chrono::{Duration, UTC};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    struct MockProvideAwsCredentials {}
+
+    impl rusoto::ProvideAwsCredentials for MockProvideAwsCredentials {
+        fn credentials(&self) -> Result<rusoto::AwsCredentials, rusoto::CredentialsError> {
+            Ok(rusoto::AwsCredentials::new("key",
+                                           "secret",
+                                           None,
+                                           UTC::now() + Duration::seconds(600)))
+        }
+    }
+
+    struct Ec2RequestDispatcherAttachSpecificVolumeSuccess {}
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherAttachSpecificVolumeSuccess {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            assert!(request.params.get("Device") == Some(&Some(String::from("/dev/xvdh"))));
+            assert!(request.params.get("InstanceId") == Some(&Some(String::from("i-1234"))));
+            assert!(request.params.get("VolumeId") == Some(&Some(String::from("vol-4321"))));
+            assert!(request.params.get("Action") == Some(&Some(String::from("AttachVolume"))));
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: String::from(""),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    struct Ec2RequestDispatcherAttachSpecificVolumeFailure {}
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherAttachSpecificVolumeFailure {
+        fn dispatch(&self,
+                    _: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            Ok(rusoto::HttpResponse {
                    status: hyper::status::StatusCode::BadRequest,
                    body: String::from(""),
                    raw_body: vec![],
@@ -374,6 +2186,427 @@ mod tests {
         }
     }
 
+    /// Rejects AttachVolume at the block devices in `in_use`, and accepts it at any other.
+    struct Ec2RequestDispatcherAttachDeviceInUse {
+        in_use: Vec<&'static str>,
+    }
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherAttachDeviceInUse {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let device = match request.params.get("Device") {
+                Some(&Some(ref device)) => device.to_owned(),
+                _ => panic!("expected Device parameter"),
+            };
+            if self.in_use.contains(&device.as_str()) {
+                Ok(ec2_error_response("InvalidParameterValue",
+                                      &format!("Invalid value '{}' for unixDevice. Attachment point {} is already \
+                                                in use",
+                                               device,
+                                               device)))
+            } else {
+                Ok(rusoto::HttpResponse {
+                       status: hyper::status::StatusCode::Ok,
+                       body: String::from("<AttachVolumeResponse><status>attaching</status></AttachVolumeResponse>"),
+                       raw_body: vec![],
+                       headers: HashMap::new(),
+                   })
+            }
+        }
+    }
+
+    fn ec2_error_response(code: &str, message: &str) -> rusoto::HttpResponse {
+        rusoto::HttpResponse {
+            status: hyper::status::StatusCode::BadRequest,
+            body: format!("<Response><Errors><Error><Code>{}</Code><Message>{}</Message></Error></Errors>\
+                           <RequestID>1</RequestID></Response>",
+                          code,
+                          message),
+            raw_body: vec![],
+            headers: HashMap::new(),
+        }
+    }
+
+    fn set_member(volume_id: &str, set_id: &str, member: &str) -> Volume {
+        Volume {
+            volume_id: Some(String::from(volume_id)),
+            tags: Some(vec![Tag {
+                                key: Some(String::from(SET_ID_TAG)),
+                                value: Some(String::from(set_id)),
+                            },
+                            Tag {
+                                key: Some(String::from(SET_MEMBER_TAG)),
+                                value: Some(String::from(member)),
+                            }]),
+            ..Volume::default()
+        }
+    }
+
+    /// A volume with `tags`, and with the tags of a set member if `set` gives its set id and member number.
+    fn volume_with_tags(volume_id: &str, set: Option<(&str, &str)>, tags: &[(&str, &str)]) -> Volume {
+        let mut vol = match set {
+            Some((set_id, member)) => set_member(volume_id, set_id, member),
+            None => Volume { volume_id: Some(String::from(volume_id)), ..Volume::default() },
+        };
+        let mut all_tags = vol.tags.take().unwrap_or_default();
+        all_tags.extend(tags.iter().map(|&(key, value)| {
+                                            Tag {
+                                                key: Some(String::from(key)),
+                                                value: Some(String::from(value)),
+                                            }
+                                        }));
+        vol.tags = Some(all_tags);
+        vol
+    }
+
+    fn set_volume_ids(set: &BTreeMap<usize, &Volume>) -> Vec<(usize, String)> {
+        set.iter().map(|(member, v)| (*member, v.volume_id.to_owned().unwrap())).collect()
+    }
+
+    #[test]
+    fn test_volume_sets_complete_only() {
+        let volumes = vec![set_member("vol-b1", "set-b", "1"),
+                           set_member("vol-a0", "set-a", "0"),
+                           set_member("vol-c0", "set-c", "0"),
+                           set_member("vol-b0", "set-b", "0"),
+                           set_member("vol-a2", "set-a", "2"),
+                           Volume { volume_id: Some(String::from("vol-single")), ..Volume::default() }];
+        let sets = volume_sets(&volumes, 2, false);
+        assert_eq!(1, sets.len());
+        assert_eq!(vec![(0, String::from("vol-b0")), (1, String::from("vol-b1"))],
+                   set_volume_ids(&sets[0]));
+    }
+
+    #[test]
+    fn test_volume_sets_allow_partial() {
+        let volumes = vec![set_member("vol-a1", "set-a", "1"),
+                           set_member("vol-b0", "set-b", "0"),
+                           set_member("vol-b1", "set-b", "1"),
+                           set_member("vol-b2", "set-b", "2")];
+        let sets = volume_sets(&volumes, 3, true);
+        assert_eq!(2, sets.len());
+        assert_eq!(3, sets[0].len());
+        assert_eq!(vec![(1, String::from("vol-a1"))], set_volume_ids(&sets[1]));
+    }
+
+    #[test]
+    fn test_volume_sets_keep_described_order() {
+        let volumes = vec![set_member("vol-c0", "set-c", "0"),
+                           set_member("vol-a0", "set-a", "0"),
+                           set_member("vol-a1", "set-a", "1"),
+                           set_member("vol-c1", "set-c", "1")];
+        let sets = volume_sets(&volumes, 2, false);
+        assert_eq!(vec![(0, String::from("vol-c0")), (1, String::from("vol-c1"))],
+                   set_volume_ids(&sets[0]));
+        assert_eq!(vec![(0, String::from("vol-a0")), (1, String::from("vol-a1"))],
+                   set_volume_ids(&sets[1]));
+    }
+
+    fn selection_volume(volume_id: &str, create_time: Option<&str>, size: i32, zone_tag: &str) -> Volume {
+        Volume {
+            volume_id: Some(String::from(volume_id)),
+            create_time: create_time.map(String::from),
+            size: Some(size),
+            tags: Some(vec![Tag {
+                                key: Some(String::from("Zone")),
+                                value: Some(String::from(zone_tag)),
+                            }]),
+            ..Volume::default()
+        }
+    }
+
+    fn sorted_volume_ids(selection: VolumeSelection, latest_snapshots: &HashMap<String, String>) -> Vec<String> {
+        let mut volumes = vec![selection_volume("vol-1", Some("2017-03-02T10:00:00.000Z"), 100, "a"),
+                               selection_volume("vol-2", None, 300, "b"),
+                               selection_volume("vol-3", Some("2017-05-01T10:00:00.000Z"), 100, "b"),
+                               selection_volume("vol-4", Some("2017-01-20T10:00:00.000Z"), 200, "a")];
+        sort_by_selection(&mut volumes, &selection, latest_snapshots);
+        volumes.into_iter().map(|vol| vol.volume_id.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_sort_by_selection() {
+        let no_snapshots = HashMap::new();
+        assert_eq!(vec!["vol-3", "vol-1", "vol-4", "vol-2"],
+                   sorted_volume_ids(VolumeSelection::Newest, &no_snapshots));
+        assert_eq!(vec!["vol-4", "vol-1", "vol-3", "vol-2"],
+                   sorted_volume_ids(VolumeSelection::Oldest, &no_snapshots));
+        assert_eq!(vec!["vol-2", "vol-4", "vol-1", "vol-3"],
+                   sorted_volume_ids(VolumeSelection::Largest, &no_snapshots));
+        let preferred = config::PreferredTag {
+            key: String::from("Zone"),
+            value: String::from("b"),
+        };
+        assert_eq!(vec!["vol-2", "vol-3", "vol-1", "vol-4"],
+                   sorted_volume_ids(VolumeSelection::PreferTag(preferred), &no_snapshots));
+
+        let mut latest_snapshots = HashMap::new();
+        latest_snapshots.insert(String::from("vol-4"), String::from("2017-06-01T00:00:00.000Z"));
+        latest_snapshots.insert(String::from("vol-2"), String::from("2017-06-02T00:00:00.000Z"));
+        assert_eq!(vec!["vol-2", "vol-4", "vol-1", "vol-3"],
+                   sorted_volume_ids(VolumeSelection::MostRecentSnapshot, &latest_snapshots));
+    }
+
+    struct Ec2RequestDispatcherDescribeSnapshots {}
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherDescribeSnapshots {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            assert!(request.params.get("Filter.1.Name") == Some(&Some(String::from("volume-id"))));
+            assert!(request.params.get("Owner.1") == Some(&Some(String::from("self"))));
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: String::from("<DescribeSnapshotsResponse><requestId>1</requestId><snapshotSet>\
+                                       <item><snapshotId>snap-1</snapshotId><volumeId>vol-1</volumeId>\
+                                       <startTime>2017-06-01T00:00:00.000Z</startTime></item>\
+                                       <item><snapshotId>snap-2</snapshotId><volumeId>vol-1</volumeId>\
+                                       <startTime>2017-06-03T00:00:00.000Z</startTime></item>\
+                                       <item><snapshotId>snap-3</snapshotId><volumeId>vol-2</volumeId>\
+                                       <startTime>2017-06-02T00:00:00.000Z</startTime></item>\
+                                       </snapshotSet></DescribeSnapshotsResponse>"),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_describe_latest_snapshots() {
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherDescribeSnapshots {},
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let latest = describe_latest_snapshots(vec![String::from("vol-1"), String::from("vol-2")],
+                                               &EbsTimeouts::default(),
+                                               &mock_ec2_client)
+            .unwrap();
+        assert_eq!(2, latest.len());
+        assert_eq!("2017-06-03T00:00:00.000Z", latest["vol-1"]);
+        assert_eq!("2017-06-02T00:00:00.000Z", latest["vol-2"]);
+    }
+
+    #[test]
+    fn test_matching_fingerprint() {
+        let volumes = || {
+            vec![volume_with_tags("vol-1", None, &[(CONFIG_FINGERPRINT_TAG, "ext4/single/none")]),
+                 volume_with_tags("vol-2", None, &[]),
+                 volume_with_tags("vol-3", None, &[(CONFIG_FINGERPRINT_TAG, "xfs/single/none")])]
+        };
+        let mut config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::Skip,
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let volume_ids = |volumes: Vec<Volume>| -> Vec<String> {
+            volumes.into_iter().map(|v| v.volume_id.unwrap()).collect()
+        };
+        assert_eq!(vec!["vol-1", "vol-2"],
+                   volume_ids(matching_fingerprint(volumes(), "ext4/single/none", &config).unwrap()));
+
+        config.fingerprint_mismatch = config::FingerprintMismatch::Fail;
+        assert_eq!(vec!["vol-1", "vol-2"],
+                   volume_ids(matching_fingerprint(volumes(), "ext4/single/none", &config).unwrap()));
+        match matching_fingerprint(volumes().split_off(2), "ext4/single/none", &config) {
+            Err(AttachVolumeError::FingerprintMismatch(volume_id, fingerprint)) => {
+                assert_eq!("vol-3", volume_id);
+                assert_eq!("xfs/single/none", fingerprint);
+            }
+            other => panic!("expected a fingerprint mismatch, got {:?}", other),
+        }
+        assert_eq!(vec!["vol-2", "vol-3"],
+                   volume_ids(matching_fingerprint(volumes().split_off(1), "xfs/single/none", &config).unwrap()));
+
+        // a volume set created for another layout doesn't stop a single volume from being attached
+        let volumes = vec![volume_with_tags("vol-a0",
+                                            Some(("set-a", "0")),
+                                            &[(CONFIG_FINGERPRINT_TAG, "ext4/lvm-2/none")]),
+                           volume_with_tags("vol-1", None, &[(CONFIG_FINGERPRINT_TAG, "ext4/single/none")])];
+        assert_eq!(vec!["vol-1"],
+                   volume_ids(matching_fingerprint(layout_candidates(volumes, false), "ext4/single/none", &config)
+                                  .unwrap()));
+    }
+
+    #[test]
+    fn test_attach_candidates_single() {
+        let volumes = vec![set_member("vol-a0", "set-a", "0"),
+                           Volume { volume_id: Some(String::from("vol-1")), ..Volume::default() },
+                           Volume { volume_id: Some(String::from("vol-2")), ..Volume::default() }];
+        let block_devices = vec![String::from("/dev/xvdf")];
+        assert_eq!(vec![vec![(String::from("/dev/xvdf"), String::from("vol-1"))],
+                        vec![(String::from("/dev/xvdf"), String::from("vol-2"))]],
+                   attach_candidates(&volumes, &block_devices, false, false));
+    }
+
+    #[test]
+    fn test_attach_candidates_volume_set() {
+        let volumes = vec![set_member("vol-a1", "set-a", "1"),
+                           set_member("vol-a0", "set-a", "0"),
+                           Volume { volume_id: Some(String::from("vol-1")), ..Volume::default() }];
+        let block_devices = vec![String::from("/dev/xvdf"), String::from("/dev/xvdg")];
+        assert_eq!(vec![vec![(String::from("/dev/xvdf"), String::from("vol-a0")),
+                             (String::from("/dev/xvdg"), String::from("vol-a1"))]],
+                   attach_candidates(&volumes, &block_devices, true, false));
+    }
+
+    struct Ec2RequestDispatcherDryRun {}
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherDryRun {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            assert!(request.params.get("DryRun") == Some(&Some(String::from("true"))));
+            let code = match request.params.get("Action") {
+                Some(&Some(ref action)) if action == "CreateTags" => "UnauthorizedOperation",
+                Some(&Some(ref action)) if action == "AttachVolume" => "InvalidVolume.NotFound",
+                _ => "DryRunOperation",
+            };
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::PreconditionFailed,
+                   body: format!("<Response><Errors><Error><Code>{}</Code><Message></Message></Error></Errors>\
+                                  <RequestID>1</RequestID></Response>",
+                                 code),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    struct Ec2RequestDispatcherDescribeTags {}
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherDescribeTags {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            assert!(request.params.get("Action") == Some(&Some(String::from("DescribeTags"))));
+            assert!(request.params.get("Filter.1.Name") == Some(&Some(String::from("resource-id"))));
+            assert!(request.params.get("Filter.1.Value.1") == Some(&Some(String::from("i-1234"))));
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: String::from("<DescribeTagsResponse><requestId>1</requestId><tagSet>\
+                                       <item><resourceId>i-1234</resourceId><resourceType>instance</resourceType>\
+                                       <key>Name</key><value>db-1</value></item>\
+                                       <item><resourceId>i-1234</resourceId><resourceType>instance</resourceType>\
+                                       <key>Role</key><value>PostgreSQL</value></item>\
+                                       </tagSet></DescribeTagsResponse>"),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_describe_instance_tags() {
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherDescribeTags {},
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let tags = describe_instance_tags("i-1234", &mock_ec2_client).unwrap();
+        assert_eq!(2, tags.len());
+        assert_eq!(Some(&String::from("db-1")), tags.get("Name"));
+        assert_eq!(Some(&String::from("PostgreSQL")), tags.get("Role"));
+    }
+
+    struct Ec2RequestDispatcherDescribeInstances {}
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherDescribeInstances {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            assert!(request.params.get("InstanceId.1") == Some(&Some(String::from("i-1234"))));
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: String::from("<DescribeInstancesResponse><requestId>1</requestId><reservationSet><item>\
+                                       <reservationId>r-1</reservationId><instancesSet><item>\
+                                       <instanceId>i-1234</instanceId><rootDeviceName>/dev/xvda</rootDeviceName>\
+                                       <blockDeviceMapping>\
+                                       <item><deviceName>/dev/xvda</deviceName></item>\
+                                       <item><deviceName>/dev/sdf</deviceName></item>\
+                                       </blockDeviceMapping></item></instancesSet></item></reservationSet>\
+                                       </DescribeInstancesResponse>"),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_describe_instance_block_devices() {
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherDescribeInstances {},
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        assert_eq!(vec!["/dev/xvda", "/dev/sdf"],
+                   describe_instance_block_devices("i-1234", &mock_ec2_client).unwrap());
+    }
+
+    #[test]
+    fn test_dry_run_attach_calls() {
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherDryRun {},
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let checks = dry_run_attach_calls("i-1234",
+                                          "us-west-2a",
+                                          "/dev/xvdf",
+                                          PLACEHOLDER_VOLUME_ID,
+                                          &config,
+                                          &mock_ec2_client);
+        assert_eq!(3, checks.len());
+        assert_eq!(("ec2:CreateVolume", PermissionCheck::Allowed), checks[0]);
+        assert_eq!(("ec2:CreateTags", PermissionCheck::Denied), checks[1]);
+        match checks[2] {
+            ("ec2:AttachVolume", PermissionCheck::Unverified(ref err)) => assert!(err.contains("NotFound")),
+            ref other => assert!(false, "unexpected check {:?}", other),
+        }
+    }
+
+    struct MockProvideNoAwsCredentials {}
+
+    impl rusoto::ProvideAwsCredentials for MockProvideNoAwsCredentials {
+        fn credentials(&self) -> Result<rusoto::AwsCredentials, rusoto::CredentialsError> {
+            Err(rusoto::CredentialsError { message: String::from("no instance profile") })
+        }
+    }
+
+    #[test]
+    fn test_dry_run_attach_calls_without_credentials() {
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherDryRun {},
+                                                          MockProvideNoAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let checks = dry_run_attach_calls("i-1234",
+                                          "us-west-2a",
+                                          "/dev/xvdf",
+                                          PLACEHOLDER_VOLUME_ID,
+                                          &config,
+                                          &mock_ec2_client);
+        assert_eq!(3, checks.len());
+        for check in checks {
+            match check {
+                (_, PermissionCheck::Failed(ref err)) => assert!(err.contains("no instance profile")),
+                ref other => assert!(false, "unexpected check {:?}", other),
+            }
+        }
+    }
 
     #[test]
     fn test_attach_specific_volume_success() {
@@ -381,7 +2614,11 @@ mod tests {
         let mock_ec2_client = rusoto::ec2::Ec2Client::new(mock_request_dispatcher,
                                                           MockProvideAwsCredentials {},
                                                           rusoto::Region::UsWest2);
-        let result = attach_specific_volume("/dev/xvdh", "i-1234", "vol-4321", &mock_ec2_client);
+        let result = attach_specific_volume("/dev/xvdh",
+                                            "i-1234",
+                                            "vol-4321",
+                                            &EbsTimeouts::default(),
+                                            &mock_ec2_client);
         result.expect("success test case");
     }
 
@@ -391,7 +2628,627 @@ mod tests {
         let mock_ec2_client = rusoto::ec2::Ec2Client::new(mock_request_dispatcher,
                                                           MockProvideAwsCredentials {},
                                                           rusoto::Region::UsWest2);
-        let result = attach_specific_volume("/dev/xvdh", "i-1234", "vol-4321", &mock_ec2_client);
+        let result = attach_specific_volume("/dev/xvdh",
+                                            "i-1234",
+                                            "vol-4321",
+                                            &EbsTimeouts::default(),
+                                            &mock_ec2_client);
         assert!(result.is_err())
     }
+
+    #[test]
+    fn test_classifies_attach_errors() {
+        let classify = |code: &str, message: &str| {
+            let err = rusoto::ec2::AttachVolumeError::from_body(&ec2_error_response(code, message).body);
+            classify_attach_error(&err)
+        };
+        assert_eq!(AttachFailure::VolumeInUse,
+                   classify("VolumeInUse", "vol-1234 is already attached to an instance"));
+        assert_eq!(AttachFailure::IncorrectState,
+                   classify("IncorrectState", "vol-1234 is not 'available'."));
+        assert_eq!(AttachFailure::ZoneMismatch,
+                   classify("InvalidVolume.ZoneMismatch", "The volume is not in the same availability zone"));
+        assert_eq!(AttachFailure::DeviceInUse,
+                   classify("InvalidParameterValue", "Attachment point /dev/xvdc is already in use"));
+        assert_eq!(AttachFailure::PermissionDenied,
+                   classify("UnauthorizedOperation", "You are not authorized to perform this operation."));
+        assert_eq!(AttachFailure::Throttled, classify("RequestLimitExceeded", "Request limit exceeded."));
+        assert_eq!(AttachFailure::Other, classify("InvalidVolume.NotFound", "The volume does not exist."));
+    }
+
+    #[test]
+    fn test_attaches_at_next_free_block_device() {
+        let mock_request_dispatcher = Ec2RequestDispatcherAttachDeviceInUse { in_use: vec!["/dev/xvdc", "/dev/xvdd"] };
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(mock_request_dispatcher,
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let block_devices = vec![String::from("/dev/xvdc"), String::from("/dev/xvdd"), String::from("/dev/xvde")];
+        let mut device = 0;
+        attach_at_free_block_device(&block_devices,
+                                    &mut device,
+                                    "i-1234",
+                                    "vol-4321",
+                                    &EbsTimeouts::default(),
+                                    &mock_ec2_client)
+            .unwrap();
+        assert_eq!(2, device);
+
+        let mut device = 0;
+        let err = attach_at_free_block_device(&block_devices[..2],
+                                              &mut device,
+                                              "i-1234",
+                                              "vol-4321",
+                                              &EbsTimeouts::default(),
+                                              &mock_ec2_client)
+            .unwrap_err();
+        assert_eq!(AttachFailure::DeviceInUse, classify_attach_error(&err));
+        assert_eq!(1, device);
+    }
+
+    /// Creates volumes until `capacity` have been created, then fails with InsufficientVolumeCapacity, describes every
+    /// volume as available and no other volumes, describes snapshots with `snapshot_fingerprint`, and records the
+    /// other requests made.
+    struct Ec2RequestDispatcherCreateVolume {
+        capacity: usize,
+        snapshot_fingerprint: Option<&'static str>,
+        actions: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherCreateVolume {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let param = |name: &str| match request.params.get(name) {
+                Some(&Some(ref value)) => value.to_owned(),
+                _ => String::new(),
+            };
+            let action = param("Action");
+            let created = self.actions.borrow().iter().filter(|a| a.starts_with("CreateVolume")).count();
+            let body = match action.as_str() {
+                "CreateVolume" if created == self.capacity => {
+                    return Ok(ec2_error_response("InsufficientVolumeCapacity",
+                                                 "There is not enough capacity to fulfill your request."));
+                }
+                "CreateVolume" => {
+                    format!("<CreateVolumeResponse><requestId>1</requestId><volumeId>vol-{}</volumeId>\
+                             <status>creating</status></CreateVolumeResponse>",
+                            created + 1)
+                }
+                "DescribeVolumes" if param("VolumeId.1").is_empty() => {
+                    String::from("<DescribeVolumesResponse><requestId>1</requestId><volumeSet/>\
+                                  </DescribeVolumesResponse>")
+                }
+                "DescribeVolumes" => {
+                    format!("<DescribeVolumesResponse><requestId>1</requestId><volumeSet><item>\
+                             <volumeId>{}</volumeId><status>available</status></item></volumeSet>\
+                             </DescribeVolumesResponse>",
+                            param("VolumeId.1"))
+                }
+                "DescribeSnapshots" => {
+                    let tags = self.snapshot_fingerprint.map_or(String::new(), |fingerprint| {
+                        format!("<item><key>{}</key><value>{}</value></item>", CONFIG_FINGERPRINT_TAG, fingerprint)
+                    });
+                    format!("<DescribeSnapshotsResponse><requestId>1</requestId><snapshotSet><item>\
+                             <snapshotId>{}</snapshotId><tagSet>{}</tagSet></item></snapshotSet>\
+                             </DescribeSnapshotsResponse>",
+                            param("SnapshotId.1"),
+                            tags)
+                }
+                _ => String::new(),
+            };
+            if !action.starts_with("Describe") {
+                let detail = match action.as_str() {
+                    "CreateVolume" if param("SnapshotId").is_empty() => format!("{}", created + 1),
+                    "CreateVolume" => format!("{} {}", created + 1, param("SnapshotId")),
+                    "CreateTags" => {
+                        let fingerprint = (1..10)
+                            .find(|i| param(&format!("Tag.{}.Key", i)) == CONFIG_FINGERPRINT_TAG)
+                            .map_or(String::from("(no fingerprint)"), |i| param(&format!("Tag.{}.Value", i)));
+                        format!("{} {}", param("ResourceId.1"), fingerprint)
+                    }
+                    _ => param("VolumeId"),
+                };
+                self.actions.borrow_mut().push(format!("{} {}", action, detail));
+            }
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: body,
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_deletes_incomplete_volume_set() {
+        let actions = Rc::new(RefCell::new(Vec::new()));
+        let mock_request_dispatcher = Ec2RequestDispatcherCreateVolume {
+            capacity: 2,
+            snapshot_fingerprint: None,
+            actions: actions.clone(),
+        };
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(mock_request_dispatcher,
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let block_devices = vec![String::from("/dev/xvdf"), String::from("/dev/xvdg"), String::from("/dev/xvdh")];
+        match create_and_attach_volume_set(&mock_ec2_client,
+                                           &config,
+                                           "ext4/lvm-3/none",
+                                           "us-west-2a",
+                                           &block_devices,
+                                           "i-1234") {
+            Err(AttachVolumeError::CreatingVolumeFailed(_)) => {}
+            other => panic!("expected CreatingVolumeFailed, got {:?}", other),
+        }
+        assert_eq!(vec!["CreateVolume 1",
+                        "CreateTags vol-1 ext4/lvm-3/none",
+                        "CreateVolume 2",
+                        "CreateTags vol-2 ext4/lvm-3/none",
+                        "DeleteVolume vol-1",
+                        "DeleteVolume vol-2"],
+                   *actions.borrow());
+    }
+
+    #[test]
+    fn test_deletes_incomplete_pooled_volume_set() {
+        let actions = Rc::new(RefCell::new(Vec::new()));
+        let mock_request_dispatcher = Ec2RequestDispatcherCreateVolume {
+            capacity: 3,
+            snapshot_fingerprint: None,
+            actions: actions.clone(),
+        };
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(mock_request_dispatcher,
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let availability_zones = vec![String::from("us-west-2a")];
+        let request = PoolRequest {
+            availability_zones: &availability_zones,
+            size: 2,
+            snapshot_id: None,
+            fingerprint: "ext4/lvm-2/none",
+            members: 2,
+        };
+        match fill_volume_pool(&config, &request, "i-1234", false, &mock_ec2_client) {
+            Err(PoolError::CreatingVolumeFailed(_)) => {}
+            other => panic!("expected CreatingVolumeFailed, got {:?}", other),
+        }
+        // the first set is complete, so it's kept for an instance to attach
+        assert_eq!(vec!["CreateVolume 1",
+                        "CreateTags vol-1 ext4/lvm-2/none",
+                        "CreateVolume 2",
+                        "CreateTags vol-2 ext4/lvm-2/none",
+                        "CreateVolume 3",
+                        "CreateTags vol-3 ext4/lvm-2/none",
+                        "DeleteVolume vol-3"],
+                   *actions.borrow());
+    }
+
+    #[test]
+    fn test_checks_pool_snapshot_fingerprint() {
+        let fill = |snapshot_fingerprint: Option<&'static str>| {
+            let actions = Rc::new(RefCell::new(Vec::new()));
+            let mock_request_dispatcher = Ec2RequestDispatcherCreateVolume {
+                capacity: 1,
+                snapshot_fingerprint: snapshot_fingerprint,
+                actions: actions.clone(),
+            };
+            let mock_ec2_client = rusoto::ec2::Ec2Client::new(mock_request_dispatcher,
+                                                              MockProvideAwsCredentials {},
+                                                              rusoto::Region::UsWest2);
+            let config = EbsBlockProviderConfig {
+                ebs_tags: HashMap::new(),
+                selection: None,
+                fingerprint_mismatch: config::FingerprintMismatch::default(),
+                pool: None,
+                timeouts: EbsTimeouts::default(),
+                size: 200,
+                volume_type: String::from("gp2"),
+            };
+            let availability_zones = vec![String::from("us-west-2a")];
+            let request = PoolRequest {
+                availability_zones: &availability_zones,
+                size: 1,
+                snapshot_id: Some("snap-1"),
+                fingerprint: "ext4/single/luks",
+                members: 1,
+            };
+            let result = fill_volume_pool(&config, &request, "i-1234", false, &mock_ec2_client);
+            let actions = actions.borrow().clone();
+            (result, actions)
+        };
+
+        let (result, actions) = fill(Some("ext4/single/luks"));
+        assert!(result.is_ok());
+        assert_eq!(vec!["CreateVolume 1 snap-1", "CreateTags vol-1 ext4/single/luks"], actions);
+
+        let (result, actions) = fill(None);
+        assert!(result.is_ok());
+        assert_eq!(vec!["CreateVolume 1 snap-1", "CreateTags vol-1 (no fingerprint)"], actions);
+
+        match fill(Some("ext4/single/none")) {
+            (Err(PoolError::SnapshotFingerprintMismatch(snapshot_id, fingerprint)), actions) => {
+                assert_eq!("snap-1", snapshot_id);
+                assert_eq!("ext4/single/none", fingerprint);
+                assert!(actions.is_empty());
+            }
+            (other, _) => panic!("expected a snapshot fingerprint mismatch, got {:?}", other),
+        }
+    }
+
+    /// Describes the volumes in `attached`, as (volume id, block device, set member) triples, as attached to the
+    /// instance filtered for, at the block devices filtered for.
+    struct Ec2RequestDispatcherDescribeAttached {
+        attached: Vec<(&'static str, &'static str, Option<&'static str>)>,
+    }
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherDescribeAttached {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let param = |name: &str| match request.params.get(name) {
+                Some(&Some(ref value)) => value.to_owned(),
+                _ => String::new(),
+            };
+            assert_eq!("DescribeVolumes", param("Action"));
+            let filter_values = |filter_name: &str| -> Vec<String> {
+                let filter = (1..10).find(|i| param(&format!("Filter.{}.Name", i)) == filter_name).unwrap();
+                (1..20)
+                    .map(|i| param(&format!("Filter.{}.Value.{}", filter, i)))
+                    .take_while(|value| !value.is_empty())
+                    .collect()
+            };
+            let instance_id = filter_values("attachment.instance-id").remove(0);
+            let devices = filter_values("attachment.device");
+            let mut items = String::new();
+            for &(volume_id, device, member) in self.attached.iter().filter(|a| devices.iter().any(|d| d == a.1)) {
+                let tags = member.map_or(String::new(), |member| {
+                    format!("<item><key>{}</key><value>set-a</value></item>\
+                             <item><key>{}</key><value>{}</value></item>",
+                            SET_ID_TAG,
+                            SET_MEMBER_TAG,
+                            member)
+                });
+                items.push_str(&format!("<item><volumeId>{}</volumeId><status>in-use</status><attachmentSet><item>\
+                                         <volumeId>{}</volumeId><instanceId>{}</instanceId><device>{}</device>\
+                                         <status>attached</status></item></attachmentSet><tagSet>{}</tagSet></item>",
+                                        volume_id,
+                                        volume_id,
+                                        instance_id,
+                                        device,
+                                        tags));
+            }
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: format!("<DescribeVolumesResponse><requestId>1</requestId><volumeSet>{}</volumeSet>\
+                                  </DescribeVolumesResponse>",
+                                 items),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_finds_volumes_already_attached() {
+        let config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let client = |attached| {
+            rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherDescribeAttached { attached: attached },
+                                        MockProvideAwsCredentials {},
+                                        rusoto::Region::UsWest2)
+        };
+        let block_devices = vec![String::from("/dev/xvdf"), String::from("/dev/xvdg"), String::from("/dev/xvdh")];
+
+        // the configured block device is held by a matching volume, which is reused rather than attaching another
+        let attached = attached_volumes("i-1234", &block_devices, &config, &client(vec![("vol-1", "/dev/xvdf", None)]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![(String::from("/dev/xvdf"), String::from("vol-1"))], attached.volumes);
+        assert!(!attached.created);
+
+        let attached = attached_volumes("i-1234",
+                                        &block_devices,
+                                        &config,
+                                        &client(vec![("vol-a1", "/dev/xvdg", Some("1")),
+                                                     ("vol-a0", "/dev/xvdf", Some("0"))]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![(String::from("/dev/xvdf"), String::from("vol-a0")),
+                        (String::from("/dev/xvdg"), String::from("vol-a1"))],
+                   attached.volumes);
+
+        assert!(attached_volumes("i-1234", &block_devices[1..], &config, &client(vec![("vol-1", "/dev/xvdf", None)]))
+                    .unwrap()
+                    .is_none());
+    }
+
+    fn gc_actions(collected: &[GcVolume]) -> Vec<(&str, GcAction)> {
+        collected.iter().map(|vol| (vol.volume_id.as_str(), vol.action.clone())).collect()
+    }
+
+    #[test]
+    fn test_plan_gc() {
+        let now = DateTime::parse_from_rfc3339("2017-06-10T12:00:00+00:00").unwrap().with_timezone(&UTC);
+        let in_state = |state: &str, vol: Volume| Volume { state: Some(String::from(state)), ..vol };
+        let since_may = [(AVAILABLE_SINCE_TAG, "2017-05-01T12:00:00+00:00")];
+        let since_june = [(AVAILABLE_SINCE_TAG, "2017-06-01T12:00:00+00:00")];
+        let since_yesterday = [(AVAILABLE_SINCE_TAG, "2017-06-09T12:00:00+00:00")];
+        let since_today = [(AVAILABLE_SINCE_TAG, "2017-06-10T06:00:00+00:00")];
+        let volumes = vec![in_state("available", volume_with_tags("vol-new", None, &[])),
+                           in_state("in-use", volume_with_tags("vol-attached", None, &[])),
+                           in_state("available", volume_with_tags("vol-recent", None, &since_today)),
+                           in_state("available", volume_with_tags("vol-old", None, &since_june)),
+                           in_state("available", volume_with_tags("vol-older", None, &since_may)),
+                           in_state("available", volume_with_tags("vol-a0", Some(("set-a", "0")), &since_may)),
+                           in_state("available", volume_with_tags("vol-a1", Some(("set-a", "1")), &since_yesterday)),
+                           in_state("available", volume_with_tags("vol-b0", Some(("set-b", "0")), &since_may)),
+                           in_state("in-use", volume_with_tags("vol-b1", Some(("set-b", "1")), &[]))];
+        let deleted = GcAction::Deleted { snapshot_id: None };
+        assert_eq!(vec![("vol-new", GcAction::Marked),
+                        ("vol-recent", GcAction::Retained),
+                        ("vol-old", deleted.clone()),
+                        ("vol-older", deleted.clone()),
+                        ("vol-a0", deleted.clone()),
+                        ("vol-a1", deleted.clone()),
+                        ("vol-b0", GcAction::Retained)],
+                   gc_actions(&plan_gc(&volumes, now, Duration::hours(24), 0)));
+        assert_eq!(vec![("vol-new", GcAction::Marked),
+                        ("vol-recent", GcAction::Retained),
+                        ("vol-old", GcAction::Kept),
+                        ("vol-older", deleted.clone()),
+                        ("vol-a0", GcAction::Kept),
+                        ("vol-a1", GcAction::Kept),
+                        ("vol-b0", GcAction::Retained)],
+                   gc_actions(&plan_gc(&volumes, now, Duration::hours(24), 4)));
+        let collected = plan_gc(&volumes, now, Duration::hours(24), 0);
+        assert_eq!(now, collected[0].available_since);
+
+        let pooled_tags = [since_may[0], (POOLED_BY_TAG, "i-1")];
+        let reused_tags = [since_may[0], (POOLED_BY_TAG, "i-1"), (LAST_ATTACHED_INSTANCE_TAG, "i-2")];
+        let pooled = in_state("available", volume_with_tags("vol-pooled", None, &pooled_tags));
+        let reused = in_state("available", volume_with_tags("vol-reused", None, &reused_tags));
+        assert_eq!(vec![("vol-pooled", GcAction::Retained), ("vol-reused", deleted.clone())],
+                   gc_actions(&plan_gc(&[pooled.clone(), reused.clone()], now, Duration::hours(24), 0)));
+        assert_eq!(vec![("vol-pooled", GcAction::Retained), ("vol-reused", GcAction::Kept)],
+                   gc_actions(&plan_gc(&[pooled, reused], now, Duration::hours(24), 1)));
+    }
+
+    #[test]
+    fn test_pool_available() {
+        let in_zone = |zone: &str, vol: Volume| Volume { availability_zone: Some(String::from(zone)), ..vol };
+        let single = [(CONFIG_FINGERPRINT_TAG, "ext4/single/none")];
+        let lvm = [(CONFIG_FINGERPRINT_TAG, "ext4/lvm-2/none")];
+        let volumes = vec![in_zone("us-west-2a", volume_with_tags("vol-1", None, &[])),
+                           in_zone("us-west-2a", volume_with_tags("vol-2", None, &single)),
+                           in_zone("us-west-2a",
+                                   volume_with_tags("vol-3", None, &[(CONFIG_FINGERPRINT_TAG, "xfs/single/none")])),
+                           in_zone("us-west-2b", volume_with_tags("vol-4", None, &single)),
+                           in_zone("us-west-2a", volume_with_tags("vol-a0", Some(("set-a", "0")), &lvm)),
+                           in_zone("us-west-2a", volume_with_tags("vol-a1", Some(("set-a", "1")), &lvm)),
+                           in_zone("us-west-2a", volume_with_tags("vol-b0", Some(("set-b", "0")), &lvm))];
+        assert_eq!(2, pool_available(&volumes, "us-west-2a", "ext4/single/none", 1));
+        assert_eq!(1, pool_available(&volumes, "us-west-2b", "ext4/single/none", 1));
+        assert_eq!(0, pool_available(&volumes, "us-west-2c", "ext4/single/none", 1));
+        assert_eq!(1, pool_available(&volumes, "us-west-2a", "ext4/lvm-2/none", 2));
+        assert_eq!(0, pool_available(&volumes, "us-west-2a", "xfs/lvm-2/none", 2));
+    }
+
+    /// Describes one available volume that's past any retention period and one newly available volume, and records
+    /// the other requests made.
+    struct Ec2RequestDispatcherGc {
+        actions: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherGc {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let param = |name: &str| match request.params.get(name) {
+                Some(&Some(ref value)) => value.to_owned(),
+                _ => String::new(),
+            };
+            let action = param("Action");
+            let body = match action.as_str() {
+                "DescribeVolumes" => {
+                    String::from("<DescribeVolumesResponse><requestId>1</requestId><volumeSet>\
+                                  <item><volumeId>vol-old</volumeId><status>available</status><tagSet>\
+                                  <item><key>Role</key><value>db</value></item>\
+                                  <item><key>cps:available-since</key><value>2017-01-01T00:00:00+00:00</value></item>\
+                                  </tagSet></item>\
+                                  <item><volumeId>vol-new</volumeId><status>available</status><tagSet>\
+                                  <item><key>Role</key><value>db</value></item></tagSet></item>\
+                                  </volumeSet></DescribeVolumesResponse>")
+                }
+                "CreateSnapshot" => {
+                    String::from("<CreateSnapshotResponse><requestId>1</requestId><snapshotId>snap-1</snapshotId>\
+                                  <volumeId>vol-old</volumeId><status>pending</status></CreateSnapshotResponse>")
+                }
+                _ => String::new(),
+            };
+            if action != "DescribeVolumes" {
+                let detail = match action.as_str() {
+                    "CreateTags" => format!("{} {}", param("ResourceId.1"), param("Tag.1.Key")),
+                    _ => param("VolumeId"),
+                };
+                self.actions.borrow_mut().push(format!("{} {}", action, detail));
+            }
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: body,
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_collect_volume_garbage() {
+        let actions = Rc::new(RefCell::new(Vec::new()));
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherGc { actions: actions.clone() },
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let config = EbsBlockProviderConfig {
+            ebs_tags: HashMap::new(),
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let mut options = GcOptions {
+            retention: Duration::hours(24),
+            keep: 0,
+            snapshot: true,
+            dry_run: true,
+        };
+        let collected = collect_volume_garbage(&config, &options, UTC::now(), &mock_ec2_client).unwrap();
+        assert_eq!(vec![("vol-old", GcAction::Deleted { snapshot_id: None }), ("vol-new", GcAction::Marked)],
+                   gc_actions(&collected));
+
+        options.dry_run = false;
+        let collected = collect_volume_garbage(&config, &options, UTC::now(), &mock_ec2_client).unwrap();
+        assert_eq!(vec![("vol-old", GcAction::Deleted { snapshot_id: Some(String::from("snap-1")) }),
+                        ("vol-new", GcAction::Marked)],
+                   gc_actions(&collected));
+        assert_eq!(vec!["CreateTags vol-new cps:available-since",
+                        "CreateSnapshot vol-old",
+                        "CreateTags snap-1 Role",
+                        "DeleteVolume vol-old"],
+                   *actions.borrow());
+    }
+
+    fn utc(time: &str) -> DateTime<UTC> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&UTC)
+    }
+
+    #[test]
+    fn test_retained_snapshot_times() {
+        // Thursday 2017-06-15 back to Sunday 2017-06-04, in three ISO weeks
+        let times = vec![utc("2017-06-15T10:30:00+00:00"),
+                         utc("2017-06-15T10:00:00+00:00"),
+                         utc("2017-06-15T09:00:00+00:00"),
+                         utc("2017-06-14T23:00:00+00:00"),
+                         utc("2017-06-14T08:00:00+00:00"),
+                         utc("2017-06-12T08:00:00+00:00"),
+                         utc("2017-06-11T08:00:00+00:00"),
+                         utc("2017-06-04T08:00:00+00:00")];
+        let retained = |hourly, daily, weekly| {
+            let retention = SnapshotRetention {
+                hourly: hourly,
+                daily: daily,
+                weekly: weekly,
+            };
+            let mut retained: Vec<String> =
+                retained_snapshot_times(&times, &retention).iter().map(|time| time.to_rfc3339()).collect();
+            retained.sort();
+            retained
+        };
+        assert_eq!(vec!["2017-06-15T09:00:00+00:00", "2017-06-15T10:30:00+00:00"], retained(2, 0, 0));
+        assert_eq!(vec!["2017-06-12T08:00:00+00:00", "2017-06-14T23:00:00+00:00", "2017-06-15T10:30:00+00:00"],
+                   retained(0, 3, 0));
+        assert_eq!(vec!["2017-06-04T08:00:00+00:00", "2017-06-11T08:00:00+00:00", "2017-06-15T10:30:00+00:00"],
+                   retained(0, 0, 5));
+        assert_eq!(vec!["2017-06-11T08:00:00+00:00", "2017-06-14T23:00:00+00:00", "2017-06-15T09:00:00+00:00",
+                        "2017-06-15T10:30:00+00:00"],
+                   retained(2, 2, 2));
+        assert_eq!(0, retained(0, 0, 0).len());
+    }
+
+    /// Accepts CreateSnapshot and CreateTags, recording the tags requested.
+    struct Ec2RequestDispatcherCreateSnapshot {
+        tags: Rc<RefCell<Vec<(String, String)>>>,
+    }
+
+    impl rusoto::DispatchSignedRequest for Ec2RequestDispatcherCreateSnapshot {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let param = |name: String| match request.params.get(&name) {
+                Some(&Some(ref value)) => Some(value.to_owned()),
+                _ => None,
+            };
+            let body = match param(String::from("Action")).unwrap().as_str() {
+                "CreateSnapshot" => {
+                    assert_eq!(Some(String::from("vol-a1")), param(String::from("VolumeId")));
+                    String::from("<CreateSnapshotResponse><requestId>1</requestId><snapshotId>snap-1</snapshotId>\
+                                  <volumeId>vol-a1</volumeId><status>pending</status></CreateSnapshotResponse>")
+                }
+                "CreateTags" => {
+                    assert_eq!(Some(String::from("snap-1")), param(String::from("ResourceId.1")));
+                    let mut n = 1;
+                    while let Some(key) = param(format!("Tag.{}.Key", n)) {
+                        let value = param(format!("Tag.{}.Value", n)).unwrap_or_default();
+                        self.tags.borrow_mut().push((key, value));
+                        n += 1;
+                    }
+                    String::new()
+                }
+                action => panic!("unexpected action {}", action),
+            };
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: body,
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_create_snapshots() {
+        let tags = Rc::new(RefCell::new(Vec::new()));
+        let mock_ec2_client = rusoto::ec2::Ec2Client::new(Ec2RequestDispatcherCreateSnapshot { tags: tags.clone() },
+                                                          MockProvideAwsCredentials {},
+                                                          rusoto::Region::UsWest2);
+        let mut ebs_tags = HashMap::new();
+        ebs_tags.insert(String::from("Role"), String::from("db"));
+        let config = EbsBlockProviderConfig {
+            ebs_tags: ebs_tags,
+            selection: None,
+            fingerprint_mismatch: config::FingerprintMismatch::default(),
+            pool: None,
+            timeouts: EbsTimeouts::default(),
+            size: 200,
+            volume_type: String::from("gp2"),
+        };
+        let volumes = [set_member("vol-a1", "set-a", "1")];
+        let snapshot_ids = create_snapshots(&volumes, 0, &mock_ec2_client).unwrap();
+        assert_eq!(vec![String::from("snap-1")], snapshot_ids);
+        assert!(tags.borrow().is_empty());
+        tag_snapshots(&volumes, &snapshot_ids, &config, utc("2017-06-15T10:30:00+00:00"), &mock_ec2_client).unwrap();
+        let mut tags = tags.borrow().clone();
+        tags.sort();
+        assert_eq!(vec![(String::from("Role"), String::from("db")),
+                        (String::from(SET_ID_TAG), String::from("set-a")),
+                        (String::from(SET_MEMBER_TAG), String::from("1")),
+                        (String::from(SNAPSHOT_OF_TAG), String::from("set-a")),
+                        (String::from(SNAPSHOT_TIME_TAG), String::from("2017-06-15T10:30:00+00:00"))],
+                   tags);
+    }
 }
@@ -0,0 +1,165 @@
+use std;
+use std::process::{Command, Stdio};
+use config::{Fsck, FsckErrorAction};
+use mkfs::{self, FilesystemType};
+
+/// Exit status of coreutils `timeout` when the command it ran was killed for exceeding the time limit.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Part of the message xfs_repair prints, with or without -n, when the filesystem's log holds changes that haven't
+/// been written back, as after an unclean shutdown.
+const XFS_DIRTY_LOG_MESSAGE: &'static str = "valuable metadata changes in a log";
+
+#[derive(Debug)]
+pub enum FsckError {
+    SpawnFailed(std::io::Error),
+    DetectFilesystemFailed(mkfs::MakeFilesystemError),
+    TimedOut(String),
+    CheckerFailed(Option<i32>, String),
+    UncorrectedErrors(String),
+}
+
+impl From<std::io::Error> for FsckError {
+    fn from(err: std::io::Error) -> FsckError {
+        FsckError::SpawnFailed(err)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CheckResult {
+    Clean,
+    /// The filesystem wasn't unmounted cleanly, and its log will be replayed when it's mounted.
+    LogReplayNeeded,
+    ErrorsCorrected,
+    ErrorsUncorrected,
+    CheckerFailed,
+}
+
+/// Checks the existing filesystem on `block_device` and decides how it may be mounted.
+///
+/// Returns `Ok(true)` if the filesystem should be mounted read-only.  Errors that the checker could not correct are
+/// handled according to `on-errors`; a checker that times out or fails to run always results in an error, since the
+/// state of the filesystem is unknown.
+pub fn check_filesystem(config: &Fsck, block_device: &str) -> Result<bool, FsckError> {
+    let filesystem_type = mkfs::filesystem_type(block_device).map_err(FsckError::DetectFilesystemFailed)?;
+    let mut cmd = Command::new("/usr/bin/timeout");
+    cmd.arg(config.timeout.to_string());
+    let classify: fn(Option<i32>, &str) -> CheckResult = match filesystem_type {
+        Some(FilesystemType::Ext) => {
+            cmd.arg("/sbin/e2fsck").arg("-p");
+            classify_e2fsck
+        }
+        Some(FilesystemType::Xfs) => {
+            cmd.arg("/sbin/xfs_repair").arg("-n");
+            classify_xfs_repair
+        }
+        None => {
+            warn!("no filesystem check available for the filesystem on {}; skipping", block_device);
+            return Ok(false);
+        }
+    };
+    cmd.arg(block_device);
+
+    trace!("invoking fsck: {:?}", cmd);
+    let result = try!(cmd.stdin(Stdio::null()).output());
+    let mut output = String::from_utf8_lossy(&result.stdout).into_owned();
+    output.push_str(&String::from_utf8_lossy(&result.stderr));
+
+    let exit_code = result.status.code();
+    if exit_code == Some(TIMEOUT_EXIT_CODE) {
+        return Err(FsckError::TimedOut(output));
+    }
+    match classify(exit_code, &output) {
+        CheckResult::Clean => {
+            info!("filesystem on {} is clean", block_device);
+            Ok(false)
+        }
+        CheckResult::LogReplayNeeded => {
+            warn!("filesystem on {} was not unmounted cleanly; its log will be replayed when it's mounted",
+                  block_device);
+            Ok(false)
+        }
+        CheckResult::ErrorsCorrected => {
+            warn!("filesystem errors on {} were corrected: {}", block_device, output);
+            Ok(false)
+        }
+        CheckResult::ErrorsUncorrected => {
+            match config.on_errors {
+                FsckErrorAction::Abort => Err(FsckError::UncorrectedErrors(output)),
+                FsckErrorAction::ReadOnly => {
+                    warn!("filesystem on {} has uncorrected errors; mounting read-only: {}", block_device, output);
+                    Ok(true)
+                }
+                FsckErrorAction::Mount => {
+                    warn!("filesystem on {} has uncorrected errors; mounting anyway: {}", block_device, output);
+                    Ok(false)
+                }
+            }
+        }
+        CheckResult::CheckerFailed => Err(FsckError::CheckerFailed(exit_code, output)),
+    }
+}
+
+/// e2fsck exit codes are a bit mask: 1 and 2 mean errors were corrected, 4 means errors were left uncorrected, and
+/// 8 and above mean the check itself failed.
+fn classify_e2fsck(exit_code: Option<i32>, _output: &str) -> CheckResult {
+    match exit_code {
+        Some(0) => CheckResult::Clean,
+        Some(code) if code & !7 != 0 => CheckResult::CheckerFailed,
+        Some(code) if code & 4 != 0 => CheckResult::ErrorsUncorrected,
+        Some(_) => CheckResult::ErrorsCorrected,
+        None => CheckResult::CheckerFailed,
+    }
+}
+
+/// xfs_repair -n never modifies the filesystem; it exits 1 when corruption is detected.  After an unclean shutdown it
+/// can't see the changes in the log, which only mounting replays, so it reports inconsistencies that aren't really
+/// there.  Rather than mounting and unmounting the filesystem before checking it, a dirty log is taken as clean enough
+/// to mount; the kernel replays the log and checks the metadata it touches as it does.
+fn classify_xfs_repair(exit_code: Option<i32>, output: &str) -> CheckResult {
+    match exit_code {
+        Some(0) => CheckResult::Clean,
+        Some(1) if output.contains(XFS_DIRTY_LOG_MESSAGE) => CheckResult::LogReplayNeeded,
+        Some(1) => CheckResult::ErrorsUncorrected,
+        _ => CheckResult::CheckerFailed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_e2fsck_exit_codes() {
+        assert_eq!(CheckResult::Clean, classify_e2fsck(Some(0), ""));
+        assert_eq!(CheckResult::ErrorsCorrected, classify_e2fsck(Some(1), ""));
+        assert_eq!(CheckResult::ErrorsCorrected, classify_e2fsck(Some(3), ""));
+        assert_eq!(CheckResult::ErrorsUncorrected, classify_e2fsck(Some(4), ""));
+        assert_eq!(CheckResult::ErrorsUncorrected, classify_e2fsck(Some(5), ""));
+        assert_eq!(CheckResult::CheckerFailed, classify_e2fsck(Some(8), ""));
+        assert_eq!(CheckResult::CheckerFailed, classify_e2fsck(Some(12), ""));
+        assert_eq!(CheckResult::CheckerFailed, classify_e2fsck(Some(32), ""));
+        assert_eq!(CheckResult::CheckerFailed, classify_e2fsck(None, ""));
+    }
+
+    const CLEAN_OUTPUT: &'static str = "Phase 1 - find and verify superblock...\n";
+
+    #[test]
+    fn classifies_xfs_repair_exit_codes() {
+        assert_eq!(CheckResult::Clean, classify_xfs_repair(Some(0), CLEAN_OUTPUT));
+        assert_eq!(CheckResult::ErrorsUncorrected, classify_xfs_repair(Some(1), CLEAN_OUTPUT));
+        assert_eq!(CheckResult::CheckerFailed, classify_xfs_repair(Some(2), CLEAN_OUTPUT));
+        assert_eq!(CheckResult::CheckerFailed, classify_xfs_repair(None, CLEAN_OUTPUT));
+    }
+
+    #[test]
+    fn classifies_xfs_repair_dirty_log() {
+        let output = "Phase 1 - find and verify superblock...\n\
+                      Phase 2 - using internal log\n\
+                      ALERT: The filesystem has valuable metadata changes in a log which is being ignored because the \
+                      -n option was used.  Expect spurious inconsistencies which may be resolved by first mounting \
+                      the filesystem to replay the log.\n";
+        assert_eq!(CheckResult::LogReplayNeeded, classify_xfs_repair(Some(1), output));
+        assert_eq!(CheckResult::CheckerFailed, classify_xfs_repair(Some(2), output));
+    }
+}
@@ -0,0 +1,286 @@
+use std;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use aws_instance_metadata;
+use rusoto::{DefaultCredentialsProvider, ProvideAwsCredentials, DispatchSignedRequest};
+use rusoto::kms::{KmsClient, DecryptRequest, DecryptError};
+use rusoto::default_tls_client;
+use rustc_serialize::base64::FromBase64;
+use config::{Encryption, KeySource, KmsKeySource};
+use mkfs;
+
+#[derive(Debug)]
+pub enum LuksError {
+    SpawnFailed(std::io::Error),
+    ExternalCommandFailed(String),
+    ReadingKeyFailed(std::io::Error),
+    KeyEnvironmentVariableMissing(String),
+    InvalidKmsCiphertext(String),
+    KmsDecryptFailed(DecryptError),
+    KmsPlaintextMissing,
+    InstanceMetadataFailed(aws_instance_metadata::myerr::MetadataRetrievalError),
+    RefusingToFormatExistingFilesystem(String),
+    MappedToOtherDevice(String),
+}
+
+impl From<std::io::Error> for LuksError {
+    fn from(err: std::io::Error) -> LuksError {
+        LuksError::SpawnFailed(err)
+    }
+}
+
+impl From<DecryptError> for LuksError {
+    fn from(err: DecryptError) -> LuksError {
+        LuksError::KmsDecryptFailed(err)
+    }
+}
+
+/// Ensures that `block_device` is a LUKS volume and that it is opened as `/dev/mapper/<name>`.
+///
+/// A device without a LUKS header is formatted with LUKS2, unless it already contains a filesystem; that would
+/// indicate a volume created before encryption was configured, and formatting it would destroy its data.  A mapping
+/// that's already open must be backed by `block_device`.
+pub fn ensure_open(config: &Encryption, block_device: &str) -> Result<(), LuksError> {
+    let mapped_device = format!("/dev/mapper/{}", config.name);
+    if Path::new(&mapped_device).exists() {
+        let backing_device = backing_device(&config.name)?;
+        if !same_device(&backing_device, block_device) {
+            return Err(LuksError::MappedToOtherDevice(format!("{} is open on {}, not {}",
+                                                              mapped_device,
+                                                              backing_device,
+                                                              block_device)));
+        }
+        info!("{} is already open", mapped_device);
+        return Ok(());
+    }
+
+    let key = read_key(&config.key_source)?;
+
+    if !has_luks_header(block_device)? {
+        if mkfs::filesystem_exists(block_device).unwrap_or(true) {
+            return Err(LuksError::RefusingToFormatExistingFilesystem(String::from(block_device)));
+        }
+        info!("no LUKS header found on {}; formatting", block_device);
+        let mut cmd = Command::new("/sbin/cryptsetup");
+        cmd.arg("luksFormat")
+            .arg("--type")
+            .arg("luks2")
+            .arg("--batch-mode")
+            .arg("--key-file")
+            .arg("-")
+            .arg(block_device);
+        run_command_with_key(cmd, "cryptsetup luksFormat", &key)?;
+    }
+
+    info!("opening LUKS volume {} as {}", block_device, mapped_device);
+    let mut cmd = Command::new("/sbin/cryptsetup");
+    cmd.arg("open")
+        .arg("--type")
+        .arg("luks")
+        .arg("--key-file")
+        .arg("-")
+        .arg(block_device)
+        .arg(&config.name);
+    run_command_with_key(cmd, "cryptsetup open", &key)
+}
+
+/// Closes the `/dev/mapper/<name>` mapping opened by `ensure_open`.
+pub fn close(config: &Encryption) -> Result<(), LuksError> {
+    let mut cmd = Command::new("/sbin/cryptsetup");
+    cmd.arg("close").arg(&config.name);
+    run_command_with_key(cmd, "cryptsetup close", &[])
+}
+
+/// Reads the device that the open `/dev/mapper/<name>` mapping is backed by from `cryptsetup status`.
+fn backing_device(name: &str) -> Result<String, LuksError> {
+    let mut cmd = Command::new("/sbin/cryptsetup");
+    cmd.arg("status").arg(name);
+    trace!("invoking cryptsetup status: {:?}", cmd);
+    let result = cmd.stdin(Stdio::null()).output()?;
+    if !result.status.success() {
+        let err_text = String::from_utf8(result.stderr)
+            .unwrap_or_else(|_| String::from("unable to decode cryptsetup status stderr"));
+        return Err(LuksError::ExternalCommandFailed(err_text));
+    }
+    parse_status_device(&String::from_utf8_lossy(&result.stdout)).ok_or_else(|| {
+        LuksError::ExternalCommandFailed(format!("cryptsetup status didn't report a device for {}", name))
+    })
+}
+
+fn parse_status_device(status_output: &str) -> Option<String> {
+    status_output.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next().map(str::trim), parts.next()) {
+                (Some("device"), Some(device)) => Some(String::from(device.trim())),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Whether both paths name the same device, following symlinks such as `/dev/<vg>/<lv>` and NVMe aliases.
+fn same_device(a: &str, b: &str) -> bool {
+    let canonical = |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    canonical(a) == canonical(b)
+}
+
+pub fn has_luks_header(block_device: &str) -> Result<bool, LuksError> {
+    let mut buf = [0; 6];
+    let mut file = File::open(block_device)?;
+    let bytes_read = file.read(&mut buf)?;
+    Ok(mkfs::is_luks_header(&buf[..bytes_read]))
+}
+
+/// Reads the passphrase from `key_source` and discards it, to verify that it's available before it is needed.
+pub fn check_key_source(key_source: &KeySource) -> Result<(), LuksError> {
+    read_key(key_source).map(|_| ())
+}
+
+fn read_key(key_source: &KeySource) -> Result<Vec<u8>, LuksError> {
+    match *key_source {
+        KeySource::File(ref path) => {
+            let mut key = Vec::new();
+            File::open(path).and_then(|mut f| f.read_to_end(&mut key))
+                .map_err(LuksError::ReadingKeyFailed)?;
+            Ok(key)
+        }
+        KeySource::Env(ref name) => {
+            env::var_os(name)
+                .map(|value| value.to_string_lossy().into_owned().into_bytes())
+                .ok_or_else(|| LuksError::KeyEnvironmentVariableMissing(name.to_owned()))
+        }
+        KeySource::Kms(ref kms) => {
+            let ciphertext = read_kms_ciphertext(kms)?;
+            let metadata = aws_instance_metadata::get().map_err(LuksError::InstanceMetadataFailed)?;
+            let kms_client = KmsClient::new(default_tls_client().unwrap(),
+                                            DefaultCredentialsProvider::new().unwrap(),
+                                            metadata.region().unwrap());
+            decrypt_kms_ciphertext(&kms_client, ciphertext)
+        }
+    }
+}
+
+fn read_kms_ciphertext(config: &KmsKeySource) -> Result<Vec<u8>, LuksError> {
+    let encoded = match (config.ciphertext.as_ref(), config.ciphertext_file.as_ref()) {
+        (Some(ciphertext), _) => ciphertext.to_owned(),
+        (None, Some(path)) => {
+            let mut encoded = String::new();
+            File::open(path).and_then(|mut f| f.read_to_string(&mut encoded))
+                .map_err(LuksError::ReadingKeyFailed)?;
+            encoded
+        }
+        (None, None) => return Err(LuksError::InvalidKmsCiphertext(String::from("no ciphertext configured"))),
+    };
+    encoded.trim()
+        .from_base64()
+        .map_err(|e| LuksError::InvalidKmsCiphertext(e.to_string()))
+}
+
+fn decrypt_kms_ciphertext<P, D>(kms_client: &KmsClient<P, D>, ciphertext: Vec<u8>) -> Result<Vec<u8>, LuksError>
+    where P: ProvideAwsCredentials,
+          D: DispatchSignedRequest
+{
+    let request = DecryptRequest {
+        ciphertext_blob: ciphertext,
+        encryption_context: None,
+        grant_tokens: None,
+    };
+    trace!("executing KMS Decrypt");
+    kms_client.decrypt(&request)?
+        .plaintext
+        .ok_or(LuksError::KmsPlaintextMissing)
+}
+
+fn run_command_with_key(mut cmd: Command, name: &str, key: &[u8]) -> Result<(), LuksError> {
+    trace!("invoking {}: {:?}", name, cmd);
+    let mut child = cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(key)?;
+    let result = child.wait_with_output()?;
+    if result.status.success() {
+        trace!("external {} command succeeded", name);
+        Ok(())
+    } else {
+        let err_text = String::from_utf8(result.stderr)
+            .unwrap_or_else(|_| format!("unable to decode {} stderr", name));
+        Err(LuksError::ExternalCommandFailed(err_text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate hyper;
+
+    use super::*;
+    use rusoto;
+    use chrono::{Duration, UTC};
+    use std::collections::HashMap;
+
+    struct MockProvideAwsCredentials {}
+
+    impl rusoto::ProvideAwsCredentials for MockProvideAwsCredentials {
+        fn credentials(&self) -> Result<rusoto::AwsCredentials, rusoto::CredentialsError> {
+            Ok(rusoto::AwsCredentials::new("key",
+                                           "secret",
+                                           None,
+                                           UTC::now() + Duration::seconds(600)))
+        }
+    }
+
+    struct KmsRequestDispatcherDecryptSuccess {}
+
+    impl rusoto::DispatchSignedRequest for KmsRequestDispatcherDecryptSuccess {
+        fn dispatch(&self,
+                    request: &rusoto::SignedRequest)
+                    -> Result<rusoto::HttpResponse, rusoto::HttpDispatchError> {
+            let payload = String::from_utf8(request.payload.clone().unwrap()).unwrap();
+            assert!(payload.contains("\"CiphertextBlob\":\"Y2lwaGVydGV4dA==\""));
+            Ok(rusoto::HttpResponse {
+                   status: hyper::status::StatusCode::Ok,
+                   body: String::from("{\"KeyId\":\"arn:aws:kms:us-west-2:123456789012:key/abc\",\
+                                       \"Plaintext\":\"cGFzc3BocmFzZQ==\"}"),
+                   raw_body: vec![],
+                   headers: HashMap::new(),
+               })
+        }
+    }
+
+    #[test]
+    fn test_parse_status_device() {
+        let output = "/dev/mapper/cps-data is active and is in use.\n  type:    LUKS2\n  \
+                      cipher:  aes-xts-plain64\n  keysize: 512 bits\n  key location: keyring\n  \
+                      device:  /dev/xvdf\n  sector size:  512\n  offset:  32768 sectors\n";
+        assert_eq!(Some(String::from("/dev/xvdf")), parse_status_device(output));
+        assert_eq!(None, parse_status_device("/dev/mapper/cps-data is inactive.\n"));
+    }
+
+    #[test]
+    fn test_same_device() {
+        assert!(same_device("/dev/xvdf", "/dev/xvdf"));
+        assert!(!same_device("/dev/xvdf", "/dev/xvdg"));
+    }
+
+    #[test]
+    fn test_read_kms_ciphertext() {
+        let config = KmsKeySource {
+            ciphertext: Some(String::from("Y2lwaGVydGV4dA==\n")),
+            ciphertext_file: None,
+        };
+        assert_eq!(b"ciphertext".to_vec(), read_kms_ciphertext(&config).unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_kms_ciphertext() {
+        let kms_client = KmsClient::new(KmsRequestDispatcherDecryptSuccess {},
+                                        MockProvideAwsCredentials {},
+                                        rusoto::Region::UsWest2);
+        let plaintext = decrypt_kms_ciphertext(&kms_client, b"ciphertext".to_vec()).unwrap();
+        assert_eq!(b"passphrase".to_vec(), plaintext);
+    }
+}
@@ -0,0 +1,133 @@
+use std;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use config::LvmLayout;
+
+#[derive(Debug)]
+pub enum LvmError {
+    SpawnFailed(std::io::Error),
+    ExternalCommandFailed(String),
+    MissingMemberDevice(String),
+    PartiallyInitializedMembers(Vec<String>),
+}
+
+impl From<std::io::Error> for LvmError {
+    fn from(err: std::io::Error) -> LvmError {
+        LvmError::SpawnFailed(err)
+    }
+}
+
+/// Ensures that the striped logical volume described by `config` exists across `block_devices` and is active.
+///
+/// If none of the member devices carry an LVM physical volume label, this is the first use of the volume set; the
+/// physical volumes, volume group and striped logical volume are created.  If every member carries a label, the
+/// existing volume group is activated.  A mix of labelled and unlabelled members indicates that the set was not
+/// attached as a whole, or that a previous initialization failed part way, and is refused rather than risking the
+/// data on the labelled members.
+pub fn ensure_logical_volume(config: &LvmLayout, block_devices: &[String]) -> Result<(), LvmError> {
+    let mut labelled = Vec::new();
+    let mut unlabelled = Vec::new();
+    for block_device in block_devices {
+        if !Path::new(block_device).exists() {
+            return Err(LvmError::MissingMemberDevice(block_device.to_owned()));
+        }
+        if has_physical_volume_label(block_device)? {
+            labelled.push(block_device.to_owned());
+        } else {
+            unlabelled.push(block_device.to_owned());
+        }
+    }
+
+    if labelled.is_empty() {
+        info!("no member devices are LVM physical volumes; creating volume group {}",
+              config.volume_group);
+        create_logical_volume(config, block_devices)
+    } else if unlabelled.is_empty() {
+        info!("all member devices are LVM physical volumes; activating volume group {}",
+              config.volume_group);
+        let mut cmd = Command::new("/sbin/vgchange");
+        cmd.arg("--activate").arg("y").arg(&config.volume_group);
+        run_command(cmd, "vgchange")
+    } else {
+        Err(LvmError::PartiallyInitializedMembers(unlabelled))
+    }
+}
+
+/// Deactivates the volume group so that its member devices can be detached.
+pub fn deactivate_logical_volume(config: &LvmLayout) -> Result<(), LvmError> {
+    let mut cmd = Command::new("/sbin/vgchange");
+    cmd.arg("--activate").arg("n").arg(&config.volume_group);
+    run_command(cmd, "vgchange")
+}
+
+fn create_logical_volume(config: &LvmLayout, block_devices: &[String]) -> Result<(), LvmError> {
+    let mut cmd = Command::new("/sbin/pvcreate");
+    cmd.arg("--yes");
+    cmd.args(block_devices);
+    run_command(cmd, "pvcreate")?;
+
+    let mut cmd = Command::new("/sbin/vgcreate");
+    cmd.arg("--yes");
+    cmd.arg(&config.volume_group);
+    cmd.args(block_devices);
+    run_command(cmd, "vgcreate")?;
+
+    let mut cmd = Command::new("/sbin/lvcreate");
+    cmd.arg("--yes");
+    cmd.arg("--stripes").arg(block_devices.len().to_string());
+    if let Some(ref stripe_size) = config.stripe_size {
+        cmd.arg("--stripesize").arg(stripe_size);
+    }
+    cmd.arg("--extents").arg("100%FREE");
+    cmd.arg("--name").arg(&config.logical_volume);
+    cmd.arg(&config.volume_group);
+    run_command(cmd, "lvcreate")
+}
+
+/// Detects the "LABELONE" LVM label, which is written to one of the first four 512-byte sectors of a physical
+/// volume (the second sector by default).
+pub fn has_physical_volume_label(block_device: &str) -> Result<bool, LvmError> {
+    let mut buf = [0; 2048];
+    let mut file = File::open(block_device)?;
+    let bytes_read = file.read(&mut buf)?;
+    Ok(contains_physical_volume_label(&buf[..bytes_read]))
+}
+
+fn contains_physical_volume_label(buf: &[u8]) -> bool {
+    buf.chunks(512).any(|sector| sector.starts_with(b"LABELONE"))
+}
+
+fn run_command(mut cmd: Command, name: &str) -> Result<(), LvmError> {
+    trace!("invoking {}: {:?}", name, cmd);
+    let result = try!(cmd.stdin(Stdio::null()).output());
+    if result.status.success() {
+        trace!("external {} command succeeded", name);
+        Ok(())
+    } else {
+        let err_text = String::from_utf8(result.stderr)
+            .unwrap_or_else(|_| format!("unable to decode {} stderr", name));
+        Err(LvmError::ExternalCommandFailed(err_text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_physical_volume_label_in_second_sector() {
+        let mut buf = [0u8; 2048];
+        buf[512..520].copy_from_slice(b"LABELONE");
+        assert!(contains_physical_volume_label(&buf));
+    }
+
+    #[test]
+    fn ignores_label_outside_sector_start() {
+        let mut buf = [0u8; 2048];
+        buf[600..608].copy_from_slice(b"LABELONE");
+        assert!(!contains_physical_volume_label(&buf));
+        assert!(!contains_physical_volume_label(&[0u8; 2048]));
+    }
+}
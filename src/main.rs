@@ -6,8 +6,15 @@ extern crate aws_instance_metadata;
 extern crate chrono;
 extern crate serde_yaml;
 #[macro_use]
+extern crate serde_json;
+#[macro_use]
 extern crate serde_derive;
 extern crate getopts;
+extern crate rustc_serialize;
+extern crate hyper;
+extern crate toml;
+extern crate rand;
+extern crate ring;
 
 use getopts::Options;
 use std::env;
@@ -16,10 +23,89 @@ mod mkfs;
 mod ebs;
 mod mount;
 mod config;
+mod lvm;
+mod raid;
+mod luks;
+mod fsck;
+mod retry;
+mod template;
+mod config_source;
+mod block_devices;
+mod schema;
+mod yaml_location;
+mod commands;
+
+const COMMANDS: &'static str = "
+Commands:
+    attach      attach, format if necessary, and mount the configured volumes
+                (the default if no command is given)
+    detach      unmount the configured volumes and detach them from this
+                instance
+    status      show what is currently attached and mounted
+    list        list the EBS volumes matching the configuration, and where
+                they are attached
+    validate    check the configuration file, without making any AWS calls or
+                looking up ${instance.*} references
+    plan        show what attach would do, without changing anything
+    doctor      check AWS permissions, instance metadata, encryption keys and
+                required system commands before running attach
+    gc          delete the EBS volumes matching the configuration that have
+                been unattached for longer than --retention
+    snapshot    freeze the mounted filesystems, snapshot the attached EBS
+                volumes, and delete older snapshots beyond --keep-hourly,
+                --keep-daily and --keep-weekly
+    pool        create EBS volumes until each configured pool has its size
+                available in each of its availability zones
+    config schema
+                print a JSON Schema for the configuration file
+";
+
+const COMMAND_NAMES: &'static [&'static str] = &["attach", "detach", "status", "list", "validate", "plan",
+                                                  "doctor", "gc", "snapshot", "pool", "config"];
 
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {} [command] [options]", program);
     print!("{}", opts.usage(&brief));
+    print!("{}", COMMANDS);
+}
+
+/// Environment variable that a configuration override can be given in, eg. CPS_MOUNT_TARGET for mount-target.
+fn override_environment_variable(name: &str) -> String {
+    String::from("CPS_") + &name.to_uppercase().replace('-', "_")
+}
+
+/// Reads the gc command's options.  There's deliberately no default retention period, since deleting volumes that
+/// are only waiting for an instance would lose data.
+fn gc_options(matches: &getopts::Matches, dry_run: bool) -> Result<ebs::GcOptions, String> {
+    let retention = match matches.opt_str("retention") {
+        Some(hours) => {
+            hours.parse::<u32>()
+                .map_err(|_| format!("invalid --retention, expected a number of hours: {}", hours))?
+        }
+        None => return Err(String::from("gc requires --retention")),
+    };
+    Ok(ebs::GcOptions {
+           retention: chrono::Duration::hours(retention as i64),
+           keep: count_option(matches, "keep")?,
+           snapshot: matches.opt_present("snapshot"),
+           dry_run: dry_run,
+       })
+}
+
+fn snapshot_retention(matches: &getopts::Matches) -> Result<ebs::SnapshotRetention, String> {
+    Ok(ebs::SnapshotRetention {
+           hourly: count_option(matches, "keep-hourly")?,
+           daily: count_option(matches, "keep-daily")?,
+           weekly: count_option(matches, "keep-weekly")?,
+       })
+}
+
+/// Reads a count option, which is 0 if it isn't given.
+fn count_option(matches: &getopts::Matches, name: &str) -> Result<usize, String> {
+    match matches.opt_str(name) {
+        Some(count) => count.parse().map_err(|_| format!("invalid --{}, expected a number: {}", name, count)),
+        None => Ok(0),
+    }
 }
 
 fn main() {
@@ -31,8 +117,57 @@ fn main() {
     let mut opts = Options::new();
     opts.optopt("c",
                 "config",
-                "configuration file path (required)",
+                "configuration file path; required unless the configuration is given entirely by the options below",
                 "config.yml");
+    opts.optopt("",
+                "config-source",
+                "read the configuration from somewhere other than a file: user-data, ssm:NAME for an SSM parameter, \
+                 or instance-tags:PREFIX for this instance's tags named PREFIX followed by an option name below",
+                "SOURCE");
+    opts.optopt("",
+                "config-format",
+                "format of the configuration; by default, files are read by their extension and other sources as yaml",
+                "yaml|json|toml");
+    for option in config::OVERRIDE_OPTIONS {
+        if option.multiple {
+            opts.optmulti("", option.name, option.description, option.hint);
+        } else {
+            opts.optopt("", option.name, option.description, option.hint);
+        }
+    }
+    opts.optflag("n",
+                 "dry-run",
+                 "with attach, gc or pool, show what would be done (and with attach, check AWS permissions) \
+                  without changing anything");
+    opts.optopt("o",
+                "output",
+                "with attach, also print the result as json to stdout (default text)",
+                "text|json");
+    opts.optopt("",
+                "result-file",
+                "with attach, write the result as json to this file",
+                "result.json");
+    opts.optopt("",
+                "retention",
+                "with gc, delete volumes that have been unattached for at least this many hours",
+                "HOURS");
+    opts.optopt("",
+                "keep",
+                "with gc, leave at least this many unattached volumes (or volume sets) undeleted (default 0)",
+                "N");
+    opts.optflag("", "snapshot", "with gc, snapshot each volume before deleting it");
+    opts.optopt("",
+                "keep-hourly",
+                "with snapshot, keep the most recent snapshot in each of this many hours",
+                "N");
+    opts.optopt("",
+                "keep-daily",
+                "with snapshot, keep the most recent snapshot in each of this many days",
+                "N");
+    opts.optopt("",
+                "keep-weekly",
+                "with snapshot, keep the most recent snapshot in each of this many weeks",
+                "N");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -43,78 +178,195 @@ fn main() {
         return;
     }
 
-    let config_path = match matches.opt_str("c") {
-        Some(c) => c,
-        None => {
-            error!("configuration file path (-c, --config) must be provided");
-            std::process::exit(100);
+    let command = matches.free.get(0).map_or("attach", |c| c.as_str());
+    if !COMMAND_NAMES.contains(&command) {
+        error!("unrecognized command: {}", command);
+        print_usage(&program, &opts);
+        std::process::exit(100);
+    }
+    let arguments = if command == "config" { 2 } else { 1 };
+    if matches.free.len() > arguments {
+        error!("unexpected arguments: {:?}", &matches.free[arguments..]);
+        std::process::exit(100);
+    }
 
-        }
+    let dry_run = matches.opt_present("n");
+    let output = commands::OutputOptions {
+        json: match matches.opt_str("o").as_ref().map(|o| o.as_str()) {
+            None | Some("text") => false,
+            Some("json") => true,
+            Some(other) => {
+                error!("unrecognized output format, expected text or json: {}", other);
+                std::process::exit(100);
+            }
+        },
+        result_file: matches.opt_str("result-file"),
     };
-    info!("config path: {}", config_path);
+    if command != "attach" && command != "gc" && command != "pool" && dry_run {
+        error!("--dry-run can only be used with the attach, gc and pool commands");
+        std::process::exit(100);
+    }
+    if command != "attach" && (output.json || output.result_file.is_some()) {
+        error!("--output and --result-file can only be used with the attach command");
+        std::process::exit(100);
+    }
+    if dry_run && (output.json || output.result_file.is_some()) {
+        error!("--output and --result-file cannot be used with --dry-run");
+        std::process::exit(100);
+    }
 
-    let config = match config::read_config_from_file(config_path.as_str()) {
-        Ok(c) => c,
+    let gc_options = if command == "gc" {
+        match gc_options(&matches, dry_run) {
+            Ok(options) => Some(options),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(100);
+            }
+        }
+    } else if matches.opt_present("retention") || matches.opt_present("keep") || matches.opt_present("snapshot") {
+        error!("--retention, --keep and --snapshot can only be used with the gc command");
+        std::process::exit(100);
+    } else {
+        None
+    };
+    let snapshot_retention = match snapshot_retention(&matches) {
+        Ok(ref retention) if command != "snapshot" && !retention.is_empty() => {
+            error!("--keep-hourly, --keep-daily and --keep-weekly can only be used with the snapshot command");
+            std::process::exit(100);
+        }
+        Ok(retention) => retention,
         Err(e) => {
-            error!("failed to read configuration: {:?}", e);
+            error!("{}", e);
             std::process::exit(100);
         }
     };
-    info!("configuration: {:?}", config);
 
-    match config.block_provider {
-        config::BlockProvider::AwsEbs(ebs) => {
-            match ebs::find_and_attach_volume(config.block_device.as_str(), &ebs) {
-                Ok(_) => info!("attach volume succeeded"),
-                Err(e) => {
-                    error!("attach volume failed: {:?}", e);
-                    std::process::exit(101);
+    if command == "config" {
+        match matches.free.get(1).map(|c| c.as_str()) {
+            Some("schema") => std::process::exit(commands::config_schema()),
+            _ => {
+                error!("expected a config subcommand: schema");
+                std::process::exit(100);
+            }
+        }
+    }
+
+    // settings given on the command line take precedence over the environment, which takes precedence over the
+    // configuration file
+    let mut overrides: Vec<(&str, Vec<String>)> = Vec::new();
+    for option in config::OVERRIDE_OPTIONS {
+        let mut values = matches.opt_strs(option.name);
+        if values.is_empty() {
+            if let Ok(value) = env::var(override_environment_variable(option.name)) {
+                if option.multiple {
+                    values = config::split_values(&value);
+                } else {
+                    values.push(value);
                 }
             }
         }
-        _ => {
-            error!("attach volume had unexpected block provider");
-            std::process::exit(101);
+        if !values.is_empty() {
+            overrides.push((option.name, values));
         }
-    };
+    }
 
-    match mkfs::filesystem_exists(config.block_device.as_str()) {
-        Ok(true) => {
-            info!("filesystem already exists on block device");
+    // a configuration path or source given on the command line takes precedence over either in the environment
+    let (config_path, config_source) = match (matches.opt_str("c"), matches.opt_str("config-source")) {
+        (None, None) => (env::var("CPS_CONFIG").ok(), env::var("CPS_CONFIG_SOURCE").ok()),
+        options => {
+            for name in &["CPS_CONFIG", "CPS_CONFIG_SOURCE"] {
+                if let Ok(value) = env::var(name) {
+                    info!("ignoring {}={}, since the configuration was given on the command line", name, value);
+                }
+            }
+            options
         }
-        Ok(false) => {
-            info!("filesystem was not found; creating");
-            match mkfs::make_filesystem(&config.file_system, config.block_device.as_str()) {
-                Ok(_) => info!("created filesystem successfully"),
+    };
+    let config_source = match (config_path, config_source) {
+        (Some(_), Some(_)) => {
+            error!("only one of the configuration file path (-c, --config) and --config-source may be provided");
+            std::process::exit(100);
+        }
+        (Some(path), None) => Some(config_source::ConfigSource::File(path)),
+        (None, Some(source)) => {
+            match source.parse() {
+                Ok(source) => Some(source),
                 Err(e) => {
-                    error!("failed to create filesystem: {:?}", e);
-                    std::process::exit(102);
+                    error!("invalid --config-source: {}", e);
+                    std::process::exit(100);
                 }
             }
         }
-        Err(e) => {
-            error!("failed to detect whether filesystem already exists: {:?}",
-                   e);
-            std::process::exit(102);
-        }
+        (None, None) => None,
+    };
+    if config_source.is_none() && overrides.is_empty() {
+        error!("configuration file path (-c, --config) or --config-source must be provided");
+        std::process::exit(100);
     }
+    info!("config source: {:?}", config_source);
 
-    match std::fs::create_dir_all(config.mount.target.to_owned()) {
-        Ok(_) => info!("created/ensured mount point directory successfully"),
-        Err(e) => {
-            error!("failed to create mount point directory: {:?}", e);
-            std::process::exit(103);
+    let config_format = match matches.opt_str("config-format").or_else(|| env::var("CPS_CONFIG_FORMAT").ok()) {
+        Some(format) => {
+            match format.parse() {
+                Ok(format) => Some(format),
+                Err(e) => {
+                    error!("invalid --config-format: {}", e);
+                    std::process::exit(100);
+                }
+            }
         }
-    }
+        None => None,
+    };
 
-    match mount::mount(&config.mount, config.block_device.as_str()) {
-        Ok(_) => info!("mounted filesystem successfully"),
+    // validate makes no AWS calls beyond reading the configuration source, so instance references aren't looked up
+    let mut instance_variables = template::InstanceVariables::new();
+    let mut reference_check_variables = template::ReferenceCheckVariables;
+    let variables: &mut template::Variables = if command == "validate" {
+        &mut reference_check_variables
+    } else {
+        &mut instance_variables
+    };
+    let config = match config::read_config(config_source.as_ref(), config_format, &overrides, variables) {
+        Ok(c) => c,
+        Err(config::ConfigError::Invalid(errors)) => {
+            for e in errors {
+                error!("invalid configuration: {}", e);
+            }
+            std::process::exit(100);
+        }
         Err(e) => {
-            error!("failed to mount filesystem: {:?}", e);
-            std::process::exit(104);
+            error!("failed to read configuration: {:?}", e);
+            std::process::exit(100);
         }
+    };
+    info!("configuration: {:?}", config);
+
+    let exit_code = match command {
+        "attach" if dry_run => commands::dry_run(&config),
+        "attach" => commands::attach(&config, &output),
+        "detach" => commands::detach(&config),
+        "status" => commands::status(&config),
+        "list" => commands::list(&config),
+        "validate" => commands::validate(&config),
+        "plan" => commands::plan(&config),
+        "doctor" => commands::doctor(&config),
+        "gc" => commands::gc(&config, gc_options.as_ref().unwrap()),
+        "snapshot" => commands::snapshot(&config, &snapshot_retention),
+        "pool" => commands::pool(&config, dry_run),
+        _ => unreachable!(),
+    };
+    if exit_code != 0 {
+        std::process::exit(exit_code);
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_environment_variable_names() {
+        assert_eq!("CPS_SIZE", override_environment_variable("size"));
+        assert_eq!("CPS_MOUNT_TARGET", override_environment_variable("mount-target"));
+    }
+}
@@ -35,19 +35,139 @@ pub fn make_filesystem(config: &FileSystem, block_device: &str) -> Result<(), Ma
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum FilesystemType {
+    Ext,
+    Xfs,
+}
+
 pub fn filesystem_exists(block_device: &str) -> Result<bool, MakeFilesystemError> {
+    let buf = read_superblock(block_device)?;
+    Ok(has_filesystem_signature(&buf))
+}
+
+pub fn filesystem_type(block_device: &str) -> Result<Option<FilesystemType>, MakeFilesystemError> {
+    let buf = read_superblock(block_device)?;
+    Ok(detect_filesystem_type(&buf))
+}
+
+fn read_superblock(block_device: &str) -> Result<Vec<u8>, MakeFilesystemError> {
     let mut buf = [0; 2048];
     let mut file = File::open(block_device)?;
     let bytes_read = file.read(&mut buf)?;
-    if bytes_read < 2048 {
-        Ok(false)
+    Ok(buf[..bytes_read].to_vec())
+}
+
+fn has_filesystem_signature(buf: &[u8]) -> bool {
+    if detect_filesystem_type(buf).is_some() {
+        true
+    } else if is_luks_header(buf) {
+        // LUKS encrypted volume; never format over it, even if encryption isn't configured
+        true
+    } else {
+        false
+    }
+}
+
+fn detect_filesystem_type(buf: &[u8]) -> Option<FilesystemType> {
+    if buf.len() < 2048 {
+        None
     } else if buf[0x438] == 0x53 && buf[0x439] == 0xEF {
         // ext2/3/4 filesystem
-        Ok(true)
+        Some(FilesystemType::Ext)
+    } else if buf.starts_with(b"XFSB") {
+        Some(FilesystemType::Xfs)
     } else {
-        Ok(false)
+        None
     }
 }
 
+/// Filesystem type and UUID, as reported by blkid.
+#[derive(Debug, Default, PartialEq)]
+pub struct FilesystemInfo {
+    pub fs_type: Option<String>,
+    pub uuid: Option<String>,
+}
+
+pub fn filesystem_info(block_device: &str) -> Result<FilesystemInfo, MakeFilesystemError> {
+    let mut cmd = Command::new("/sbin/blkid");
+    cmd.arg("-o").arg("export").arg(block_device);
+    trace!("invoking blkid: {:?}", cmd);
+
+    let result = try!(cmd.stdin(Stdio::null()).output());
+    if result.status.success() {
+        Ok(parse_blkid_export(&String::from_utf8_lossy(&result.stdout)))
+    } else {
+        let err_text =
+            String::from_utf8(result.stderr).unwrap_or_else(|_| String::from("unable to decode blkid stderr"));
+        Err(MakeFilesystemError::ExternalCommandFailed(err_text))
+    }
+}
+
+fn parse_blkid_export(output: &str) -> FilesystemInfo {
+    let mut info = FilesystemInfo::default();
+    for line in output.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("TYPE"), Some(fs_type)) => info.fs_type = Some(String::from(fs_type)),
+            (Some("UUID"), Some(uuid)) => info.uuid = Some(String::from(uuid)),
+            _ => {}
+        }
+    }
+    info
+}
+
+pub fn is_luks_header(buf: &[u8]) -> bool {
+    buf.starts_with(b"LUKS\xBA\xBE")
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_ext4_signature() {
+        let mut buf = [0u8; 2048];
+        buf[0x438] = 0x53;
+        buf[0x439] = 0xEF;
+        assert!(has_filesystem_signature(&buf));
+    }
+
+    #[test]
+    fn detects_filesystem_type() {
+        let mut buf = [0u8; 2048];
+        buf[0x438] = 0x53;
+        buf[0x439] = 0xEF;
+        assert_eq!(Some(FilesystemType::Ext), detect_filesystem_type(&buf));
+
+        let mut buf = [0u8; 2048];
+        buf[..4].copy_from_slice(b"XFSB");
+        assert_eq!(Some(FilesystemType::Xfs), detect_filesystem_type(&buf));
+        assert!(has_filesystem_signature(&buf));
+
+        assert_eq!(None, detect_filesystem_type(&[0u8; 2048]));
+    }
+
+    #[test]
+    fn detects_luks_header() {
+        let mut buf = [0u8; 2048];
+        buf[..6].copy_from_slice(b"LUKS\xBA\xBE");
+        assert!(has_filesystem_signature(&buf));
+    }
+
+    #[test]
+    fn parses_blkid_export() {
+        let output = "DEVNAME=/dev/xvdf\nUUID=0b8c9a2e-4b3a-4c1e-9a63-5f0d4b8c2a11\nTYPE=ext4\n";
+        assert_eq!(FilesystemInfo {
+                       fs_type: Some(String::from("ext4")),
+                       uuid: Some(String::from("0b8c9a2e-4b3a-4c1e-9a63-5f0d4b8c2a11")),
+                   },
+                   parse_blkid_export(output));
+    }
+
+    #[test]
+    fn empty_device_has_no_filesystem() {
+        assert!(!has_filesystem_signature(&[0u8; 2048]));
+        assert!(!has_filesystem_signature(&[0u8; 512]));
+    }
+}
@@ -1,6 +1,10 @@
 use std;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
 use std::process::{Command, Stdio};
-use config::Mount;
+use config::{Bind, Mount};
 
 #[derive(Debug)]
 pub enum MountError {
@@ -14,21 +18,187 @@ impl From<std::io::Error> for MountError {
     }
 }
 
-pub fn mount(config: &Mount, block_device: &str) -> Result<(), MountError> {
+pub fn mount(config: &Mount, block_device: &str, read_only: bool) -> Result<(), MountError> {
     let mut cmd = Command::new("/bin/mount");
+    if read_only {
+        cmd.arg("-o").arg("ro");
+    }
     cmd.arg(block_device);
     cmd.arg(config.target.to_owned());
     trace!("invoking mount: {:?}", cmd);
+    run_command(cmd, "mount")
+}
+
+pub fn apply_permissions(config: &Mount) -> Result<(), MountError> {
+    if config.owner.is_some() || config.group.is_some() {
+        let mut owner_spec = config.owner.to_owned().unwrap_or_default();
+        if let Some(ref group) = config.group {
+            owner_spec.push(':');
+            owner_spec.push_str(group);
+        }
+        let mut cmd = Command::new("/bin/chown");
+        cmd.arg(owner_spec);
+        cmd.arg(config.target.to_owned());
+        trace!("invoking chown: {:?}", cmd);
+        run_command(cmd, "chown")?;
+    }
+
+    if let Some(mode) = config.mode_bits() {
+        // mode is validated in config::validate_mount, so the unwrap is safe
+        let mode = mode.unwrap();
+        trace!("setting mode {:o} on {}", mode, config.target);
+        fs::set_permissions(config.target.to_owned(), fs::Permissions::from_mode(mode))?;
+    }
+
+    if let Some(ref context) = config.context {
+        let mut cmd = Command::new("/usr/bin/chcon");
+        cmd.arg(context);
+        cmd.arg(config.target.to_owned());
+        trace!("invoking chcon: {:?}", cmd);
+        run_command(cmd, "chcon")?;
+    }
+
+    Ok(())
+}
+
+pub fn bind_mount(config: &Mount, bind: &Bind) -> Result<(), MountError> {
+    let source = std::path::Path::new(&config.target).join(&bind.source);
+    fs::create_dir_all(&source)?;
+    fs::create_dir_all(&bind.target)?;
+
+    let mut cmd = Command::new("/bin/mount");
+    cmd.arg("--bind");
+    cmd.arg(source);
+    cmd.arg(bind.target.to_owned());
+    trace!("invoking bind mount: {:?}", cmd);
+    run_command(cmd, "mount")
+}
+
+pub fn unmount(target: &str) -> Result<(), MountError> {
+    let mut cmd = Command::new("/bin/umount");
+    cmd.arg(target);
+    trace!("invoking umount: {:?}", cmd);
+    run_command(cmd, "umount")
+}
+
+/// Suspends writes to the filesystem mounted at `target` and flushes it to disk (FIFREEZE), so that a snapshot of its
+/// block devices is consistent.  Every write blocks until the filesystem is thawed again with `thaw`.
+pub fn freeze(target: &str) -> Result<(), MountError> {
+    let mut cmd = Command::new("/sbin/fsfreeze");
+    cmd.arg("--freeze");
+    cmd.arg(target);
+    trace!("invoking fsfreeze: {:?}", cmd);
+    run_command(cmd, "fsfreeze")
+}
+
+/// Resumes writes to the filesystem mounted at `target` after `freeze` (FITHAW).
+pub fn thaw(target: &str) -> Result<(), MountError> {
+    let mut cmd = Command::new("/sbin/fsfreeze");
+    cmd.arg("--unfreeze");
+    cmd.arg(target);
+    trace!("invoking fsfreeze: {:?}", cmd);
+    run_command(cmd, "fsfreeze")
+}
+
+/// A filesystem frozen with `freeze`.  It's thawed by `thaw`, or if that isn't reached, when this is dropped, so that
+/// an error or panic while it's frozen doesn't leave every write on the host blocked.
+pub struct FrozenFilesystem {
+    target: Option<String>,
+}
+
+impl FrozenFilesystem {
+    pub fn freeze(target: &str) -> Result<FrozenFilesystem, MountError> {
+        freeze(target)?;
+        Ok(FrozenFilesystem { target: Some(String::from(target)) })
+    }
+
+    pub fn thaw(mut self) -> Result<(), MountError> {
+        let target = self.target.take().unwrap();
+        thaw(&target)
+    }
+}
+
+impl Drop for FrozenFilesystem {
+    fn drop(&mut self) {
+        if let Some(ref target) = self.target {
+            if let Err(e) = thaw(target) {
+                error!("failed to thaw filesystem at {}: {:?}", target, e);
+            }
+        }
+    }
+}
+
+/// A mounted filesystem, as listed in /proc/mounts.
+#[derive(Debug, PartialEq)]
+pub struct MountEntry {
+    pub device: String,
+    pub target: String,
+    pub options: Vec<String>,
+}
+
+impl MountEntry {
+    pub fn read_only(&self) -> bool {
+        self.options.iter().any(|option| option == "ro")
+    }
+}
+
+/// Finds the filesystem mounted at `target`, if any.  If several filesystems are stacked on the same target, the
+/// most recently mounted one is returned.
+pub fn find_mount(target: &str) -> Result<Option<MountEntry>, MountError> {
+    let mut mounts = String::new();
+    File::open("/proc/mounts")?.read_to_string(&mut mounts)?;
+    Ok(parse_mounts(&mounts).into_iter().filter(|entry| entry.target == target).last())
+}
+
+fn parse_mounts(mounts: &str) -> Vec<MountEntry> {
+    mounts.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 {
+                return None;
+            }
+            Some(MountEntry {
+                     device: unescape_mount_field(fields[0]),
+                     target: unescape_mount_field(fields[1]),
+                     options: fields[3].split(',').map(String::from).collect(),
+                 })
+        })
+        .collect()
+}
+
+/// /proc/mounts escapes whitespace and backslashes in paths as three-digit octal sequences, eg. "\040" for a space.
+fn unescape_mount_field(field: &str) -> String {
+    field.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+fn run_command(mut cmd: Command, name: &str) -> Result<(), MountError> {
     let result = try!(cmd.stdin(Stdio::null()).output());
     if result.status.success() {
-        trace!("external mount command succeeded");
+        trace!("external {} command succeeded", name);
         Ok(())
     } else {
-        let err_text =
-            String::from_utf8(result.stderr).unwrap_or_else(|_| String::from("unable to decode mount stderr"));
+        let err_text = String::from_utf8(result.stderr)
+            .unwrap_or_else(|_| format!("unable to decode {} stderr", name));
         Err(MountError::ExternalCommandFailed(err_text))
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_proc_mounts() {
+        let mounts = "proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+                      /dev/xvdf /mnt/my\\040data ext4 ro,relatime,data=ordered 0 0\n";
+        let entries = parse_mounts(mounts);
+        assert_eq!(2, entries.len());
+        assert_eq!("/dev/xvdf", entries[1].device);
+        assert_eq!("/mnt/my data", entries[1].target);
+        assert!(entries[1].read_only());
+        assert!(!entries[0].read_only());
+    }
+}
@@ -0,0 +1,156 @@
+use std;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use config::RaidLayout;
+
+#[derive(Debug)]
+pub enum RaidError {
+    SpawnFailed(std::io::Error),
+    ExternalCommandFailed(String),
+    MissingMemberDevices(Vec<String>),
+    PartiallyInitializedMembers(Vec<String>),
+    MismatchedArrayUuids(Vec<String>),
+    DegradedArrayNotAllowed { present: usize, expected: usize },
+}
+
+impl From<std::io::Error> for RaidError {
+    fn from(err: std::io::Error) -> RaidError {
+        RaidError::SpawnFailed(err)
+    }
+}
+
+/// Ensures that the md array described by `config` is running on `block_devices`.
+///
+/// If none of the member devices have an md superblock, this is the first use of the volume set and the array is
+/// created; every member must be present.  Otherwise, the members must all belong to the same array, which is
+/// assembled by its UUID.  An array with missing members is only assembled, and started degraded, when
+/// `allow-degraded` is configured.
+pub fn ensure_array(config: &RaidLayout, block_devices: &[String]) -> Result<(), RaidError> {
+    let (present, missing): (Vec<String>, Vec<String>) =
+        block_devices.iter().cloned().partition(|block_device| Path::new(block_device).exists());
+
+    let mut array_uuids = Vec::with_capacity(present.len());
+    let mut uninitialized = Vec::new();
+    for block_device in &present {
+        match examine_array_uuid(block_device)? {
+            Some(uuid) => array_uuids.push(uuid),
+            None => uninitialized.push(block_device.to_owned()),
+        }
+    }
+
+    if array_uuids.is_empty() {
+        if !missing.is_empty() {
+            return Err(RaidError::MissingMemberDevices(missing));
+        }
+        info!("no member devices have an md superblock; creating raid{} array {}",
+              config.level,
+              config.device);
+        return create_array(config, block_devices);
+    }
+
+    if !uninitialized.is_empty() {
+        return Err(RaidError::PartiallyInitializedMembers(uninitialized));
+    }
+    array_uuids.sort();
+    array_uuids.dedup();
+    if array_uuids.len() > 1 {
+        return Err(RaidError::MismatchedArrayUuids(array_uuids));
+    }
+    if !missing.is_empty() && !config.allow_degraded {
+        return Err(RaidError::DegradedArrayNotAllowed {
+                       present: present.len(),
+                       expected: block_devices.len(),
+                   });
+    }
+
+    info!("assembling array {} with uuid {} from {} of {} members",
+          config.device,
+          array_uuids[0],
+          present.len(),
+          block_devices.len());
+    let mut cmd = Command::new("/sbin/mdadm");
+    cmd.arg("--assemble");
+    cmd.arg(&config.device);
+    cmd.arg(format!("--uuid={}", array_uuids[0]));
+    if config.allow_degraded {
+        cmd.arg("--run");
+    }
+    cmd.args(&present);
+    run_command(cmd, "mdadm")
+}
+
+/// Stops the md array so that its member devices can be detached.
+pub fn stop_array(config: &RaidLayout) -> Result<(), RaidError> {
+    let mut cmd = Command::new("/sbin/mdadm");
+    cmd.arg("--stop").arg(&config.device);
+    run_command(cmd, "mdadm")
+}
+
+fn create_array(config: &RaidLayout, block_devices: &[String]) -> Result<(), RaidError> {
+    let mut cmd = Command::new("/sbin/mdadm");
+    cmd.arg("--create");
+    cmd.arg(&config.device);
+    cmd.arg("--run");
+    cmd.arg(format!("--level={}", config.level));
+    cmd.arg(format!("--raid-devices={}", block_devices.len()));
+    cmd.args(block_devices);
+    run_command(cmd, "mdadm")
+}
+
+/// Reads the UUID of the md array that `block_device` is a member of, if it has an md superblock.
+pub fn examine_array_uuid(block_device: &str) -> Result<Option<String>, RaidError> {
+    let mut cmd = Command::new("/sbin/mdadm");
+    cmd.arg("--examine").arg("--export").arg(block_device);
+    trace!("invoking mdadm: {:?}", cmd);
+    let result = try!(cmd.stdin(Stdio::null()).output());
+    // mdadm --examine exits non-zero when the device has no superblock
+    if result.status.success() {
+        Ok(parse_array_uuid(&String::from_utf8_lossy(&result.stdout)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn parse_array_uuid(examine_output: &str) -> Option<String> {
+    examine_output.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("MD_UUID"), Some(uuid)) => Some(String::from(uuid.trim())),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn run_command(mut cmd: Command, name: &str) -> Result<(), RaidError> {
+    trace!("invoking {}: {:?}", name, cmd);
+    let result = try!(cmd.stdin(Stdio::null()).output());
+    if result.status.success() {
+        trace!("external {} command succeeded", name);
+        Ok(())
+    } else {
+        let err_text = String::from_utf8(result.stderr)
+            .unwrap_or_else(|_| format!("unable to decode {} stderr", name));
+        Err(RaidError::ExternalCommandFailed(err_text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_array_uuid_from_examine_export() {
+        let output = "MD_LEVEL=raid1\nMD_DEVICES=2\nMD_NAME=ip-10-0-0-1:0\n\
+                      MD_UUID=3aaa0122:29827cfa:5331ad66:ca767371\nMD_UPDATE_TIME=1490000000\n";
+        assert_eq!(Some(String::from("3aaa0122:29827cfa:5331ad66:ca767371")),
+                   parse_array_uuid(output));
+    }
+
+    #[test]
+    fn parses_missing_array_uuid() {
+        assert_eq!(None, parse_array_uuid("MD_LEVEL=raid1\n"));
+        assert_eq!(None, parse_array_uuid(""));
+    }
+}
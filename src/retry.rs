@@ -0,0 +1,165 @@
+use std;
+use std::cmp;
+use std::time::{Duration, Instant};
+use rand::{self, Rng};
+use config::Polling;
+
+/// Delay before the first retry of a throttled request; each further retry doubles it, up to
+/// `MAX_THROTTLE_DELAY_MS`.
+const INITIAL_THROTTLE_DELAY_MS: u64 = 500;
+const MAX_THROTTLE_DELAY_MS: u64 = 20000;
+
+/// Calls `poll` until it returns true, or until `polling.timeout` has passed, in which case false is returned.  The
+/// interval between calls starts at `polling.initial_interval` and doubles up to `polling.max_interval`, and each
+/// interval is jittered so that instances that start together don't keep polling together.
+pub fn poll_until<F, E>(polling: &Polling, mut poll: F) -> Result<bool, E>
+    where F: FnMut() -> Result<bool, E>
+{
+    let start = Instant::now();
+    let timeout = Duration::from_secs(polling.timeout);
+    let mut attempt = 0;
+    while Instant::now().duration_since(start) < timeout {
+        if poll()? {
+            return Ok(true);
+        }
+        // a slow poll can itself run past the timeout
+        let remaining = match timeout.checked_sub(Instant::now().duration_since(start)) {
+            Some(remaining) => remaining,
+            None => return Ok(false),
+        };
+        std::thread::sleep(cmp::min(remaining, equal_jitter(poll_interval(polling, attempt))));
+        attempt += 1;
+    }
+    Ok(false)
+}
+
+/// Calls `request` until it succeeds or fails with an error other than throttling, retrying throttled requests up
+/// to `retries` times after an exponentially increasing, fully jittered delay.
+pub fn retry_throttled<F, T, E>(retries: u32, mut request: F) -> Result<T, E>
+    where F: FnMut() -> Result<T, E>,
+          E: std::fmt::Debug
+{
+    let mut attempt = 0;
+    loop {
+        match request() {
+            Err(ref e) if attempt < retries && is_throttled(e) => {
+                let delay = full_jitter(throttle_delay(attempt));
+                warn!("request was throttled; retrying in {} ms", millis(delay));
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// EC2 rejects requests over the account's rate limit with `RequestLimitExceeded`, and most other services with
+/// `Throttling`; rusoto reports both as unknown errors carrying the response body.
+pub fn is_throttled<E: std::fmt::Debug>(err: &E) -> bool {
+    let err_text = format!("{:?}", err);
+    err_text.contains("RequestLimitExceeded") || err_text.contains("Throttling")
+}
+
+fn poll_interval(polling: &Polling, attempt: u32) -> Duration {
+    let interval = polling.initial_interval.saturating_mul(1 << cmp::min(attempt, 16));
+    Duration::from_secs(cmp::min(interval, polling.max_interval))
+}
+
+fn throttle_delay(attempt: u32) -> Duration {
+    let delay = INITIAL_THROTTLE_DELAY_MS.saturating_mul(1 << cmp::min(attempt, 16));
+    Duration::from_millis(cmp::min(delay, MAX_THROTTLE_DELAY_MS))
+}
+
+/// A random duration between half of `interval` and all of it.
+fn equal_jitter(interval: Duration) -> Duration {
+    let half = millis(interval) / 2;
+    Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+}
+
+/// A random duration between zero and `delay`.
+fn full_jitter(delay: Duration) -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0, millis(delay) + 1))
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn polling(initial_interval: u64, max_interval: u64) -> Polling {
+        Polling {
+            timeout: 300,
+            initial_interval: initial_interval,
+            max_interval: max_interval,
+        }
+    }
+
+    #[test]
+    fn poll_interval_backs_off_exponentially() {
+        let polling = polling(1, 15);
+        let intervals: Vec<u64> = (0..6).map(|attempt| poll_interval(&polling, attempt).as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 15, 15], intervals);
+        assert_eq!(15, poll_interval(&polling, 1000).as_secs());
+        assert_eq!(Duration::from_millis(20000), throttle_delay(1000));
+    }
+
+    #[test]
+    fn jitter_stays_within_interval() {
+        for _ in 0..100 {
+            let jittered = millis(equal_jitter(Duration::from_secs(4)));
+            assert!(jittered >= 2000 && jittered <= 4000, "{}", jittered);
+            assert!(millis(full_jitter(Duration::from_millis(500))) <= 500);
+        }
+    }
+
+    #[test]
+    fn poll_running_past_timeout_times_out() {
+        let polling = Polling {
+            timeout: 1,
+            initial_interval: 1,
+            max_interval: 1,
+        };
+        let polls = Cell::new(0);
+        let result: Result<bool, ()> = poll_until(&polling, || {
+            polls.set(polls.get() + 1);
+            std::thread::sleep(Duration::from_millis(1100));
+            Ok(false)
+        });
+        assert_eq!(Ok(false), result);
+        assert_eq!(1, polls.get());
+    }
+
+    #[test]
+    fn retries_only_throttled_requests() {
+        let attempts = Cell::new(0);
+        let result: Result<u32, String> = retry_throttled(3, || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 2 {
+                Err(String::from("Unknown(\"<Code>RequestLimitExceeded</Code>\")"))
+            } else {
+                Ok(attempts.get())
+            }
+        });
+        assert_eq!(Ok(2), result);
+
+        attempts.set(0);
+        let result: Result<(), String> = retry_throttled(3, || {
+            attempts.set(attempts.get() + 1);
+            Err(String::from("Unknown(\"<Code>InvalidVolume.NotFound</Code>\")"))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, attempts.get());
+
+        attempts.set(0);
+        let result: Result<(), String> = retry_throttled(0, || {
+            attempts.set(attempts.get() + 1);
+            Err(String::from("Throttling"))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, attempts.get());
+    }
+}
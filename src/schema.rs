@@ -0,0 +1,345 @@
+use serde_json::Value;
+use config;
+
+/// Describes the configuration file as a JSON Schema (draft 4), for validating configurations in editors and CI.  The
+/// schema is written by hand, and must be kept in step with the structs in `config`.
+pub fn config_schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-04/schema#",
+        "title": "cloud-persistent-storage configuration",
+        "type": "object",
+        "properties": {
+            "block-device": block_device_schema(),
+            "block-provider": { "$ref": "#/definitions/block-provider" },
+            "file-system": { "$ref": "#/definitions/file-system" },
+            "mount": { "$ref": "#/definitions/mount" },
+            "layout": { "$ref": "#/definitions/layout" },
+            "encryption": { "$ref": "#/definitions/encryption" },
+            "volumes": {
+                "type": "array",
+                "items": { "$ref": "#/definitions/volume" }
+            }
+        },
+        "additionalProperties": false,
+        "definitions": {
+            "volume": volume_schema(),
+            "block-provider": block_provider_schema(),
+            "file-system": file_system_schema(),
+            "mount": mount_schema(),
+            "layout": layout_schema(),
+            "encryption": encryption_schema(),
+            "polling": polling_schema(),
+            // YAML reads unquoted numbers, eg. `mode: 0750`, as numbers; they're accepted where strings are expected
+            "scalar": { "type": ["string", "number", "boolean"] }
+        }
+    })
+}
+
+fn block_device_schema() -> Value {
+    json!({
+        "type": "string",
+        "description": "block device that the volume is attached to, or auto to choose a free one when attaching; \
+                        multi-member layouts use consecutive devices",
+        "default": config::default_block_device()
+    })
+}
+
+fn volume_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "block-device": block_device_schema(),
+            "block-provider": { "$ref": "#/definitions/block-provider" },
+            "file-system": { "$ref": "#/definitions/file-system" },
+            "mount": { "$ref": "#/definitions/mount" },
+            "layout": { "$ref": "#/definitions/layout" },
+            "encryption": { "$ref": "#/definitions/encryption" }
+        },
+        "required": ["block-provider"],
+        "additionalProperties": false
+    })
+}
+
+fn block_provider_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "aws-ebs": {
+                "type": "object",
+                "properties": {
+                    "type": {
+                        "enum": ["gp2", "io1", "st1", "sc1"],
+                        "default": config::default_ebs_volume_type()
+                    },
+                    "size": { "type": "integer", "minimum": 0, "description": "size in GiB" },
+                    "ebs-tags": {
+                        "type": "object",
+                        "additionalProperties": { "$ref": "#/definitions/scalar" },
+                        "minProperties": 1
+                    },
+                    "selection": {
+                        "description": "which volume is attached when several are available",
+                        "oneOf": [
+                            { "enum": ["newest", "oldest", "largest", "most-recent-snapshot"] },
+                            {
+                                "type": "object",
+                                "properties": {
+                                    "prefer-tag": {
+                                        "type": "object",
+                                        "properties": {
+                                            "key": { "type": "string" },
+                                            "value": { "type": "string" }
+                                        },
+                                        "required": ["key", "value"],
+                                        "additionalProperties": false
+                                    }
+                                },
+                                "required": ["prefer-tag"],
+                                "additionalProperties": false
+                            }
+                        ]
+                    },
+                    "fingerprint-mismatch": {
+                        "description": "whether attach fails, or skips the volume, when an available volume was \
+                                        created with a different filesystem type, layout or encryption",
+                        "enum": ["fail", "skip"]
+                    },
+                    "pool": {
+                        "description": "available volumes that the pool command keeps ready for instances to attach",
+                        "type": "object",
+                        "properties": {
+                            "size": {
+                                "description": "available volumes, or volume sets, to keep in each availability zone",
+                                "type": "integer",
+                                "minimum": 1
+                            },
+                            "availability-zones": {
+                                "description": "availability zones to keep volumes in; defaults to the instance's",
+                                "type": "array",
+                                "items": { "type": "string", "minLength": 1 }
+                            },
+                            "snapshot-id": {
+                                "description": "snapshot to create pooled volumes from; single layout only",
+                                "type": "string",
+                                "pattern": "^snap-"
+                            }
+                        },
+                        "required": ["size"],
+                        "additionalProperties": false
+                    },
+                    "timeouts": {
+                        "type": "object",
+                        "properties": {
+                            "volume-available": { "$ref": "#/definitions/polling" },
+                            "volume-attached": { "$ref": "#/definitions/polling" },
+                            "volume-detached": { "$ref": "#/definitions/polling" },
+                            "throttle-retries": {
+                                "type": "integer",
+                                "minimum": 0,
+                                "default": config::default_throttle_retries()
+                            }
+                        },
+                        "additionalProperties": false
+                    }
+                },
+                "required": ["size", "ebs-tags"],
+                "additionalProperties": false
+            }
+        },
+        "required": ["aws-ebs"],
+        "additionalProperties": false
+    })
+}
+
+fn polling_schema() -> Value {
+    json!({
+        "type": "object",
+        "description": "waiting for a volume to change state; all values are in seconds",
+        "properties": {
+            "timeout": { "type": "integer", "minimum": 1, "default": config::default_polling_timeout() },
+            "initial-interval": {
+                "type": "integer",
+                "minimum": 1,
+                "default": config::default_polling_initial_interval()
+            },
+            "max-interval": { "type": "integer", "minimum": 1, "default": config::default_polling_max_interval() }
+        },
+        "additionalProperties": false
+    })
+}
+
+fn file_system_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "mkfs": {
+                "type": "array",
+                "items": { "$ref": "#/definitions/scalar" },
+                "minItems": 1,
+                "default": config::default_file_system_mkfs()
+            },
+            "fsck": {
+                "type": "object",
+                "properties": {
+                    "timeout": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "default": config::default_fsck_timeout()
+                    },
+                    "on-errors": {
+                        "enum": ["abort", "read-only", "mount"],
+                        "default": "abort"
+                    }
+                },
+                "additionalProperties": false
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+fn mount_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "target": { "type": "string", "default": config::default_mount_target() },
+            "owner": { "$ref": "#/definitions/scalar" },
+            "group": { "$ref": "#/definitions/scalar" },
+            "mode": { "$ref": "#/definitions/scalar", "description": "octal permissions, eg. 0750" },
+            "context": { "type": "string" },
+            "apply-permissions": { "enum": ["on-create", "always"], "default": "on-create" },
+            "binds": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "source": { "type": "string" },
+                        "target": { "type": "string" }
+                    },
+                    "required": ["source", "target"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+fn layout_schema() -> Value {
+    json!({
+        "default": "single",
+        "oneOf": [
+            { "enum": ["single"] },
+            {
+                "type": "object",
+                "properties": {
+                    "lvm": {
+                        "type": "object",
+                        "properties": {
+                            "members": { "type": "integer", "minimum": 1 },
+                            "volume-group": { "type": "string", "default": config::default_lvm_volume_group() },
+                            "logical-volume": { "type": "string", "default": config::default_lvm_logical_volume() },
+                            "stripe-size": { "$ref": "#/definitions/scalar" }
+                        },
+                        "required": ["members"],
+                        "additionalProperties": false
+                    }
+                },
+                "required": ["lvm"],
+                "additionalProperties": false
+            },
+            {
+                "type": "object",
+                "properties": {
+                    "raid": {
+                        "type": "object",
+                        "properties": {
+                            "level": { "enum": [0, 1, 10] },
+                            "members": { "type": "integer", "minimum": 2 },
+                            "device": { "type": "string", "default": config::default_raid_device() },
+                            "allow-degraded": { "type": "boolean", "default": false }
+                        },
+                        "required": ["level", "members"],
+                        "additionalProperties": false
+                    }
+                },
+                "required": ["raid"],
+                "additionalProperties": false
+            }
+        ]
+    })
+}
+
+fn encryption_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "default": config::default_encryption_name() },
+            "key-source": {
+                "oneOf": [
+                    {
+                        "type": "object",
+                        "properties": { "file": { "type": "string" } },
+                        "required": ["file"],
+                        "additionalProperties": false
+                    },
+                    {
+                        "type": "object",
+                        "properties": { "env": { "type": "string" } },
+                        "required": ["env"],
+                        "additionalProperties": false
+                    },
+                    {
+                        "type": "object",
+                        "properties": {
+                            "kms": {
+                                "type": "object",
+                                "properties": {
+                                    "ciphertext": { "type": "string" },
+                                    "ciphertext-file": { "type": "string" }
+                                },
+                                "additionalProperties": false
+                            }
+                        },
+                        "required": ["kms"],
+                        "additionalProperties": false
+                    }
+                ]
+            }
+        },
+        "required": ["key-source"],
+        "additionalProperties": false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn property_names(schema: &Value) -> Vec<String> {
+        let mut names: Vec<String> = schema["properties"].as_object().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn schema_properties_match_config() {
+        let schema = config_schema();
+        assert_eq!(vec!["block-device", "block-provider", "encryption", "file-system", "layout", "mount", "volumes"],
+                   property_names(&schema));
+        assert_eq!(vec!["block-device", "block-provider", "encryption", "file-system", "layout", "mount"],
+                   property_names(&schema["definitions"]["volume"]));
+        assert_eq!(vec!["apply-permissions", "binds", "context", "group", "mode", "owner", "target"],
+                   property_names(&schema["definitions"]["mount"]));
+    }
+
+    #[test]
+    fn schema_includes_defaults() {
+        let schema = config_schema();
+        assert_eq!(Some("/mnt"),
+                   schema["definitions"]["mount"]["properties"]["target"]["default"].as_str());
+        assert_eq!(Some(300),
+                   schema["definitions"]["file-system"]["properties"]["fsck"]["properties"]["timeout"]["default"]
+                       .as_u64());
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use aws_instance_metadata;
+use aws_instance_metadata::metadata::InstanceMetadata;
+use aws_instance_metadata::myerr::MetadataRetrievalError;
+use rusoto::ParseRegionError;
+use rusoto::ec2::DescribeTagsError;
+use ebs;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Unterminated(String),
+    UnknownVariable(String),
+    EnvironmentVariableMissing(String),
+    InstanceTagMissing(String),
+    InstanceMetadataFailed(MetadataRetrievalError),
+    InvalidRegion(ParseRegionError),
+    DescribeTagsFailed(DescribeTagsError),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateError::Unterminated(ref template) => write!(f, "unterminated ${{ in {}", template),
+            TemplateError::UnknownVariable(ref name) => write!(f, "unknown variable: {}", name),
+            TemplateError::EnvironmentVariableMissing(ref name) => {
+                write!(f, "environment variable is not set: {}", name)
+            }
+            TemplateError::InstanceTagMissing(ref name) => write!(f, "instance has no tag: {}", name),
+            TemplateError::InstanceMetadataFailed(ref err) => {
+                write!(f, "unable to retrieve instance metadata: {:?}", err)
+            }
+            TemplateError::InvalidRegion(ref err) => write!(f, "unrecognized instance region: {:?}", err),
+            TemplateError::DescribeTagsFailed(ref err) => write!(f, "unable to describe instance tags: {:?}", err),
+        }
+    }
+}
+
+/// Source of the values substituted for `${name}` references.
+pub trait Variables {
+    fn lookup(&mut self, name: &str) -> Result<String, TemplateError>;
+}
+
+/// Resolves `env.<NAME>` from the environment and `instance.az`, `instance.region`, `instance.id` and
+/// `instance.tag.<Name>` from this instance.  Instance metadata and tags are only retrieved the first time they're
+/// referenced, so configurations without instance references don't make any requests.
+pub struct InstanceVariables {
+    metadata: Option<InstanceMetadata>,
+    tags: Option<HashMap<String, String>>,
+}
+
+impl InstanceVariables {
+    pub fn new() -> InstanceVariables {
+        InstanceVariables {
+            metadata: None,
+            tags: None,
+        }
+    }
+
+    fn metadata(&mut self) -> Result<&InstanceMetadata, TemplateError> {
+        if self.metadata.is_none() {
+            self.metadata = Some(aws_instance_metadata::get().map_err(TemplateError::InstanceMetadataFailed)?);
+        }
+        Ok(self.metadata.as_ref().unwrap())
+    }
+
+    fn tags(&mut self) -> Result<&HashMap<String, String>, TemplateError> {
+        if self.tags.is_none() {
+            let tags = ebs::instance_tags(self.metadata()?).map_err(TemplateError::DescribeTagsFailed)?;
+            self.tags = Some(tags);
+        }
+        Ok(self.tags.as_ref().unwrap())
+    }
+}
+
+impl Variables for InstanceVariables {
+    fn lookup(&mut self, name: &str) -> Result<String, TemplateError> {
+        if name.starts_with("env.") {
+            return environment_variable(&name["env.".len()..]);
+        }
+        if name.starts_with("instance.tag.") {
+            let tag = &name["instance.tag.".len()..];
+            return self.tags()?
+                .get(tag)
+                .cloned()
+                .ok_or_else(|| TemplateError::InstanceTagMissing(String::from(tag)));
+        }
+        match name {
+            "instance.az" => Ok(self.metadata()?.availability_zone.to_owned()),
+            "instance.region" => {
+                Ok(self.metadata()?.region().map_err(TemplateError::InvalidRegion)?.to_string())
+            }
+            "instance.id" => Ok(self.metadata()?.instance_id.to_owned()),
+            _ => Err(TemplateError::UnknownVariable(String::from(name))),
+        }
+    }
+}
+
+/// Resolves `env.<NAME>` from the environment like `InstanceVariables`, but only checks that instance references name
+/// a known fact, without retrieving it, so that configurations can be validated off-instance.  Each instance
+/// reference expands to its own name, eg. `instance.tag.Name`.
+pub struct ReferenceCheckVariables;
+
+impl Variables for ReferenceCheckVariables {
+    fn lookup(&mut self, name: &str) -> Result<String, TemplateError> {
+        if name.starts_with("env.") {
+            return environment_variable(&name["env.".len()..]);
+        }
+        match name {
+            "instance.az" | "instance.region" | "instance.id" => Ok(String::from(name)),
+            _ if name.starts_with("instance.tag.") => Ok(String::from(name)),
+            _ => Err(TemplateError::UnknownVariable(String::from(name))),
+        }
+    }
+}
+
+fn environment_variable(name: &str) -> Result<String, TemplateError> {
+    env::var(name).map_err(|_| TemplateError::EnvironmentVariableMissing(String::from(name)))
+}
+
+/// Replaces each `${name}` in `template` with its value from `variables`.  `$$` is a literal `$`.
+pub fn expand(template: &str, variables: &mut Variables) -> Result<String, TemplateError> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if rest.starts_with('$') {
+            expanded.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => return Err(TemplateError::Unterminated(String::from(template))),
+            };
+            expanded.push_str(&variables.lookup(&rest[1..end])?);
+            rest = &rest[end + 1..];
+        } else {
+            expanded.push('$');
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestVariables {}
+
+    impl Variables for TestVariables {
+        fn lookup(&mut self, name: &str) -> Result<String, TemplateError> {
+            match name {
+                "instance.az" => Ok(String::from("us-west-2c")),
+                "instance.tag.Name" => Ok(String::from("db-1")),
+                _ => Err(TemplateError::UnknownVariable(String::from(name))),
+            }
+        }
+    }
+
+    #[test]
+    fn expands_variables() {
+        assert_eq!("/data/db-1/us-west-2c",
+                   expand("/data/${instance.tag.Name}/${instance.az}", &mut TestVariables {}).unwrap());
+        assert_eq!("no variables", expand("no variables", &mut TestVariables {}).unwrap());
+        assert_eq!("cost $5, ${instance.az}",
+                   expand("cost $5, $${instance.az}", &mut TestVariables {}).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_references() {
+        match expand("${instance.az", &mut TestVariables {}) {
+            Err(TemplateError::Unterminated(_)) => {}
+            other => panic!("expected unterminated error, got {:?}", other),
+        }
+        match expand("${instance.nope}", &mut TestVariables {}) {
+            Err(TemplateError::UnknownVariable(ref name)) if name == "instance.nope" => {}
+            other => panic!("expected unknown variable error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn looks_up_environment_variables() {
+        env::set_var("CPS_TEMPLATE_TEST", "value");
+        let mut variables = InstanceVariables::new();
+        assert_eq!("value", variables.lookup("env.CPS_TEMPLATE_TEST").unwrap());
+        match variables.lookup("env.CPS_TEMPLATE_TEST_MISSING") {
+            Err(TemplateError::EnvironmentVariableMissing(_)) => {}
+            other => panic!("expected missing variable error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn checks_references_without_looking_up() {
+        let mut variables = ReferenceCheckVariables;
+        assert_eq!("/data/instance.tag.Name/instance.az",
+                   expand("/data/${instance.tag.Name}/${instance.az}", &mut variables).unwrap());
+        match expand("${instance.nope}", &mut variables) {
+            Err(TemplateError::UnknownVariable(ref name)) if name == "instance.nope" => {}
+            other => panic!("expected unknown variable error, got {:?}", other),
+        }
+    }
+}
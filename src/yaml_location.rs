@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+/// Finds the line and column (both starting at 1) of each key and sequence item in a YAML document, keyed by path,
+/// eg. `block-provider.aws-ebs.size` or `volumes[1].mount`, so that validation errors can point at the setting
+/// they concern.
+///
+/// This only understands block-style YAML, which is what configuration files are written in; settings within
+/// flow-style `{ ... }` and `[ ... ]` values aren't located, and neither are keys within multi-line strings.
+pub fn locate(text: &str) -> HashMap<String, (usize, usize)> {
+    let mut locations = HashMap::new();
+    // the enclosing keys and sequence items of the current line, with their columns
+    let mut stack: Vec<(usize, String, bool)> = Vec::new();
+    let mut sequence_lengths: HashMap<String, usize> = HashMap::new();
+
+    for (line_number, line) in text.lines().enumerate() {
+        let content = strip_comment(line);
+        if content.trim().is_empty() || content.starts_with("---") || content.starts_with("...") {
+            continue;
+        }
+        let mut column = content.len() - content.trim_left_matches(' ').len();
+        let mut rest = &content[column..];
+
+        while rest == "-" || rest.starts_with("- ") {
+            // a sequence item is a child of the key at a lesser column, or of a key at the same column, since YAML
+            // allows sequences to be indented as far as their key
+            while stack.last().map_or(false, |&(c, _, item)| c > column || (c == column && item)) {
+                stack.pop();
+            }
+            let parent = stack.last().map_or(String::new(), |&(_, ref path, _)| path.clone());
+            let index = sequence_lengths.entry(parent.clone()).or_insert(0);
+            let path = format!("{}[{}]", parent, *index);
+            *index += 1;
+            locations.insert(path.clone(), (line_number + 1, column + 1));
+            stack.push((column, path, true));
+
+            let item = &rest[1..];
+            column += 1 + item.len() - item.trim_left_matches(' ').len();
+            rest = item.trim_left_matches(' ');
+        }
+
+        if let Some(key) = mapping_key(rest) {
+            while stack.last().map_or(false, |&(c, _, _)| c >= column) {
+                stack.pop();
+            }
+            let path = match stack.last() {
+                Some(&(_, ref parent, _)) => format!("{}.{}", parent, key),
+                None => String::from(key),
+            };
+            locations.insert(path.clone(), (line_number + 1, column + 1));
+            stack.push((column, path, false));
+        }
+    }
+    locations
+}
+
+/// Finds the location of `path`, or of its closest enclosing setting that was located; a setting that fails
+/// validation may have been left out of the document and taken its default.
+pub fn find(locations: &HashMap<String, (usize, usize)>, path: &str) -> Option<(usize, usize)> {
+    let mut path = path;
+    loop {
+        if let Some(location) = locations.get(path) {
+            return Some(*location);
+        }
+        match path.rfind(|c| c == '.' || c == '[') {
+            Some(end) => path = &path[..end],
+            None => return None,
+        }
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let line = line.trim_right();
+    if line.trim_left().starts_with('#') {
+        return "";
+    }
+    match line.find(" #") {
+        Some(start) => line[..start].trim_right(),
+        None => line,
+    }
+}
+
+/// Returns the key if `text` starts with a `key:` mapping entry.
+fn mapping_key(text: &str) -> Option<&str> {
+    let end = match text.find(": ") {
+        Some(end) => end,
+        None if text.ends_with(':') => text.len() - 1,
+        None => return None,
+    };
+    let key = text[..end].trim();
+    if key.is_empty() || key.starts_with('{') || key.starts_with('[') {
+        None
+    } else if key.len() >= 2 &&
+              (key.starts_with('"') && key.ends_with('"') || key.starts_with('\'') && key.ends_with('\'')) {
+        Some(&key[1..key.len() - 1])
+    } else {
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_keys_and_sequence_items() {
+        let locations = locate("# comment
+block-device: /dev/xvdf
+block-provider:
+  aws-ebs:
+    size: 100  # GiB
+    ebs-tags:
+      \"Role\": PostgreSQL
+volumes:
+- mount:
+    target: /data
+  layout: single
+-   mount: { target: /wal }
+mount:
+  binds:
+    - source: a
+      target: /a
+    - source: b
+");
+        assert_eq!(Some(&(2, 1)), locations.get("block-device"));
+        assert_eq!(Some(&(5, 5)), locations.get("block-provider.aws-ebs.size"));
+        assert_eq!(Some(&(7, 7)), locations.get("block-provider.aws-ebs.ebs-tags.Role"));
+        assert_eq!(Some(&(9, 1)), locations.get("volumes[0]"));
+        assert_eq!(Some(&(9, 3)), locations.get("volumes[0].mount"));
+        assert_eq!(Some(&(10, 5)), locations.get("volumes[0].mount.target"));
+        assert_eq!(Some(&(11, 3)), locations.get("volumes[0].layout"));
+        assert_eq!(Some(&(12, 5)), locations.get("volumes[1].mount"));
+        assert_eq!(None, locations.get("volumes[1].mount.target"));
+        assert_eq!(Some(&(16, 7)), locations.get("mount.binds[0].target"));
+        assert_eq!(Some(&(17, 7)), locations.get("mount.binds[1].source"));
+    }
+
+    #[test]
+    fn finds_closest_enclosing_setting() {
+        let locations = locate("volumes:\n  - block-provider:\n      aws-ebs:\n        ebs-tags: { a: b }\n");
+        assert_eq!(Some((3, 7)), find(&locations, "volumes[0].block-provider.aws-ebs.size"));
+        assert_eq!(Some((4, 9)), find(&locations, "volumes[0].block-provider.aws-ebs.ebs-tags.a"));
+        assert_eq!(Some((2, 3)), find(&locations, "volumes[0].mount.binds[0].source"));
+        assert_eq!(None, find(&locations, "mount.target"));
+    }
+}